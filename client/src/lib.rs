@@ -0,0 +1,286 @@
+//! Async client for the json api of manga-server, so scripts and services do
+//! not have to build urls like `/manga/{id}/{chapter}/{pic}` by hand.
+//!
+//! The types are the server's own, from `manga-server-types`, re-exported here. Failed calls return an
+//! `anyhow::Error` that downcasts to [`ServerError`] when the server answered.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let client = manga_server_client::Client::new("http://localhost:24317")?.with_user("me", "");
+//! for series in client.search("one").await? {
+//!     let chapters = client.chapters(&series.id).await?;
+//!     let first = client.page(&series.id, &chapters[0].id, 0).await?;
+//!     client.set_progress(&series.id, &chapters[0].id, 0, false).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::fmt::Display;
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use hyper::{
+    body::{Bytes, HttpBody},
+    client::HttpConnector,
+    header::{self, HeaderValue},
+    Body, Method, Request, Response, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+pub use manga_server_types::{
+    api::{Chapter, ErrorBody, Library, Page, ProgressUpdate, Series},
+    ChapterBasicInfo, ChapterInfo, MangaInfo, Progress, ScanProgress,
+};
+
+const API: &str = "/api/v1";
+
+/// The server answered with an error status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub status: StatusCode,
+    /// The `error` of the json body, or the reason phrase of the status.
+    pub message: String,
+}
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status.as_u16(), self.message)
+    }
+}
+impl std::error::Error for ServerError {}
+
+fn path(prefix: &str, segments: &[&str]) -> String {
+    let mut out = prefix.to_owned();
+    for s in segments {
+        out.push('/');
+        out.push_str(&utf8_percent_encode(s, NON_ALPHANUMERIC).to_string());
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Scheme, host and path prefix, without a trailing slash.
+    base: String,
+    http: hyper::Client<HttpsConnector<HttpConnector>>,
+    auth: Option<HeaderValue>,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, e.g. `http://localhost:24317`.
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        let base = base_url.trim_end_matches('/').to_owned();
+        let uri: Uri = base
+            .parse()
+            .with_context(|| format!("`{}` is not a url", base_url))?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+            bail!("`{}` is not an http or https url", base_url);
+        }
+        let http = hyper::Client::builder().build(
+            HttpsConnectorBuilder::new()
+                .with_webpki_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        );
+        Ok(Self {
+            base,
+            http,
+            auth: None,
+        })
+    }
+
+    /// Sends basic auth, read progress is kept per user.
+    pub fn with_user(mut self, user: &str, password: &str) -> Self {
+        let token =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        self.auth = HeaderValue::from_str(&format!("Basic {}", token)).ok();
+        self
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> anyhow::Result<Response<Body>> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, path));
+        if let Some(auth) = &self.auth {
+            req = req.header(header::AUTHORIZATION, auth.clone());
+        }
+        let req = match body {
+            Some(b) => req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(b))?,
+            None => req.body(Body::empty())?,
+        };
+        let response = self
+            .http
+            .request(req)
+            .await
+            .with_context(|| format!("can not reach {}", self.base))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap_or_default();
+        let message = match serde_json::from_slice::<ErrorBody>(&body) {
+            Ok(e) => e.error,
+            // the routes outside the api answer with the 404 page
+            Err(_) => status.canonical_reason().unwrap_or("error").to_owned(),
+        };
+        Err(ServerError { status, message }.into())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let response = self.request(Method::GET, path, None).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        serde_json::from_slice(&body).with_context(|| format!("unexpected answer to {}", path))
+    }
+
+    async fn bytes(&self, path: &str) -> anyhow::Result<Bytes> {
+        let response = self.request(Method::GET, path, None).await?;
+        Ok(hyper::body::to_bytes(response.into_body()).await?)
+    }
+
+    /// Streams the body at `path` into `out`, returns the bytes written.
+    async fn copy(&self, path: &str, out: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<u64> {
+        let mut body = self.request(Method::GET, path, None).await?.into_body();
+        let mut written = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            out.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        out.flush().await?;
+        Ok(written)
+    }
+
+    pub async fn libraries(&self) -> anyhow::Result<Vec<Library>> {
+        self.get(&path(API, &["libraries"])).await
+    }
+
+    /// Every series, sorted by name.
+    pub async fn series_list(&self) -> anyhow::Result<Vec<Series>> {
+        self.get(&path(API, &["series"])).await
+    }
+
+    /// Series whose name contains `query`, ignoring case.
+    pub async fn search(&self, query: &str) -> anyhow::Result<Vec<Series>> {
+        let q = utf8_percent_encode(query, NON_ALPHANUMERIC);
+        self.get(&format!("{}?q={}", path(API, &["series"]), q))
+            .await
+    }
+
+    pub async fn series(&self, id: &str) -> anyhow::Result<Series> {
+        self.get(&path(API, &["series", id])).await
+    }
+
+    /// The series as the index has it, with every chapter.
+    pub async fn manga(&self, id: &str) -> anyhow::Result<MangaInfo> {
+        self.get(&path("/manga", &[id])).await
+    }
+
+    /// Chapters in reading order, with their page counts.
+    pub async fn chapters(&self, series: &str) -> anyhow::Result<Vec<Chapter>> {
+        self.get(&path(API, &["series", series, "chapters"])).await
+    }
+
+    pub async fn chapter(&self, series: &str, chapter: &str) -> anyhow::Result<Chapter> {
+        self.get(&path(API, &["series", series, "chapters", chapter]))
+            .await
+    }
+
+    pub async fn chapter_info(&self, manga: &str, chapter: &str) -> anyhow::Result<ChapterInfo> {
+        self.get(&path("/manga", &[manga, chapter])).await
+    }
+
+    pub async fn pages(&self, series: &str, chapter: &str) -> anyhow::Result<Vec<Page>> {
+        self.get(&path(
+            API,
+            &["series", series, "chapters", chapter, "pages"],
+        ))
+        .await
+    }
+
+    /// The image of a page, starting at 0.
+    pub async fn page(&self, series: &str, chapter: &str, index: usize) -> anyhow::Result<Bytes> {
+        self.bytes(&path(
+            API,
+            &[
+                "series",
+                series,
+                "chapters",
+                chapter,
+                "pages",
+                &index.to_string(),
+            ],
+        ))
+        .await
+    }
+
+    /// Writes the chapter as a CBZ to `out`, returns its length.
+    pub async fn download_chapter(
+        &self,
+        series: &str,
+        chapter: &str,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> anyhow::Result<u64> {
+        self.copy(&path("/download", &[series, chapter]), out).await
+    }
+
+    /// Writes every chapter of the series as one CBZ to `out`, returns its length.
+    pub async fn download_series(
+        &self,
+        series: &str,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> anyhow::Result<u64> {
+        self.copy(&path("/download", &[series]), out).await
+    }
+
+    /// Where the user stopped reading, `None` if they did not open the chapter.
+    pub async fn progress(&self, series: &str, chapter: &str) -> anyhow::Result<Option<Progress>> {
+        self.get(&path(
+            API,
+            &["series", series, "chapters", chapter, "progress"],
+        ))
+        .await
+    }
+
+    /// Records `page` as the last page read, starting at 0.
+    pub async fn set_progress(
+        &self,
+        series: &str,
+        chapter: &str,
+        page: usize,
+        completed: bool,
+    ) -> anyhow::Result<Progress> {
+        let p = path(API, &["series", series, "chapters", chapter, "progress"]);
+        let body = serde_json::to_vec(&ProgressUpdate { page, completed })?;
+        let response = self.request(Method::PUT, &p, Some(body)).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        serde_json::from_slice(&body).with_context(|| format!("unexpected answer to {}", p))
+    }
+
+    pub async fn clear_progress(&self, series: &str, chapter: &str) -> anyhow::Result<()> {
+        let p = path(API, &["series", series, "chapters", chapter, "progress"]);
+        self.request(Method::DELETE, &p, None).await?;
+        Ok(())
+    }
+}
+
+#[test]
+fn t_path() {
+    assert_eq!(
+        path(API, &["series", "a b/c", "chapters"]),
+        "/api/v1/series/a%20b%2Fc/chapters"
+    );
+    assert!(Client::new("ftp://localhost").is_err());
+    assert!(Client::new("http://localhost:24317/").is_ok());
+}
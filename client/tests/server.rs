@@ -0,0 +1,74 @@
+//! The client against a real server on a free port, serving the in-memory
+//! library: 2 series of 2 chapters with 3 pages each.
+use std::time::Duration;
+
+use hyper::StatusCode;
+use manga_server::{
+    config::{Backend, Config},
+    memory::{self, Library},
+    net::ListenAddr,
+    server::ServerBuilder,
+};
+use manga_server_client::{Client, ServerError};
+
+#[tokio::test]
+async fn t_server() {
+    memory::seed(Library::generated(2, 2, 3));
+    let server = ServerBuilder::new(Config::new(Backend::Memory))
+        .listen(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()))
+        .build()
+        .unwrap();
+    let client = Client::new(&format!("http://{}", server.local_addrs()[0]))
+        .unwrap()
+        .with_user("me", "pw");
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(server.run_until(async {
+        stopped.await.ok();
+    }));
+
+    // the library is scanned in the background
+    let mut found = Vec::new();
+    for _ in 0..50 {
+        found = client.search("series 1").await.unwrap_or_default();
+        if !found.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].name, "Series 1");
+    let chapters = client.chapters("1").await.unwrap();
+    assert_eq!(chapters.len(), 2);
+    assert_eq!(chapters[1].page_count, 3);
+    let page = client.page("1", "2", 0).await.unwrap();
+    assert!(page.starts_with(b"\x89PNG"));
+    let mut cbz = Vec::new();
+    let len = client.download_chapter("1", "2", &mut cbz).await.unwrap();
+    assert_eq!(len, cbz.len() as u64);
+    assert!(cbz.starts_with(b"PK\x03\x04"));
+
+    let p = client.set_progress("1", "2", 2, true).await.unwrap();
+    assert_eq!((p.page, p.completed), (2, true));
+    assert_eq!(client.progress("1", "2").await.unwrap(), Some(p));
+    // progress is kept per user
+    let other = client.clone().with_user("other", "");
+    assert_eq!(other.progress("1", "2").await.unwrap(), None);
+    client.clear_progress("1", "2").await.unwrap();
+    assert_eq!(client.progress("1", "2").await.unwrap(), None);
+
+    let e = client.series("x").await.unwrap_err();
+    assert_eq!(
+        e.downcast_ref::<ServerError>(),
+        Some(&ServerError {
+            status: StatusCode::NOT_FOUND,
+            message: "series not found".into()
+        })
+    );
+
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), running)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
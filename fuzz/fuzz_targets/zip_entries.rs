@@ -0,0 +1,17 @@
+//! Reads the central directory of arbitrary bytes, `cargo +nightly fuzz run
+//! zip_entries` from the repository root. Entries that come back have to lie
+//! within the input.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use manga_server::zip;
+
+fuzz_target!(|data: &[u8]| {
+    let len = data.len() as u64;
+    let mut file = std::io::Cursor::new(data);
+    if let Ok(entries) = pollster::block_on(zip::read_entries_from(&mut file, len)) {
+        for e in entries {
+            assert!(e.header_offset + zip::LOCAL_HEADER_LEN + e.compressed_size <= len);
+        }
+    }
+});
@@ -0,0 +1,413 @@
+//! The versioned json api under `/api/v1`. The OpenAPI document describing
+//! it is generated from these types and served at `/api/v1/openapi.json`.
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response, StatusCode,
+};
+use serde::Serialize;
+use utoipa::OpenApi;
+
+use crate::{
+    backend::BackendTrait,
+    komga,
+    maintenance::chapter_length,
+    manga_list::{self, MangaInfo},
+    metrics,
+    progress::{self, Progress},
+    router::{Query, RouteError},
+};
+
+pub use manga_server_types::api::{Chapter, ErrorBody, Library, Page, ProgressUpdate, Series};
+
+pub const PREFIX: &str = "/api/v1";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "manga-server", description = "Browse and read the library."),
+    paths(
+        list_libraries,
+        get_library,
+        list_series,
+        get_series,
+        list_chapters,
+        get_chapter,
+        list_pages,
+        get_page,
+        get_progress,
+        put_progress,
+        delete_progress
+    ),
+    components(schemas(Library, Series, Chapter, Page, Progress, ProgressUpdate, ErrorBody))
+)]
+pub struct ApiDoc;
+
+/// Failure of an api call, answered with a json [`ErrorBody`].
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+impl ApiError {
+    pub fn not_found(what: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: format!("{} not found", what),
+        }
+    }
+    pub fn into_response(self) -> Response<Body> {
+        let mut r = json(&ErrorBody {
+            error: self.message,
+        });
+        *r.status_mut() = self.status;
+        r
+    }
+}
+/// Largest request body that is read, the json bodies of the apis are small.
+pub const MAX_BODY: usize = 64 * 1024;
+
+/// Reads a request body of up to [`MAX_BODY`] bytes, a larger one is a 413.
+pub async fn read_body(mut body: Body) -> Result<Bytes, ApiError> {
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(anyhow::Error::from)?;
+        if out.len() + chunk.len() > MAX_BODY {
+            return Err(ApiError {
+                status: StatusCode::PAYLOAD_TOO_LARGE,
+                message: format!("request body is larger than {} bytes", MAX_BODY),
+            });
+        }
+        out.extend_from_slice(&chunk);
+    }
+    Ok(out.into())
+}
+
+impl From<RouteError> for ApiError {
+    fn from(e: RouteError) -> Self {
+        let status = match e {
+            RouteError::NotFound => StatusCode::NOT_FOUND,
+            RouteError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            RouteError::BadRequest(_) => StatusCode::BAD_REQUEST,
+        };
+        Self {
+            status,
+            message: e.to_string(),
+        }
+    }
+}
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let kind = metrics::error_kind(&e);
+        metrics::record_error(kind);
+        let status = match kind {
+            "not_found" => StatusCode::NOT_FOUND,
+            "bad_request" => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self {
+            status,
+            message: format!("{:#}", e),
+        }
+    }
+}
+
+pub type ApiResult = Result<Response<Body>, ApiError>;
+
+pub(crate) fn json<T: Serialize>(v: &T) -> Response<Body> {
+    let mut r = Response::new(Body::from(serde_json::to_vec(v).unwrap_or_default()));
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    r
+}
+
+fn library_id() -> String {
+    manga_list::library_name().to_owned()
+}
+
+fn manga(id: &str) -> Result<MangaInfo, ApiError> {
+    manga_list::get_list_ref()
+        .get_list_mut()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("series"))
+}
+
+fn series(m: &MangaInfo) -> Series {
+    Series {
+        id: m.id.clone(),
+        library_id: library_id(),
+        name: m.name.clone(),
+        cover_url: match m.chapters.first() {
+            Some(c) => page_url(&m.id, &c.id, 0),
+            None => String::new(),
+        },
+        chapter_count: m.chapters.len(),
+        added: m.added,
+    }
+}
+
+pub(crate) fn page_url(series: &str, chapter: &str, page: usize) -> String {
+    format!(
+        "{}/series/{}/chapters/{}/pages/{}",
+        PREFIX, series, chapter, page
+    )
+}
+
+fn library() -> Library {
+    let list = manga_list::get_list_ref().get_list_mut();
+    Library {
+        id: library_id(),
+        backend: library_id(),
+        series_count: list.len(),
+        chapter_count: list.values().map(|m| m.chapters.len()).sum(),
+    }
+}
+
+pub fn openapi() -> Response<Body> {
+    let mut r = Response::new(Body::from(
+        ApiDoc::openapi().to_pretty_json().unwrap_or_default(),
+    ));
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    r
+}
+
+/// Every library, currently only the configured backend.
+#[utoipa::path(get, path = "/api/v1/libraries", responses(
+    (status = 200, body = [Library]),
+))]
+pub async fn list_libraries() -> ApiResult {
+    Ok(json(&[library()]))
+}
+
+#[utoipa::path(get, path = "/api/v1/libraries/{id}", params(("id" = String, Path, description = "Library id")), responses(
+    (status = 200, body = Library),
+    (status = 404, body = ErrorBody),
+))]
+pub async fn get_library(id: &str) -> ApiResult {
+    if id != library_id() {
+        return Err(ApiError::not_found("library"));
+    }
+    Ok(json(&library()))
+}
+
+/// Series sorted by name, optionally filtered by a part of the name.
+#[utoipa::path(
+    get,
+    path = "/api/v1/series",
+    params(
+        ("q" = Option<String>, Query, description = "Only series whose name contains this, ignoring case"),
+        ("offset" = Option<usize>, Query, description = "Series to skip"),
+        ("limit" = Option<usize>, Query, description = "Most series to return"),
+    ),
+    responses(
+        (status = 200, body = [Series]),
+        (status = 400, body = ErrorBody),
+    )
+)]
+pub async fn list_series(query: &Query) -> ApiResult {
+    let q = query.get("q").map(str::to_lowercase);
+    let offset = query.parse_value("offset")?.unwrap_or(0);
+    let limit = query.parse_value("limit")?.unwrap_or(usize::MAX);
+    let all = crate::maintenance::index()
+        .iter()
+        .filter(|m| match &q {
+            Some(q) => m.name.to_lowercase().contains(q),
+            None => true,
+        })
+        .skip(offset)
+        .take(limit)
+        .map(series)
+        .collect::<Vec<_>>();
+    Ok(json(&all))
+}
+
+#[utoipa::path(get, path = "/api/v1/series/{id}", params(("id" = String, Path, description = "Series id")), responses(
+    (status = 200, body = Series),
+    (status = 404, body = ErrorBody),
+))]
+pub async fn get_series(id: &str) -> ApiResult {
+    Ok(json(&series(&manga(id)?)))
+}
+
+async fn chapter<B: BackendTrait>(m: &MangaInfo, chapter_id: &str) -> Result<Chapter, ApiError> {
+    let c = m
+        .chapters
+        .iter()
+        .find(|c| c.id == chapter_id)
+        .ok_or_else(|| ApiError::not_found("chapter"))?;
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    Ok(Chapter {
+        id: c.id.clone(),
+        series_id: m.id.clone(),
+        name: c.name.clone(),
+        page_count: chapter_length::<B>(&base_path, &m.id, &c.id, c.length).await?,
+    })
+}
+
+/// Chapters of a series in reading order.
+#[utoipa::path(get, path = "/api/v1/series/{id}/chapters", params(("id" = String, Path, description = "Series id")), responses(
+    (status = 200, body = [Chapter]),
+    (status = 404, body = ErrorBody),
+))]
+pub async fn list_chapters<B: BackendTrait>(id: &str) -> ApiResult {
+    let m = manga(id)?;
+    let mut out = Vec::with_capacity(m.chapters.len());
+    for c in &m.chapters {
+        out.push(chapter::<B>(&m, &c.id).await?);
+    }
+    Ok(json(&out))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/series/{id}/chapters/{chapter}",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id")),
+    responses(
+        (status = 200, body = Chapter),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_chapter<B: BackendTrait>(id: &str, chapter_id: &str) -> ApiResult {
+    Ok(json(&chapter::<B>(&manga(id)?, chapter_id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/series/{id}/chapters/{chapter}/pages",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id")),
+    responses(
+        (status = 200, body = [Page]),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn list_pages<B: BackendTrait>(id: &str, chapter_id: &str) -> ApiResult {
+    let c = chapter::<B>(&manga(id)?, chapter_id).await?;
+    let pages = (0..c.page_count)
+        .map(|index| Page {
+            index,
+            url: page_url(&c.series_id, &c.id, index),
+        })
+        .collect::<Vec<_>>();
+    Ok(json(&pages))
+}
+
+/// The image of one page, starting at 0.
+#[utoipa::path(
+    get,
+    path = "/api/v1/series/{id}/chapters/{chapter}/pages/{page}",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id"), ("page" = usize, Path, description = "Page index, starting at 0")),
+    responses(
+        (status = 200, description = "The page image", content_type = "image/*"),
+        (status = 206, description = "The requested range of the page image", content_type = "image/*"),
+        (status = 416, description = "The requested range is outside the image"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_page<B: BackendTrait>(
+    headers: &HeaderMap,
+    id: &str,
+    chapter_id: &str,
+    page: usize,
+) -> ApiResult {
+    let m = manga(id)?;
+    if !m.chapters.iter().any(|c| c.id == chapter_id) {
+        return Err(ApiError::not_found("chapter"));
+    }
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let pic = B::get_pic_in_chapter(&base_path, id, chapter_id, page)
+        .await?
+        .ok_or_else(|| ApiError::not_found("page"))?;
+    metrics::record_page_read(id);
+    Ok(pic.respond(headers).await.map_err(anyhow::Error::from)?)
+}
+
+/// Where the basic auth user stopped reading, `null` if they did not open
+/// the chapter yet.
+#[utoipa::path(
+    get,
+    path = "/api/v1/series/{id}/chapters/{chapter}/progress",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id")),
+    responses(
+        (status = 200, body = Option<Progress>),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn get_progress(headers: &HeaderMap, id: &str, chapter_id: &str) -> ApiResult {
+    let m = manga(id)?;
+    if !m.chapters.iter().any(|c| c.id == chapter_id) {
+        return Err(ApiError::not_found("chapter"));
+    }
+    Ok(json(&progress::get(&komga::user(headers), id, chapter_id)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/series/{id}/chapters/{chapter}/progress",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id")),
+    request_body = ProgressUpdate,
+    responses(
+        (status = 200, body = Progress),
+        (status = 400, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 413, body = ErrorBody),
+    )
+)]
+pub async fn put_progress<B: BackendTrait>(
+    headers: &HeaderMap,
+    id: &str,
+    chapter_id: &str,
+    body: Body,
+) -> ApiResult {
+    let c = chapter::<B>(&manga(id)?, chapter_id).await?;
+    let body = read_body(body).await?;
+    let update: ProgressUpdate = serde_json::from_slice(&body).map_err(|e| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: format!("invalid progress: {}", e),
+    })?;
+    if update.page >= c.page_count {
+        return Err(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!(
+                "page {} is past the last page {}",
+                update.page,
+                c.page_count.saturating_sub(1)
+            ),
+        });
+    }
+    let user = komga::user(headers);
+    progress::set(&user, id, chapter_id, update.page, update.completed);
+    Ok(json(&progress::get(&user, id, chapter_id)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/series/{id}/chapters/{chapter}/progress",
+    params(("id" = String, Path, description = "Series id"), ("chapter" = String, Path, description = "Chapter id")),
+    responses(
+        (status = 204, description = "The progress is gone"),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn delete_progress(headers: &HeaderMap, id: &str, chapter_id: &str) -> ApiResult {
+    let m = manga(id)?;
+    if !m.chapters.iter().any(|c| c.id == chapter_id) {
+        return Err(ApiError::not_found("chapter"));
+    }
+    progress::clear(&komga::user(headers), id, chapter_id);
+    let mut r = Response::new(Body::empty());
+    *r.status_mut() = StatusCode::NO_CONTENT;
+    Ok(r)
+}
+
+#[test]
+fn t_openapi() {
+    let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+    assert!(doc["paths"]["/api/v1/series/{id}/chapters/{chapter}/pages/{page}"]["get"].is_object());
+    assert!(doc["components"]["schemas"]["Series"].is_object());
+    assert!(doc["paths"]["/api/v1/series/{id}/chapters/{chapter}/progress"]["put"].is_object());
+}
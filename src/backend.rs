@@ -1,25 +1,222 @@
-use crate::manga_list::MangaList;
+use std::{ops::RangeInclusive, path::Path, pin::Pin, time::SystemTime};
 
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct ChapterInfo {
-    pub length: usize,
-    pub name: String,
-}
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+use crate::{
+    manga_list::MangaList,
+    range::{self, Plan, Validators},
+    zip,
+};
+
+pub use manga_server_types::ChapterInfo;
 
 #[async_trait::async_trait]
-pub trait BackendTrait {
+pub trait BackendTrait: Send + Sync + 'static {
     fn generate_manga_list() -> MangaList;
     async fn get_pic_in_chapter(
         base_path: &str,
         manga_id: &str,
         chapter: &str,
         pic_id: usize,
-    ) -> anyhow::Result<Option<Vec<u8>>>;
+    ) -> anyhow::Result<Option<Pic>>;
     async fn get_chapter_info(
         base_path: &str,
         manga_id: &str,
         chapter: &str,
     ) -> anyhow::Result<ChapterInfo>;
+    /// A page as it goes into a downloaded archive, backends that keep pages
+    /// in zips hand out the entry so it is copied without decompressing.
+    async fn get_archive_page(
+        base_path: &str,
+        manga_id: &str,
+        chapter: &str,
+        pic_id: usize,
+    ) -> anyhow::Result<Option<ArchivePage>> {
+        Ok(
+            Self::get_pic_in_chapter(base_path, manga_id, chapter, pic_id)
+                .await?
+                .map(ArchivePage::Pic),
+        )
+    }
+    /// The chapter as a zip on disk, for backends that keep chapters that way.
+    fn chapter_archive(_base_path: &str, _manga_id: &str, _chapter: &str) -> Option<String> {
+        None
+    }
+}
+
+pub enum ArchivePage {
+    Pic(Pic),
+    /// An entry of the zip at `path`.
+    Zip {
+        path: String,
+        entry: zip::ZipEntry,
+        modified: Option<SystemTime>,
+    },
+}
+
+/// A page that is read while it is sent instead of being loaded up front.
+pub struct Pic {
+    pub len: u64,
+    pub validators: Validators,
+    pub content_type: Option<&'static str>,
+    source: PicSource,
+}
+enum PicSource {
+    /// Opened once the page is read.
+    File(std::path::PathBuf),
+    Reader(Pin<Box<dyn AsyncRead + Send + Sync>>),
+}
+impl Pic {
+    /// Only looks at the metadata, the file is opened when the page is read.
+    pub async fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let meta = tokio::fs::metadata(path).await?;
+        if !meta.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        Ok(Self {
+            len: meta.len(),
+            validators: Validators::from_metadata(&meta),
+            content_type: content_type(&path.to_string_lossy()),
+            source: PicSource::File(path.to_owned()),
+        })
+    }
+    /// A page from a reader that yields exactly `len` bytes.
+    pub fn from_reader(
+        reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+        len: u64,
+        validators: Validators,
+        content_type: Option<&'static str>,
+    ) -> Self {
+        Self {
+            len,
+            validators,
+            content_type,
+            source: PicSource::Reader(reader),
+        }
+    }
+
+    /// Reads the whole page.
+    pub async fn bytes(self) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.len as usize);
+        match self.source {
+            PicSource::File(p) => {
+                tokio::fs::File::open(p)
+                    .await?
+                    .read_to_end(&mut out)
+                    .await?
+            }
+            PicSource::Reader(mut r) => r.read_to_end(&mut out).await?,
+        };
+        Ok(out)
+    }
+
+    /// A reader of the bytes in `range`, or all of them.
+    pub async fn reader(
+        self,
+        range: Option<RangeInclusive<u64>>,
+    ) -> std::io::Result<Pin<Box<dyn AsyncRead + Send + Sync>>> {
+        let (start, len) = match &range {
+            Some(r) => (*r.start(), r.end() - r.start() + 1),
+            None => (0, self.len),
+        };
+        Ok(match self.source {
+            PicSource::File(p) => {
+                let mut f = tokio::fs::File::open(p).await?;
+                f.seek(std::io::SeekFrom::Start(start)).await?;
+                Box::pin(f.take(len))
+            }
+            PicSource::Reader(mut r) => {
+                // compressed data can not seek, read up to the start
+                tokio::io::copy(&mut (&mut r).take(start), &mut tokio::io::sink()).await?;
+                Box::pin(r.take(len))
+            }
+        })
+    }
+
+    /// A streaming body with the bytes in `range`, or all of them.
+    pub async fn body(self, range: Option<RangeInclusive<u64>>) -> std::io::Result<Body> {
+        let reader = self.reader(range).await?;
+        Ok(Body::wrap_stream(tokio_util::io::ReaderStream::new(reader)))
+    }
+
+    /// The response for a request with `headers`, honouring ranges.
+    pub async fn respond(self, headers: &HeaderMap) -> std::io::Result<Response<Body>> {
+        let len = self.len;
+        let validators = self.validators.clone();
+        let content_type = self.content_type;
+        let (mut r, sent) = match range::plan(headers, len, &validators) {
+            Plan::Full => {
+                let body = self.body(None).await?;
+                (range::with_headers(Response::new(body), &validators), len)
+            }
+            Plan::Partial(r) => {
+                let sent = r.end() - r.start() + 1;
+                let body = self.body(Some(r.clone())).await?;
+                (range::partial(body, r, len, &validators), sent)
+            }
+            Plan::Unsatisfiable => return Ok(range::unsatisfiable(len)),
+        };
+        // hyper can not know the length of a streaming body
+        r.headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(sent));
+        if let Some(t) = content_type {
+            r.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(t));
+        }
+        Ok(r)
+    }
+}
+
+/// Mime type of an image or a static asset from its file name.
+pub fn content_type(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "svg" => "image/svg+xml",
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        _ => return None,
+    })
+}
+
+#[test]
+fn t_pic_range() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let pic = || {
+            Pic::from_reader(
+                Box::pin(std::io::Cursor::new(b"0123456789".to_vec())),
+                10,
+                Validators::default(),
+                content_type("1.png"),
+            )
+        };
+        let mut h = HeaderMap::new();
+        h.insert(header::RANGE, HeaderValue::from_static("bytes=3-5"));
+        let r = pic().respond(&h).await.unwrap();
+        assert_eq!(r.status(), hyper::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(r.headers()[header::CONTENT_LENGTH], "3");
+        assert_eq!(r.headers()[header::CONTENT_TYPE], "image/png");
+        let body = hyper::body::to_bytes(r.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"345");
+        assert_eq!(pic().bytes().await.unwrap(), b"0123456789");
+    });
 }
 
 #[test]
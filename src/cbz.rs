@@ -0,0 +1,545 @@
+//! Chapters and whole series as CBZ, a zip of the pages written while it is
+//! sent. Pages are stored as they are, pages that already sit in a zip are
+//! copied without being decompressed.
+use std::{ops::RangeInclusive, pin::Pin, time::SystemTime};
+
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    backend::{ArchivePage, BackendTrait},
+    maintenance::chapter_length,
+    manga_list::{ChapterBasicInfo, MangaInfo},
+    range::{self, Plan, Validators},
+    utils::xml_escape,
+    zip::{self, ZipEntry},
+};
+
+pub const CONTENT_TYPE: &str = "application/vnd.comicbook+zip";
+
+enum Source {
+    Inline(Vec<u8>),
+    Page { chapter: String, page: usize },
+}
+
+struct Member {
+    entry: ZipEntry,
+    flags: u16,
+    source: Source,
+}
+
+/// Layout of an archive, planned before sending so the length is known.
+pub struct Archive {
+    base_path: String,
+    manga_id: String,
+    members: Vec<Member>,
+    pub len: u64,
+    pub validators: Validators,
+    pub file_name: String,
+}
+
+/// Plans the archive of one chapter of `manga`, the chapter at `chapter` in
+/// `manga.chapters`, or of all of them with one folder per chapter.
+pub async fn plan<B: BackendTrait>(
+    base_path: &str,
+    manga: &MangaInfo,
+    chapter: Option<usize>,
+) -> anyhow::Result<Archive> {
+    let chapters = match chapter {
+        Some(i) => vec![(i, &manga.chapters[i])],
+        None => manga.chapters.iter().enumerate().collect(),
+    };
+    let mut members = Vec::new();
+    let mut tags = md5::Context::new();
+    let mut last_modified = None::<SystemTime>;
+    for (i, c) in &chapters {
+        let length = chapter_length::<B>(base_path, &manga.id, &c.id, c.length).await?;
+        let folder = match chapter {
+            Some(_) => String::new(),
+            None => format!("{:03} {}/", i + 1, file_name(&c.name)),
+        };
+        for page in 0..length {
+            let (mut entry, flags, tag, modified) =
+                match B::get_archive_page(base_path, &manga.id, &c.id, page).await? {
+                    Some(ArchivePage::Pic(pic)) => {
+                        let (dos_time, dos_date) = pic
+                            .validators
+                            .last_modified
+                            .map(zip::dos_date_time)
+                            .unwrap_or((0, (1 << 5) | 1));
+                        let entry = ZipEntry {
+                            name: extension(pic.content_type).to_owned(),
+                            method: zip::STORED,
+                            dos_time,
+                            dos_date,
+                            // known once the page is sent, see `write`
+                            crc32: 0,
+                            compressed_size: pic.len,
+                            uncompressed_size: pic.len,
+                            header_offset: 0,
+                        };
+                        let tag = pic.validators.etag.clone().unwrap_or_default();
+                        let flags = zip::FLAG_UTF8 | zip::FLAG_DESCRIPTOR;
+                        (entry, flags, tag, pic.validators.last_modified)
+                    }
+                    Some(ArchivePage::Zip {
+                        mut entry,
+                        modified,
+                        ..
+                    }) => {
+                        entry.name = match entry.name.rsplit_once('.') {
+                            Some((_, ext)) => ext.to_ascii_lowercase(),
+                            None => "jpg".to_owned(),
+                        };
+                        let tag = format!("{:08x}", entry.crc32);
+                        (entry, zip::FLAG_UTF8, tag, modified)
+                    }
+                    None => anyhow::bail!("page {} of chapter {} not found", page, c.id),
+                };
+            entry.name = format!("{}{:04}.{}", folder, page + 1, entry.name);
+            tags.consume(format!(
+                "{}:{}:{}\n",
+                entry.name, entry.compressed_size, tag
+            ));
+            last_modified = last_modified.max(modified);
+            members.push(Member {
+                entry,
+                flags,
+                source: Source::Page {
+                    chapter: c.id.clone(),
+                    page,
+                },
+            });
+        }
+    }
+
+    let info = comic_info(
+        manga,
+        chapter.map(|i| (i, &manga.chapters[i])),
+        members.len(),
+    );
+    tags.consume(&info);
+    let info = info.into_bytes();
+    let (dos_time, dos_date) = last_modified
+        .map(zip::dos_date_time)
+        .unwrap_or((0, (1 << 5) | 1));
+    members.insert(
+        0,
+        Member {
+            entry: ZipEntry {
+                name: "ComicInfo.xml".to_owned(),
+                method: zip::STORED,
+                dos_time,
+                dos_date,
+                crc32: crc32fast::hash(&info),
+                compressed_size: info.len() as u64,
+                uncompressed_size: info.len() as u64,
+                header_offset: 0,
+            },
+            flags: zip::FLAG_UTF8,
+            source: Source::Inline(info),
+        },
+    );
+
+    if let Some(m) = members
+        .iter()
+        .find(|m| m.entry.compressed_size >= u32::MAX as u64)
+    {
+        anyhow::bail!("{} is too large for an archive", m.entry.name);
+    }
+    let len = layout(&mut members);
+    let file_name = match chapter {
+        Some(i) => format!(
+            "{} - {}.cbz",
+            file_name(&manga.name),
+            file_name(&manga.chapters[i].name)
+        ),
+        None => format!("{}.cbz", file_name(&manga.name)),
+    };
+    Ok(Archive {
+        base_path: base_path.to_owned(),
+        manga_id: manga.id.clone(),
+        members,
+        len,
+        validators: Validators {
+            etag: Some(format!("\"{:x}\"", tags.compute())),
+            last_modified,
+        },
+        file_name,
+    })
+}
+
+/// Length of the local header, data and data descriptor of `m`.
+fn member_len(m: &Member) -> u64 {
+    let descriptor = if m.flags & zip::FLAG_DESCRIPTOR != 0 {
+        zip::DESCRIPTOR_LEN
+    } else {
+        0
+    };
+    zip::LOCAL_HEADER_LEN + m.entry.name.len() as u64 + m.entry.compressed_size + descriptor
+}
+
+/// Places the members one after the other and returns the length of the
+/// archive.
+fn layout(members: &mut [Member]) -> u64 {
+    let mut offset = 0;
+    for m in members.iter_mut() {
+        m.entry.header_offset = offset;
+        offset += member_len(m);
+    }
+    let central_len = members
+        .iter()
+        .map(|m| m.entry.central_header_len())
+        .sum::<u64>();
+    offset + central_len + zip::end_records(members.len() as u64, central_len, offset).len() as u64
+}
+
+pub(crate) fn extension(content_type: Option<&str>) -> &'static str {
+    match content_type {
+        Some("image/png") => "png",
+        Some("image/gif") => "gif",
+        Some("image/webp") => "webp",
+        _ => "jpg",
+    }
+}
+
+/// A name that is safe as a file or folder name everywhere.
+pub(crate) fn file_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    name.trim().trim_end_matches('.').to_owned()
+}
+
+/// The `ComicInfo.xml` readers look for, see the anansi project schema.
+fn comic_info(
+    manga: &MangaInfo,
+    chapter: Option<(usize, &ChapterBasicInfo)>,
+    pages: usize,
+) -> String {
+    let mut fields = vec![("Series", xml_escape(&manga.name))];
+    match chapter {
+        Some((i, c)) => {
+            fields.push(("Title", xml_escape(&c.name)));
+            fields.push(("Number", (i + 1).to_string()));
+        }
+        None => fields.push(("Count", manga.chapters.len().to_string())),
+    }
+    fields.push(("PageCount", pages.to_string()));
+    fields.push(("Manga", "Yes".to_owned()));
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n",
+    );
+    for (k, v) in fields {
+        out.push_str(&format!("  <{0}>{1}</{0}>\n", k, v));
+    }
+    out.push_str("</ComicInfo>\n");
+    out
+}
+
+/// Passes on the bytes `start..=end` of what is written to it and drops the
+/// rest.
+struct Window<W> {
+    w: W,
+    pos: u64,
+    start: u64,
+    end: u64,
+}
+impl<W: AsyncWrite + Unpin> Window<W> {
+    async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let len = buf.len() as u64;
+        let from = self.start.saturating_sub(self.pos).min(len) as usize;
+        let to = (self.end + 1).saturating_sub(self.pos).min(len) as usize;
+        if from < to {
+            self.w.write_all(&buf[from..to]).await?;
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Writes the bytes in `range` of the archive, fails if a page changed since
+/// it was planned. Members before the range are not read unless the central
+/// directory is wanted and needs their crc.
+async fn write<B: BackendTrait>(
+    archive: Archive,
+    w: impl AsyncWrite + Unpin,
+    range: RangeInclusive<u64>,
+) -> anyhow::Result<()> {
+    let mut w = Window {
+        w,
+        pos: 0,
+        start: *range.start(),
+        end: *range.end(),
+    };
+    let central_offset = archive
+        .members
+        .last()
+        .map_or(0, |m| m.entry.header_offset + member_len(m));
+    let wants_central = w.end >= central_offset;
+    let mut central = Vec::new();
+    let count = archive.members.len();
+    for mut m in archive.members {
+        if w.pos > w.end {
+            break;
+        }
+        let header = m.entry.local_header(m.flags);
+        let descriptor = m.flags & zip::FLAG_DESCRIPTOR != 0;
+        let member_len = member_len(&m);
+        if w.pos + member_len <= w.start && !(descriptor && wants_central) {
+            w.pos += member_len;
+            central.extend(m.entry.central_header(m.flags));
+            continue;
+        }
+        w.write_all(&header).await?;
+        let reader: Pin<Box<dyn AsyncRead + Send + Sync>> = match m.source {
+            Source::Inline(data) => Box::pin(std::io::Cursor::new(data)),
+            Source::Page { chapter, page } => {
+                match B::get_archive_page(&archive.base_path, &archive.manga_id, &chapter, page)
+                    .await?
+                {
+                    Some(ArchivePage::Pic(pic)) => pic.reader(None).await?,
+                    Some(ArchivePage::Zip { path, entry, .. }) => {
+                        Box::pin(zip::open_raw(&path, &entry).await?)
+                    }
+                    None => anyhow::bail!("page {} of chapter {} is gone", page, chapter),
+                }
+            }
+        };
+        let (written, crc) = copy(reader, &mut w).await?;
+        if written != m.entry.compressed_size {
+            anyhow::bail!("{} changed while it was sent", m.entry.name);
+        }
+        if descriptor {
+            m.entry.crc32 = crc;
+            w.write_all(&m.entry.data_descriptor()).await?;
+        }
+        central.extend(m.entry.central_header(m.flags));
+    }
+    if wants_central {
+        w.write_all(&central).await?;
+        w.write_all(&zip::end_records(
+            count as u64,
+            central.len() as u64,
+            central_offset,
+        ))
+        .await?;
+    }
+    w.w.shutdown().await?;
+    Ok(())
+}
+
+/// Copies everything, returning the length and crc of what was copied.
+async fn copy<W: AsyncWrite + Unpin>(
+    mut r: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    w: &mut Window<W>,
+) -> std::io::Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            return Ok((len, hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        w.write_all(&buf[..n]).await?;
+        len += n as u64;
+    }
+}
+
+/// Sends the archive as an attachment, it is written by a task of its own
+/// while the client reads it. Ranges are honoured.
+pub async fn respond<B: BackendTrait>(
+    headers: &HeaderMap,
+    archive: Archive,
+) -> std::io::Result<Response<Body>> {
+    let len = archive.len;
+    let validators = archive.validators.clone();
+    let disposition = format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        archive
+            .file_name
+            .chars()
+            .map(|c| if c.is_ascii() && c != '"' { c } else { '_' })
+            .collect::<String>(),
+        percent_encoding::utf8_percent_encode(
+            &archive.file_name,
+            percent_encoding::NON_ALPHANUMERIC
+        )
+    );
+    // a range is written from the member it starts in
+    let range = match range::plan(headers, len, &validators) {
+        Plan::Full => None,
+        Plan::Partial(r) => Some(r),
+        Plan::Unsatisfiable => return Ok(range::unsatisfiable(len)),
+    };
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let wanted = range.clone().unwrap_or(0..=len.saturating_sub(1));
+    tokio::spawn(async move {
+        if let Err(e) = write::<B>(archive, writer, wanted).await {
+            // also when the client went away
+            tracing::debug!("archive not sent completely: {:#}", e);
+        }
+    });
+    let body = Body::wrap_stream(tokio_util::io::ReaderStream::new(reader));
+    let (mut r, sent) = match range {
+        None => (range::with_headers(Response::new(body), &validators), len),
+        Some(range) => {
+            let sent = range.end() - range.start() + 1;
+            (range::partial(body, range, len, &validators), sent)
+        }
+    };
+    let h = r.headers_mut();
+    h.insert(header::CONTENT_LENGTH, HeaderValue::from(sent));
+    h.insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+    if let Ok(v) = HeaderValue::from_str(&disposition) {
+        r.headers_mut().insert(header::CONTENT_DISPOSITION, v);
+    }
+    Ok(r)
+}
+
+#[test]
+fn t_write() {
+    let manga = crate::manga_list::test_manga("1", "A <b> & c", &[("ch/1", 0)]);
+    let info = comic_info(&manga, Some((0, &manga.chapters[0])), 0);
+    assert!(info.contains("<Series>A &lt;b&gt; &amp; c</Series>"));
+    assert!(info.contains("<Title>ch/1</Title>"));
+    assert_eq!(file_name("ch/1: end."), "ch_1_ end");
+
+    let data = info.into_bytes();
+    let archive = || {
+        let mut members = ["ComicInfo.xml", "001 a/0001.jpg", "001 a/0002.jpg"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| Member {
+                entry: ZipEntry {
+                    name: name.to_owned(),
+                    method: zip::STORED,
+                    dos_time: 0,
+                    dos_date: 33,
+                    crc32: if i == 2 { 0 } else { crc32fast::hash(&data) },
+                    compressed_size: data.len() as u64,
+                    uncompressed_size: data.len() as u64,
+                    header_offset: 0,
+                },
+                // the last one gets its crc while it is written
+                flags: if i == 2 {
+                    zip::FLAG_UTF8 | zip::FLAG_DESCRIPTOR
+                } else {
+                    zip::FLAG_UTF8
+                },
+                source: Source::Inline(data.clone()),
+            })
+            .collect::<Vec<_>>();
+        Archive {
+            base_path: String::new(),
+            manga_id: manga.id.clone(),
+            len: layout(&mut members),
+            members,
+            validators: Validators::default(),
+            file_name: "a.cbz".into(),
+        }
+    };
+    let len = archive().len;
+
+    let path = std::env::temp_dir().join(format!("manga-server-cbz-{}.cbz", std::process::id()));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let mut out = Vec::new();
+        write::<crate::dmzj::Dmzj>(archive(), &mut out, 0..=len - 1)
+            .await
+            .unwrap();
+        assert_eq!(out.len() as u64, len);
+        tokio::fs::write(&path, &out).await.unwrap();
+        let path = path.to_str().unwrap();
+        let entries = zip::read_entries(path).await.unwrap();
+        assert_eq!(entries[1].name, "001 a/0001.jpg");
+        assert_eq!(entries[2].crc32, crc32fast::hash(&data));
+        let mut read = Vec::new();
+        zip::open_entry(path, &entries[1])
+            .await
+            .unwrap()
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+
+        // ranges in a member, across members and in the central directory
+        for range in [
+            0..=9,
+            100..=len - 400,
+            len - 300..=len - 1,
+            len - 1..=len - 1,
+        ] {
+            let mut part = Vec::new();
+            write::<crate::dmzj::Dmzj>(archive(), &mut part, range.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                part,
+                out[*range.start() as usize..=*range.end() as usize],
+                "{:?}",
+                range
+            );
+        }
+    });
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn t_many_members() {
+    // more members than the plain end of central directory can count
+    let mut members = (0..=u16::MAX as usize)
+        .map(|i| Member {
+            entry: ZipEntry {
+                name: format!("{:05}.jpg", i),
+                method: zip::STORED,
+                dos_time: 0,
+                dos_date: 33,
+                crc32: crc32fast::hash(b"p"),
+                compressed_size: 1,
+                uncompressed_size: 1,
+                header_offset: 0,
+            },
+            flags: zip::FLAG_UTF8,
+            source: Source::Inline(b"p".to_vec()),
+        })
+        .collect::<Vec<_>>();
+    let archive = Archive {
+        base_path: String::new(),
+        manga_id: "1".into(),
+        len: layout(&mut members),
+        members,
+        validators: Validators::default(),
+        file_name: "a.cbz".into(),
+    };
+    let len = archive.len;
+
+    let path = std::env::temp_dir().join(format!("manga-server-cbz64-{}.cbz", std::process::id()));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let mut out = Vec::new();
+        write::<crate::dmzj::Dmzj>(archive, &mut out, 0..=len - 1)
+            .await
+            .unwrap();
+        assert_eq!(out.len() as u64, len);
+        tokio::fs::write(&path, &out).await.unwrap();
+        let entries = zip::read_entries(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(entries.len(), u16::MAX as usize + 1);
+        assert_eq!(entries[u16::MAX as usize].name, "65535.jpg");
+    });
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,258 @@
+//! `Accept-Encoding` negotiation. Json and other in-memory text bodies are
+//! compressed per response, static assets once per encoding and kept.
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+    Level,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response, StatusCode,
+};
+use tokio::io::AsyncReadExt;
+
+use crate::range::Validators;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Picks the encoding with the highest q value, ties go to the smaller output.
+pub fn negotiate(headers: &HeaderMap) -> Encoding {
+    let accept = match headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(a) => a,
+        None => return Encoding::Identity,
+    };
+    let mut best = (Encoding::Identity, 0.0);
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let encoding = match name.as_str() {
+            "br" => Encoding::Brotli,
+            "zstd" => Encoding::Zstd,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let rank = |e: Encoding| {
+            [Encoding::Gzip, Encoding::Zstd, Encoding::Brotli]
+                .iter()
+                .position(|x| *x == e)
+        };
+        if q > 0.0 && (q > best.1 || (q == best.1 && rank(encoding) > rank(best.0))) {
+            best = (encoding, q);
+        }
+    }
+    best.0
+}
+
+/// Text formats worth compressing, images already are.
+pub fn compressible(content_type: &str) -> bool {
+    let t = content_type.split(';').next().unwrap_or("").trim();
+    t.starts_with("text/")
+        || t.ends_with("+xml")
+        || matches!(
+            t,
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
+pub async fn compress(data: &[u8], encoding: Encoding, level: Level) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match encoding {
+        Encoding::Identity => out.extend_from_slice(data),
+        Encoding::Gzip => {
+            GzipEncoder::with_quality(data, level)
+                .read_to_end(&mut out)
+                .await?;
+        }
+        Encoding::Brotli => {
+            BrotliEncoder::with_quality(data, level)
+                .read_to_end(&mut out)
+                .await?;
+        }
+        Encoding::Zstd => {
+            ZstdEncoder::with_quality(data, level)
+                .read_to_end(&mut out)
+                .await?;
+        }
+    }
+    Ok(out)
+}
+
+fn content_type<T>(r: &Response<T>) -> Option<&str> {
+    r.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Compresses a complete in-memory response if the client accepts it and
+/// the content is text. Streamed, partial and encoded responses pass through.
+pub async fn encode(
+    request: &HeaderMap,
+    response: Response<Body>,
+    min_size: usize,
+) -> anyhow::Result<Response<Body>> {
+    let len = match response.body().size_hint().exact() {
+        Some(l) => l as usize,
+        None => return Ok(response),
+    };
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+        || !content_type(&response).is_some_and(compressible)
+    {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let encoding = negotiate(request);
+    if encoding == Encoding::Identity || len < min_size {
+        return Ok(Response::from_parts(parts, body));
+    }
+    let data = hyper::body::to_bytes(body).await?;
+    let out = compress(&data, encoding, Level::Fastest).await?;
+    set_encoded(&mut parts.headers, encoding, out.len());
+    Ok(Response::from_parts(parts, Body::from(out)))
+}
+
+fn set_encoded(h: &mut HeaderMap, encoding: Encoding, len: usize) {
+    h.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    h.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    // a different representation, ranges would apply to the encoded bytes
+    h.remove(header::ACCEPT_RANGES);
+    if let Some(etag) = h.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+        let tagged = format!("{}-{}\"", etag.trim_end_matches('"'), encoding.name());
+        if let Ok(v) = HeaderValue::from_str(&tagged) {
+            h.insert(header::ETAG, v);
+        }
+    }
+}
+
+struct CachedAsset {
+    modified: Option<SystemTime>,
+    data: Bytes,
+}
+
+lazy_static::lazy_static! {
+    /// Compressed static files by path and encoding.
+    static ref STATIC: Mutex<HashMap<(String, Encoding), CachedAsset>> = Mutex::new(HashMap::new());
+}
+
+/// A static file compressed with `encoding`, compressed again when the file changed.
+pub async fn static_file(
+    path: &str,
+    encoding: Encoding,
+    content_type: &'static str,
+) -> std::io::Result<Response<Body>> {
+    let meta = tokio::fs::metadata(path).await?;
+    let validators = Validators::from_metadata(&meta);
+    let key = (path.to_owned(), encoding);
+    let cached = STATIC
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|c| c.modified == validators.last_modified)
+        .map(|c| c.data.clone());
+    let data = match cached {
+        Some(d) => d,
+        None => {
+            let raw = tokio::fs::read(path).await?;
+            let data = Bytes::from(compress(&raw, encoding, Level::Best).await?);
+            STATIC.lock().unwrap().insert(
+                key,
+                CachedAsset {
+                    modified: validators.last_modified,
+                    data: data.clone(),
+                },
+            );
+            data
+        }
+    };
+    let mut r = crate::range::with_headers(Response::new(Body::from(data.clone())), &validators);
+    let h = r.headers_mut();
+    h.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    h.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    set_encoded(h, encoding, data.len());
+    Ok(r)
+}
+
+#[test]
+fn t_negotiate() {
+    let h = |v: &'static str| {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(v));
+        h
+    };
+    assert_eq!(negotiate(&HeaderMap::new()), Encoding::Identity);
+    assert_eq!(negotiate(&h("gzip, deflate")), Encoding::Gzip);
+    assert_eq!(negotiate(&h("gzip, deflate, br, zstd")), Encoding::Brotli);
+    assert_eq!(negotiate(&h("br;q=0.5, gzip")), Encoding::Gzip);
+    assert_eq!(negotiate(&h("br;q=0, zstd")), Encoding::Zstd);
+    assert_eq!(negotiate(&h("identity")), Encoding::Identity);
+}
+
+#[test]
+fn t_encode() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let json = || {
+            let mut r = Response::new(Body::from("[1,2,3]".repeat(100)));
+            r.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            r
+        };
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let r = encode(&h, json(), 100).await.unwrap();
+        assert_eq!(r.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(r.headers()[header::VARY], "accept-encoding");
+        let body = hyper::body::to_bytes(r.into_body()).await.unwrap();
+        let mut plain = String::new();
+        async_compression::tokio::bufread::GzipDecoder::new(&body[..])
+            .read_to_string(&mut plain)
+            .await
+            .unwrap();
+        assert_eq!(plain, "[1,2,3]".repeat(100));
+
+        // too small, and images are left alone
+        let r = encode(&h, json(), 10_000).await.unwrap();
+        assert!(!r.headers().contains_key(header::CONTENT_ENCODING));
+        let mut image = Response::new(Body::from(vec![0; 2000]));
+        image
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+        let r = encode(&h, image, 100).await.unwrap();
+        assert!(!r.headers().contains_key(header::CONTENT_ENCODING));
+    });
+}
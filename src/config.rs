@@ -0,0 +1,714 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{bail, Context};
+
+use crate::net::{Cidr, ListenAddr};
+
+/// Prefix of the environment variables that override config keys.
+///
+/// `MANGA_SERVER_PORT=8080` sets `port`, nested keys are separated by a
+/// double underscore, e.g. `MANGA_SERVER_DMZJ__PATH_ZIPS` sets `dmzj.path_zips`.
+pub const ENV_PREFIX: &str = "MANGA_SERVER_";
+/// Environment variable holding the config file path, used when `--config` is not given.
+pub const ENV_CONFIG_PATH: &str = "MANGA_SERVER_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+/// Keys holding strings, their environment variables are taken as given.
+/// Other variables are read as toml numbers and booleans when they parse.
+const STRING_KEYS: &[&str] = &[
+    "bind",
+    "progress_file",
+    "webhook_log",
+    "resources",
+    "backend",
+    "tls.cert",
+    "tls.key",
+    "tls.redirect_http",
+    "log.level",
+    "log.format",
+    "log.access_log",
+    "log.access_log_format",
+    "memory.manifest",
+    "dmzj.path_zips",
+    "dmzj.path_mapping",
+    "copy_manga.path",
+    "eh.path",
+    "shaft.path",
+];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[serde(rename = "dmzj")]
+    DMZJ,
+    CopyManga,
+    Eh,
+    Shaft,
+    /// Generated pages or a manifest, see [`crate::memory`].
+    Memory,
+    /// A backend registered with [`crate::server::ServerBuilder::backend`],
+    /// it can not be picked in the config file.
+    #[serde(skip)]
+    Custom,
+}
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::DMZJ => "dmzj",
+            Backend::CopyManga => "copy_manga",
+            Backend::Eh => "eh",
+            Backend::Shaft => "shaft",
+            Backend::Memory => "memory",
+            Backend::Custom => "custom",
+        }
+    }
+}
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Backend::DMZJ,
+            Backend::CopyManga,
+            Backend::Eh,
+            Backend::Shaft,
+            Backend::Memory,
+        ]
+        .into_iter()
+        .find(|b| b.name() == s)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "unknown backend `{}`, expected dmzj, copy_manga, eh, shaft or memory",
+                s
+            )
+        })
+    }
+}
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_bind")]
+    pub bind: IpAddr,
+    pub port: Option<u16>,
+    /// Listeners to open, `bind` and `port` are ignored when this is set.
+    #[serde(default)]
+    pub listen: Vec<ListenAddr>,
+    /// Peers allowed to set `X-Forwarded-For` and `X-Forwarded-Proto`.
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Serve https on the tcp listeners.
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub graphql: GraphqlConfig,
+    /// Json file keeping where users stopped reading, kept in memory only
+    /// when not set.
+    pub progress_file: Option<PathBuf>,
+    /// Seconds between scans of the library for new chapters, the library
+    /// is only scanned at start when not set.
+    pub rescan_interval: Option<u64>,
+    /// Urls posted to when the library changes or a scan ends.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// File to append one json line per webhook delivery attempt to.
+    pub webhook_log: Option<String>,
+    /// Seconds to wait for in-flight requests after a shutdown signal.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Directory with the bundled pages, `res` in the working directory
+    /// when not set.
+    pub resources: Option<String>,
+    pub backend: Backend,
+    pub dmzj: Option<DmzjConfig>,
+    pub copy_manga: Option<PathConfig>,
+    pub eh: Option<PathConfig>,
+    pub shaft: Option<PathConfig>,
+    pub memory: Option<MemoryConfig>,
+}
+
+/// What the `memory` backend serves, generated pages unless `manifest` is set.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Toml file listing series, chapters and page files.
+    pub manifest: Option<String>,
+    #[serde(default = "default_memory_series")]
+    pub series: usize,
+    #[serde(default = "default_memory_chapters")]
+    pub chapters: usize,
+    #[serde(default = "default_memory_pages")]
+    pub pages: usize,
+}
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            manifest: None,
+            series: default_memory_series(),
+            chapters: default_memory_chapters(),
+            pages: default_memory_pages(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DmzjConfig {
+    pub path_zips: String,
+    pub path_mapping: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Pem file with the certificate chain.
+    pub cert: String,
+    /// Pem file with the private key.
+    pub key: String,
+    /// Plain http listener redirecting every request to https.
+    pub redirect_http: Option<std::net::SocketAddr>,
+    /// Seconds between checks for a renewed certificate.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in `RUST_LOG` syntax, e.g. `info` or `manga_server=debug`.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// File to append one line per request to.
+    pub access_log: Option<String>,
+    #[serde(default)]
+    pub access_log_format: AccessLogFormat,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            format: LogFormat::default(),
+            access_log: None,
+            access_log_format: AccessLogFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Compress text responses for clients that accept it.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Smaller bodies are sent as they are.
+    #[serde(default = "default_min_size")]
+    pub min_size: usize,
+}
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: default_min_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphqlConfig {
+    /// Deepest nesting of fields a query may have.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Highest cost of a query, lists count as many times as they may be long.
+    #[serde(default = "default_max_complexity")]
+    pub max_complexity: usize,
+    /// Most operations in one batched request.
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+}
+impl Default for GraphqlConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: default_max_depth(),
+            max_complexity: default_max_complexity(),
+            max_batch: default_max_batch(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// http or https url the events are posted to.
+    pub url: String,
+    /// Event types to send, see [`crate::events::KINDS`], library changes
+    /// and failed scans when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Key for the `X-Manga-Server-Signature` hmac of the body.
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// Apache combined log format.
+    #[default]
+    Combined,
+    Json,
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_min_size() -> usize {
+    1024
+}
+
+fn default_max_depth() -> usize {
+    10
+}
+
+fn default_max_complexity() -> usize {
+    20_000
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathConfig {
+    pub path: String,
+}
+
+fn default_memory_series() -> usize {
+    3
+}
+
+fn default_memory_chapters() -> usize {
+    2
+}
+
+fn default_memory_pages() -> usize {
+    5
+}
+
+fn default_max_batch() -> usize {
+    10
+}
+
+fn default_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
+}
+
+/// Values given on the command line, they take precedence over the config
+/// file and the environment.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub backend: Option<Backend>,
+}
+
+impl Config {
+    /// A config with every option at its default, for servers set up in code.
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            bind: default_bind(),
+            port: None,
+            listen: Vec::new(),
+            trusted_proxies: Vec::new(),
+            tls: None,
+            log: LogConfig::default(),
+            compression: CompressionConfig::default(),
+            graphql: GraphqlConfig::default(),
+            progress_file: None,
+            rescan_interval: None,
+            webhooks: Vec::new(),
+            webhook_log: None,
+            shutdown_timeout: default_shutdown_timeout(),
+            resources: None,
+            backend,
+            dmzj: None,
+            copy_manga: None,
+            eh: None,
+            shaft: None,
+            memory: None,
+        }
+    }
+
+    /// Reads the config file at `path`, applies environment and command line
+    /// overrides and validates the result.
+    pub fn load(path: &Path, overrides: &Overrides) -> anyhow::Result<Self> {
+        let f = std::fs::read_to_string(path)
+            .with_context(|| format!("can not read config file `{}`", path.display()))?;
+        let mut table: toml::Table = toml::from_str(&f)
+            .with_context(|| format!("`{}` is not valid toml", path.display()))?;
+        apply_env(&mut table, std::env::vars())?;
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("invalid config in `{}`", path.display()))?;
+        if overrides.bind.is_some() || overrides.port.is_some() {
+            config.listen.clear();
+        }
+        if let Some(bind) = overrides.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = overrides.port {
+            config.port = Some(port);
+        }
+        if let Some(backend) = overrides.backend {
+            config.backend = backend;
+        }
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.resolve_paths(base);
+        config
+            .validate()
+            .with_context(|| format!("invalid config in `{}`", path.display()))?;
+        Ok(config)
+    }
+
+    /// Makes relative paths relative to the directory holding the config file.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |p: &mut String| {
+            if Path::new(p).is_relative() && !base.as_os_str().is_empty() {
+                *p = base.join(&*p).to_string_lossy().into_owned();
+            }
+        };
+        if let Some(c) = &mut self.dmzj {
+            resolve(&mut c.path_zips);
+            resolve(&mut c.path_mapping);
+        }
+        for c in [&mut self.copy_manga, &mut self.eh, &mut self.shaft]
+            .into_iter()
+            .flatten()
+        {
+            resolve(&mut c.path);
+        }
+        if let Some(p) = self.memory.as_mut().and_then(|c| c.manifest.as_mut()) {
+            resolve(p);
+        }
+        if let Some(p) = &mut self.log.access_log {
+            resolve(p);
+        }
+        if let Some(p) = &mut self.webhook_log {
+            resolve(p);
+        }
+        if let Some(p) = &mut self.resources {
+            resolve(p);
+        }
+        if let Some(c) = &mut self.tls {
+            resolve(&mut c.cert);
+            resolve(&mut c.key);
+        }
+        for l in &mut self.listen {
+            if let ListenAddr::Unix(p) = l {
+                if p.is_relative() {
+                    *p = base.join(&*p);
+                }
+            }
+        }
+    }
+
+    pub fn resources(&self) -> &str {
+        self.resources.as_deref().unwrap_or("res")
+    }
+
+    /// Addresses to accept connections on.
+    pub fn listeners(&self) -> Vec<ListenAddr> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        self.port
+            .map(|port| ListenAddr::Tcp(std::net::SocketAddr::new(self.bind, port)))
+            .into_iter()
+            .collect()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fn dir(key: &str, p: &str) -> anyhow::Result<()> {
+            if !Path::new(p).is_dir() {
+                bail!("`{}` = `{}` is not a directory", key, p);
+            }
+            Ok(())
+        }
+        fn file(key: &str, p: &str) -> anyhow::Result<()> {
+            if !Path::new(p).is_file() {
+                bail!("`{}` = `{}` is not a file", key, p);
+            }
+            Ok(())
+        }
+        let missing = || {
+            anyhow::anyhow!(
+                "backend `{}` is selected but the [{}] section is missing",
+                self.backend,
+                self.backend
+            )
+        };
+        if self.listen.is_empty() && self.port.is_none() {
+            bail!("either `port` or `listen` has to be set");
+        }
+        if let Some(p) = &self.resources {
+            dir("resources", p)?;
+        }
+        if self.rescan_interval == Some(0) {
+            bail!("`rescan_interval` has to be at least 1");
+        }
+        for hook in &self.webhooks {
+            match url::Url::parse(&hook.url) {
+                Ok(u) if matches!(u.scheme(), "http" | "https") && u.has_host() => {}
+                _ => bail!("webhook url `{}` is not an http or https url", hook.url),
+            }
+            if let Some(e) = hook
+                .events
+                .iter()
+                .find(|e| !crate::events::KINDS.contains(&e.as_str()))
+            {
+                bail!(
+                    "unknown webhook event `{}`, expected one of {}",
+                    e,
+                    crate::events::KINDS.join(", ")
+                );
+            }
+        }
+        if let Some(tls) = &self.tls {
+            file("tls.cert", &tls.cert)?;
+            file("tls.key", &tls.key)?;
+            if !self
+                .listeners()
+                .iter()
+                .any(|l| matches!(l, ListenAddr::Tcp(_)))
+            {
+                bail!("[tls] is set but there is no tcp listener to serve https on");
+            }
+            if tls.reload_interval == 0 {
+                bail!("`tls.reload_interval` has to be at least 1");
+            }
+        }
+        match self.backend {
+            Backend::DMZJ => {
+                let c = self.dmzj.as_ref().ok_or_else(missing)?;
+                dir("dmzj.path_zips", &c.path_zips)?;
+                file("dmzj.path_mapping", &c.path_mapping)?;
+            }
+            Backend::CopyManga => dir(
+                "copy_manga.path",
+                &self.copy_manga.as_ref().ok_or_else(missing)?.path,
+            )?,
+            Backend::Eh => dir("eh.path", &self.eh.as_ref().ok_or_else(missing)?.path)?,
+            Backend::Shaft => dir("shaft.path", &self.shaft.as_ref().ok_or_else(missing)?.path)?,
+            Backend::Memory => {
+                if let Some(p) = self.memory.as_ref().and_then(|c| c.manifest.as_ref()) {
+                    file("memory.manifest", p)?;
+                }
+            }
+            Backend::Custom => {}
+        }
+        Ok(())
+    }
+
+    pub fn dmzj(&self) -> &DmzjConfig {
+        self.dmzj
+            .as_ref()
+            .expect("[dmzj] section missing from config")
+    }
+    pub fn copy_manga(&self) -> &PathConfig {
+        self.copy_manga
+            .as_ref()
+            .expect("[copy_manga] section missing from config")
+    }
+    pub fn eh(&self) -> &PathConfig {
+        self.eh.as_ref().expect("[eh] section missing from config")
+    }
+    pub fn shaft(&self) -> &PathConfig {
+        self.shaft
+            .as_ref()
+            .expect("[shaft] section missing from config")
+    }
+}
+
+/// Picks the config file: the `--config` flag, then `MANGA_SERVER_CONFIG`,
+/// then `config.toml` in the working directory.
+pub fn config_path(flag: Option<PathBuf>) -> PathBuf {
+    flag.or_else(|| std::env::var_os(ENV_CONFIG_PATH).map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Puts `MANGA_SERVER_*` variables into `table`, typed by [`STRING_KEYS`].
+fn apply_env(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<()> {
+    for (k, v) in vars {
+        let key = match k.strip_prefix(ENV_PREFIX) {
+            Some(key) if k != ENV_CONFIG_PATH => key.to_lowercase(),
+            _ => continue,
+        };
+        let parts = key.split("__").map(str::to_owned).collect::<Vec<_>>();
+        let value = if STRING_KEYS.contains(&parts.join(".").as_str()) {
+            toml::Value::String(v)
+        } else {
+            match toml::from_str::<toml::Table>(&format!("v = {}", v)) {
+                Ok(mut t) => t.remove("v").expect("parsed key"),
+                Err(_) => toml::Value::String(v),
+            }
+        };
+        insert(table, &parts, value).with_context(|| format!("`{}`", k))?;
+    }
+    Ok(())
+}
+
+fn insert(table: &mut toml::Table, key: &[String], value: toml::Value) -> anyhow::Result<()> {
+    let (last, sections) = key.split_last().expect("split yields at least one part");
+    let mut t = table;
+    for s in sections {
+        t = t
+            .entry(s.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("`{}` is not a table", s))?;
+    }
+    t.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Stores the config for the rest of the process, can only be called once.
+pub fn init(config: Config) -> anyhow::Result<()> {
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("config is already initialized"))
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config is not initialized")
+}
+
+#[test]
+fn t_env_override() {
+    let mut table: toml::Table =
+        toml::from_str("port = 1\nbackend = 'eh'\n[eh]\npath = 'a'").unwrap();
+    let vars = [
+        ("MANGA_SERVER_PORT", "8080"),
+        ("MANGA_SERVER_EH__PATH", "/srv/eh"),
+        ("MANGA_SERVER_DMZJ__PATH_ZIPS", "/srv/zips"),
+        ("MANGA_SERVER_CONFIG", "ignored.toml"),
+        ("HOME", "/root"),
+    ]
+    .map(|(k, v)| (k.to_string(), v.to_string()));
+    apply_env(&mut table, vars.into_iter()).unwrap();
+
+    assert_eq!(table["port"].as_integer(), Some(8080));
+    assert_eq!(table["eh"]["path"].as_str(), Some("/srv/eh"));
+    assert_eq!(table["dmzj"]["path_zips"].as_str(), Some("/srv/zips"));
+    assert!(!table.contains_key("config"));
+
+    // paths of digits stay strings, numbers still have to be numbers
+    table.remove("dmzj");
+    let vars = [
+        ("MANGA_SERVER_PORT", "8080"),
+        ("MANGA_SERVER_EH__PATH", "1234"),
+    ]
+    .map(|(k, v)| (k.to_string(), v.to_string()));
+    apply_env(&mut table, vars.into_iter()).unwrap();
+    let c: Config = toml::Value::Table(table.clone()).try_into().unwrap();
+    assert_eq!(c.port, Some(8080));
+    assert_eq!(c.eh.unwrap().path, "1234");
+    apply_env(
+        &mut table,
+        [("MANGA_SERVER_PORT".into(), "x".into())].into_iter(),
+    )
+    .unwrap();
+    assert!(toml::Value::Table(table).try_into::<Config>().is_err());
+}
+
+#[test]
+fn t_string_keys() {
+    // every listed key takes a string
+    let c =
+        "bind = '::1'\nprogress_file = 'p'\nwebhook_log = 'w'\nresources = 'r'\nbackend = 'eh'\n\
+        [tls]\ncert = 'c'\nkey = 'k'\nredirect_http = '[::]:80'\n\
+        [log]\nlevel = 'info'\nformat = 'json'\naccess_log = 'a'\naccess_log_format = 'combined'\n\
+        [memory]\nmanifest = 'm'\n[dmzj]\npath_zips = 'z'\npath_mapping = 'm'\n\
+        [copy_manga]\npath = 'c'\n[eh]\npath = 'e'\n[shaft]\npath = 's'";
+    let table: toml::Table = toml::from_str(c).unwrap();
+    assert!(toml::Value::Table(table.clone())
+        .try_into::<Config>()
+        .is_ok());
+    // and stays one when its variable looks like a number
+    for key in STRING_KEYS {
+        let var = format!("{}{}", ENV_PREFIX, key.replace('.', "__").to_uppercase());
+        let mut t = toml::Table::new();
+        apply_env(&mut t, [(var, "1234".to_owned())].into_iter()).unwrap();
+        let t = toml::Value::Table(t);
+        let v = key.split('.').fold(&t, |v, k| &v[k]);
+        assert!(v.is_str(), "{}", key);
+    }
+}
+
+#[test]
+fn t_reject_unknown() {
+    let e = toml::from_str::<Config>("port = 1\nbackend = 'eh'\nprot = 2").unwrap_err();
+    assert!(e.to_string().contains("unknown field `prot`"));
+    let e = toml::from_str::<Config>("port = 1\nbackend = 'foo'").unwrap_err();
+    assert!(e.to_string().contains("unknown variant `foo`"));
+    let e = toml::from_str::<Config>("listen = ['localhost:80']\nbackend = 'eh'").unwrap_err();
+    assert!(e.to_string().contains("is not a listen address"));
+}
+
+#[test]
+fn t_webhooks() {
+    let config = |hook: &str| {
+        toml::from_str::<Config>(&format!(
+            "port = 1\nbackend = 'eh'\n[eh]\npath = '.'\n[[webhooks]]\n{}",
+            hook
+        ))
+        .unwrap()
+    };
+    let c = config("url = 'https://example.com/hook'\nevents = ['chapters_added']");
+    assert!(c.validate().is_ok());
+    assert_eq!(c.webhooks[0].events, ["chapters_added"]);
+    let e = config("url = 'ftp://example.com'").validate().unwrap_err();
+    assert!(e.to_string().contains("not an http or https url"));
+    let e = config("url = 'http://example.com'\nevents = ['chapter_added']")
+        .validate()
+        .unwrap_err();
+    assert!(e
+        .to_string()
+        .contains("unknown webhook event `chapter_added`"));
+}
@@ -9,9 +9,9 @@ use std::{
 use serde::Serialize;
 
 use crate::{
-    backend::{BackendTrait, ChapterInfo},
+    backend::{BackendTrait, ChapterInfo, Pic},
     manga_list::{self, ChapterBasicInfo, MangaInfo, MangaList},
-    utils::ToResult,
+    utils::{modified_secs, ToResult},
 };
 
 #[test]
@@ -28,12 +28,7 @@ fn t_impl_backend() {
 #[async_trait::async_trait]
 impl BackendTrait for CopyManga {
     fn generate_manga_list() -> MangaList {
-        #[derive(Debug, Default, serde::Deserialize)]
-        struct Config {
-            path: String,
-        }
-        let f = std::fs::read_to_string("copy_manga.toml").unwrap();
-        let config: Config = toml::from_str(&f).unwrap();
+        let config = crate::config::get().copy_manga();
         let infos = read_all_infos(&config.path);
         let mut list = infos
             .iter()
@@ -54,11 +49,13 @@ impl BackendTrait for CopyManga {
                                 id: md5,
                                 name: chapter.to_owned(),
                                 length: info.length,
+                                added: modified_secs(format!("{}/{}/{}", config.path, k, chapter)),
                             };
                             out.push(info)
                         }
                         out
                     },
+                    added: modified_secs(format!("{}/{}", config.path, k)),
                 };
                 (md5, out)
             })
@@ -69,7 +66,7 @@ impl BackendTrait for CopyManga {
 
         MangaList {
             list: Arc::new(Mutex::new(list)),
-            path: Arc::new(config.path),
+            path: Arc::new(config.path.clone()),
             all_basic_info: Arc::new(Mutex::new(None)),
         }
     }
@@ -78,12 +75,10 @@ impl BackendTrait for CopyManga {
         manga_id: &str,
         chapter: &str,
         pic_id: usize,
-    ) -> anyhow::Result<Option<Vec<u8>>> {
-        // dbg!(&(manga_id, chapter, pic_id, bass_path));
+    ) -> anyhow::Result<Option<Pic>> {
         let (manga_name, chapter_name) = {
             let d = manga_list::get_list_ref().get_list_mut();
             let info = d.get(manga_id).to_result()?;
-            // dbg!(info);
             let mn = info.name.clone();
             let cn = info
                 .chapters
@@ -96,7 +91,6 @@ impl BackendTrait for CopyManga {
                 .clone();
             (mn, cn)
         };
-        // dbg!(manga_name, chapter_name);
         let path = format!(
             "{}/{}/{}/{:03}.jpg",
             base_path,
@@ -104,9 +98,8 @@ impl BackendTrait for CopyManga {
             chapter_name,
             pic_id + 1
         );
-        // dbg!(path);
-        let out = tokio::fs::read(path).await?;
-        Ok(Some(out))
+        tracing::trace!(path, "reading page");
+        Ok(Some(Pic::open(path).await?))
     }
     async fn get_chapter_info(
         _base_path: &str,
@@ -148,6 +141,7 @@ fn read_all_infos(path: &str) -> HashMap<String, MangaInfoLocal> {
     let mut h: HashMap<String, MangaInfoLocal> = HashMap::new();
     for e in walkdir::WalkDir::new(path) {
         let e = e.unwrap();
+        manga_list::scanned_entry();
         match e.depth() {
             3 => {
                 let p = e.path().as_os_str().to_str().unwrap();
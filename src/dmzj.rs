@@ -1,11 +1,20 @@
 use crate::{
-    backend::{BackendTrait, ChapterInfo},
+    backend::{content_type, ArchivePage, BackendTrait, ChapterInfo, Pic},
     manga_list::ChapterBasicInfo,
+    metrics,
+    range::Validators,
+    utils::modified_secs,
+    zip,
 };
 #[allow(unused_imports)]
 use std::io::Write as _;
 
-use std::{collections::HashMap, io::BufRead};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::BufRead,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Dmzj;
@@ -16,54 +25,41 @@ impl BackendTrait for Dmzj {
         use crate::manga_list::MangaInfo;
         use crate::manga_list::MangaList;
 
-        #[derive(Debug, Default, serde::Deserialize)]
-        struct Config {
-            path_zips: String,
-            path_mapping: String,
-        }
-        let f: String = std::fs::read_to_string("dmzj.toml").unwrap();
-        let config: Config = toml::from_str(&f).unwrap();
+        let config = crate::config::get().dmzj();
 
         let l = MangaList::new(&config.path_zips);
         let mapping = read_id_mapping(&config.path_mapping);
         let all = read_all_zips(&config.path_zips);
 
         for (k, v) in all {
-            if let Some(name) = mapping.get(&k) {
-                l.get_list_mut().insert(
-                    k.to_string(),
-                    MangaInfo {
-                        name: name.to_owned(),
-                        pic: format!("/manga/{}/{}/{}", k, v[0], 0),
-                        id: k.to_string(),
-                        chapters: v
-                            .iter()
-                            .map(|v| ChapterBasicInfo {
-                                id: v.to_string(),
-                                name: format!("id:{}", v),
-                                length: 0,
-                            })
-                            .collect(),
-                    },
-                );
-            } else {
-                l.get_list_mut().insert(
-                    k.to_string(),
-                    MangaInfo {
-                        name: k.to_string(),
-                        pic: format!("/manga/{}/{}/{}", k, v[0], 0),
-                        id: k.to_string(),
-                        chapters: v
-                            .iter()
-                            .map(|v| ChapterBasicInfo {
-                                id: v.to_string(),
-                                name: format!("id:{}", v),
-                                length: 0,
-                            })
-                            .collect(),
-                    },
-                );
-            }
+            let name = match mapping.get(&k) {
+                Some(name) => name.to_owned(),
+                None => k.to_string(),
+            };
+            let chapters: Vec<ChapterBasicInfo> = v
+                .iter()
+                .map(|v| ChapterBasicInfo {
+                    id: v.to_string(),
+                    name: format!("id:{}", v),
+                    length: 0,
+                    added: modified_secs(zip_path(
+                        &config.path_zips,
+                        &k.to_string(),
+                        &v.to_string(),
+                    )),
+                })
+                .collect();
+            let added = chapters.iter().map(|c| c.added).max().unwrap_or(0);
+            l.get_list_mut().insert(
+                k.to_string(),
+                MangaInfo {
+                    name,
+                    pic: format!("/manga/{}/{}/{}", k, v[0], 0),
+                    id: k.to_string(),
+                    chapters,
+                    added,
+                },
+            );
         }
 
         l
@@ -74,10 +70,34 @@ impl BackendTrait for Dmzj {
         manga_id: &str,
         chapter: &str,
         pic_id: usize,
-    ) -> anyhow::Result<Option<Vec<u8>>> {
+    ) -> anyhow::Result<Option<Pic>> {
         get_pic_in_chapter(bass_path, manga_id, chapter, pic_id).await
     }
 
+    async fn get_archive_page(
+        bass_path: &str,
+        manga_id: &str,
+        chapter: &str,
+        pic_id: usize,
+    ) -> anyhow::Result<Option<ArchivePage>> {
+        let path = zip_path(bass_path, manga_id, chapter);
+        let index = zip_index(&path).await?;
+        let pic_file = format!("{}.jpg", pic_id);
+        Ok(index
+            .entries
+            .iter()
+            .find(|e| e.name == pic_file)
+            .map(|e| ArchivePage::Zip {
+                path: path.clone(),
+                entry: e.clone(),
+                modified: index.modified,
+            }))
+    }
+
+    fn chapter_archive(bass_path: &str, manga_id: &str, chapter: &str) -> Option<String> {
+        Some(zip_path(bass_path, manga_id, chapter))
+    }
+
     async fn get_chapter_info(
         bass_path: &str,
         manga_id: &str,
@@ -91,54 +111,91 @@ impl BackendTrait for Dmzj {
     }
 }
 
+fn zip_path(bass_path: &str, manga_id: &str, chapter: &str) -> String {
+    format!(r"{}/{}_{}.zip", bass_path, manga_id, chapter)
+}
+
+/// Central directory of a zip.
+#[derive(Debug)]
+struct ZipIndex {
+    modified: Option<SystemTime>,
+    entries: Vec<zip::ZipEntry>,
+}
+
+const ZIP_INDEX_CAPACITY: usize = 256;
+type ZipIndexCache = (HashMap<String, Arc<ZipIndex>>, VecDeque<String>);
+
+lazy_static::lazy_static! {
+    /// Recently opened zips, so chapter info and page lookups do not have to
+    /// read the central directory every time. Oldest entries are evicted first.
+    static ref ZIP_INDEX: Mutex<ZipIndexCache> = Mutex::new((HashMap::new(), VecDeque::new()));
+}
+
+async fn zip_index(path: &str) -> anyhow::Result<Arc<ZipIndex>> {
+    let modified = tokio::fs::metadata(path).await?.modified().ok();
+    if let Some(i) = ZIP_INDEX.lock().unwrap().0.get(path) {
+        if i.modified == modified {
+            metrics::archive_cache_hit();
+            return Ok(i.clone());
+        }
+    }
+    metrics::archive_cache_miss();
+
+    let entries = zip::read_entries(path).await?;
+    let index = Arc::new(ZipIndex { modified, entries });
+
+    let (map, order) = &mut *ZIP_INDEX.lock().unwrap();
+    if map.insert(path.to_owned(), index.clone()).is_none() {
+        order.push_back(path.to_owned());
+        while order.len() > ZIP_INDEX_CAPACITY {
+            if let Some(old) = order.pop_front() {
+                map.remove(&old);
+            }
+        }
+    }
+    Ok(index)
+}
+
 async fn get_pic_in_chapter(
     bass_path: &str,
     manga_id: &str,
     chapter: &str,
     pic_id: usize,
-) -> anyhow::Result<Option<Vec<u8>>> {
-    use async_zip::tokio::read::seek::ZipFileReader;
-    use tokio::fs::File;
-    let mut file = File::open(&format!(r"{}/{}_{}.zip", bass_path, manga_id, chapter)).await?;
-    let mut zip = ZipFileReader::with_tokio(&mut file).await?;
-    let f = zip.file();
-
+) -> anyhow::Result<Option<Pic>> {
+    let path = zip_path(bass_path, manga_id, chapter);
     let pic_file = format!("{}.jpg", pic_id);
-
-    // let _e = f
-    //     .entries()
-    //     .iter()
-    //     .filter(|e| e.entry().filename().as_str().unwrap() == pic_file)
-    //     .collect::<Vec<_>>();
-
-    let e = {
-        let mut a = None;
-        for (id, e) in f.entries().iter().enumerate() {
-            if e.entry().filename().as_str()? == pic_file {
-                a = Some(id);
-                break;
-            };
-        }
-        match a {
-            Some(v) => v,
-            None => return Ok(None),
-        }
+    let index = zip_index(&path).await?;
+    let e = match index.entries.iter().find(|e| e.name == pic_file) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let validators = Validators {
+        etag: Some(format!("\"{:08x}-{:x}\"", e.crc32, e.uncompressed_size)),
+        last_modified: index.modified,
     };
+    Ok(Some(Pic::from_reader(
+        zip::open_entry(&path, e).await?,
+        e.uncompressed_size,
+        validators,
+        content_type(&e.name),
+    )))
+}
 
-    let mut reader = zip.reader_with_entry(e).await?;
-    let mut out = Vec::new();
-    let _ = reader.read_to_end_checked(&mut out).await?;
-    Ok(Some(out))
+/// Entry names of a zip file, from the cached index.
+pub(crate) async fn zip_entries(path: &str) -> anyhow::Result<Vec<String>> {
+    Ok(zip_index(path)
+        .await?
+        .entries
+        .iter()
+        .map(|e| e.name.clone())
+        .collect())
 }
 
 async fn get_zip_length(bass_path: &str, manga_id: &str, hua: &str) -> anyhow::Result<usize> {
-    use async_zip::tokio::read::seek::ZipFileReader;
-    use tokio::fs::File;
-    let mut file = File::open(&format!(r"{}/{}_{}.zip", bass_path, manga_id, hua)).await?;
-    let zip = ZipFileReader::with_tokio(&mut file).await?;
-    let f = zip.file();
-
-    Ok(f.entries().len())
+    Ok(zip_index(&zip_path(bass_path, manga_id, hua))
+        .await?
+        .entries
+        .len())
 }
 
 fn read_id_mapping(path: &str) -> HashMap<usize, String> {
@@ -163,7 +220,6 @@ fn read_id_mapping(path: &str) -> HashMap<usize, String> {
             .filter(|c| !c.is_whitespace())
             .collect::<String>()
             .to_owned();
-        //println!("{}:{}", id, name);
         id_mapping.insert(id, name);
     }
     id_mapping
@@ -174,6 +230,7 @@ fn read_all_zips(path: &str) -> HashMap<usize, Vec<usize>> {
     let mut o: HashMap<usize, Vec<usize>> = HashMap::new();
     for e in w {
         let e = e.unwrap();
+        crate::manga_list::scanned_entry();
         if e.file_type().is_file() {
         } else {
             continue;
@@ -272,26 +329,23 @@ fn t() {
 #[test]
 fn t_unzip() {
     let a = async {
-        use async_zip::tokio::read::seek::ZipFileReader;
-        use tokio::fs::File;
-        let mut file = File::open("./test.zip").await.unwrap();
-
-        let mut zip = ZipFileReader::with_tokio(&mut file).await.unwrap();
-
-        let mut string = String::new();
-
-        let f = zip.file();
-        for e in f.entries() {
-            if !e.entry().dir().unwrap() {
-                continue;
-            };
-            println!("{}", e.entry().filename().as_str().unwrap());
+        use tokio::io::AsyncReadExt;
+        let entries = zip::read_entries("./test.zip").await.unwrap();
+        for e in &entries {
+            if e.name.ends_with('/') {
+                println!("{}", e.name);
+            }
         }
 
-        let mut reader = zip.reader_with_entry(1).await.unwrap();
-        let _l = reader.read_to_string_checked(&mut string).await.unwrap();
-
-        //println!("{}", string);
+        let e = entries.iter().find(|e| e.name == "res/css/a.css").unwrap();
+        let mut string = String::new();
+        zip::open_entry("./test.zip", e)
+            .await
+            .unwrap()
+            .read_to_string(&mut string)
+            .await
+            .unwrap();
+        assert_eq!(string.len(), 1044);
     };
     let rt = tokio::runtime::Builder::new_current_thread()
         .build()
@@ -1,31 +1,31 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
 use anyhow::Ok;
 use serde::Serialize;
 
 use crate::{
-    backend::{BackendTrait, ChapterInfo},
+    backend::{BackendTrait, ChapterInfo, Pic},
     manga_list::{self, ChapterBasicInfo, MangaInfo, MangaList},
-    utils::ToResult,
+    utils::{modified_secs, ToResult},
 };
 
 #[derive(Debug, Clone, Copy)]
 pub struct Eh;
 
 lazy_static::lazy_static! {
-    static ref INFO: HashMap<String, MangaInfoLocal> = singel_info();
+    /// Filled by every scan of the library.
+    static ref INFO: RwLock<Arc<HashMap<String, MangaInfoLocal>>> = RwLock::new(Arc::new(HashMap::new()));
+}
+
+fn info() -> Arc<HashMap<String, MangaInfoLocal>> {
+    INFO.read().unwrap().clone()
 }
 
 fn singel_info() -> HashMap<String, MangaInfoLocal> {
-    #[derive(Debug, Default, serde::Deserialize)]
-    struct Config {
-        path: String,
-    }
-    let f = std::fs::read_to_string("eh.toml").unwrap();
-    let config: Config = toml::from_str(&f).unwrap();
+    let config = crate::config::get().eh();
 
     read_all_infos(&config.path)
 }
@@ -37,20 +37,17 @@ fn t_impl_backend() {
 #[async_trait::async_trait]
 impl BackendTrait for Eh {
     fn generate_manga_list() -> MangaList {
-        #[derive(Debug, Default, serde::Deserialize)]
-        struct Config {
-            path: String,
-        }
-        let f: String = std::fs::read_to_string("eh.toml").unwrap();
-        let config: Config = toml::from_str(&f).unwrap();
-
-        let info = read_all_infos(&config.path);
+        let config = crate::config::get().eh();
 
-        let info = info
+        // the page lookups share this scan
+        let scanned = singel_info();
+        *INFO.write().unwrap() = Arc::new(scanned.clone());
+        let info = scanned
             .into_iter()
             .map(|(k, v)| {
                 let manga_name = k;
                 let manga_id = format!("{:?}", md5::compute(&manga_name));
+                let added = modified_secs(format!("{}/{}", config.path, manga_name));
                 let o = MangaInfo {
                     name: manga_name,
                     pic: format!("/manga/{}/single/0", manga_id),
@@ -59,7 +56,9 @@ impl BackendTrait for Eh {
                         id: "single".to_string(),
                         name: "single".to_string(),
                         length: v.pictures.len(),
+                        added,
                     }],
+                    added,
                 };
                 (manga_id, o)
             })
@@ -67,7 +66,7 @@ impl BackendTrait for Eh {
 
         MangaList {
             list: Arc::new(Mutex::new(info)),
-            path: Arc::new(config.path),
+            path: Arc::new(config.path.clone()),
             all_basic_info: Arc::new(Mutex::new(None)),
         }
     }
@@ -76,13 +75,13 @@ impl BackendTrait for Eh {
         manga_id: &str,
         _chapter: &str,
         pic_id: usize,
-    ) -> anyhow::Result<Option<Vec<u8>>> {
+    ) -> anyhow::Result<Option<Pic>> {
         let manga_name = {
             let list = manga_list::get_list_ref().get_list_mut();
             let info = list.get(manga_id).to_result()?;
             info.name.to_owned()
         };
-        let pic_name = INFO
+        let pic_name = info()
             .get(&manga_name)
             .to_result()?
             .pictures
@@ -90,10 +89,8 @@ impl BackendTrait for Eh {
             .to_result()?
             .to_owned();
         let path = format!("{}/{}/{}", base_path, manga_name, pic_name);
-        // dbg!(&path);
-        let out = tokio::fs::read(path).await?;
-
-        Ok(Some(out))
+        tracing::trace!(path, "reading page");
+        Ok(Some(Pic::open(path).await?))
     }
     async fn get_chapter_info(
         _base_path: &str,
@@ -127,6 +124,7 @@ fn read_all_infos(path: &str) -> HashMap<String, MangaInfoLocal> {
     let pic_extand_names = vec!["png", "jpg"];
     for e in walkdir::WalkDir::new(path) {
         let e = e.unwrap();
+        manga_list::scanned_entry();
         if e.depth() != 2 {
             continue;
         }
@@ -144,7 +142,7 @@ fn read_all_infos(path: &str) -> HashMap<String, MangaInfoLocal> {
         let n: Vec<&str> = picture_name.split('.').collect();
         let extand_name = n[n.len() - 1];
         if !pic_extand_names.contains(&extand_name) {
-            println!("{:?}", e);
+            tracing::warn!(path = %e.path().display(), "skipping file that is not a png or jpg");
             continue;
         };
 
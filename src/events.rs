@@ -0,0 +1,217 @@
+//! Server-sent events at `/events`, so open pages learn about library
+//! changes, scans and read progress without polling.
+//!
+//! Everything goes through one broadcast bus. A stream gets every library
+//! and scan event but only the progress of its own user. A stream that falls
+//! too far behind gets a `resync` event and should fetch what it shows again.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response,
+};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::manga_list::{self, ScanProgress};
+
+/// Events kept for slow streams before they have to resync.
+const CAPACITY: usize = 256;
+const KEEPALIVE: Duration = Duration::from_secs(15);
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    MangaAdded {
+        manga: String,
+        name: String,
+    },
+    MangaRemoved {
+        manga: String,
+    },
+    ChaptersAdded {
+        manga: String,
+        chapters: Vec<String>,
+    },
+    Scan(ScanProgress),
+    /// A scan of the library is done, `new_chapters` is 0 for the first one.
+    ScanFinished {
+        manga: usize,
+        chapters: usize,
+        new_chapters: usize,
+        elapsed_ms: u128,
+    },
+    /// A rescan failed, the index stays as it was.
+    ScanFailed {
+        error: String,
+    },
+    /// `page` is `None` when the progress was cleared.
+    Progress {
+        user: String,
+        manga: String,
+        chapter: String,
+        page: Option<usize>,
+        completed: bool,
+    },
+}
+
+/// Every [`Event::kind`].
+pub const KINDS: &[&str] = &[
+    "manga_added",
+    "manga_removed",
+    "chapters_added",
+    "scan",
+    "scan_finished",
+    "scan_failed",
+    "progress",
+];
+
+impl Event {
+    /// The `event:` field, what pages add listeners for.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::MangaAdded { .. } | Event::MangaRemoved { .. } | Event::ChaptersAdded { .. } => {
+                "library"
+            }
+            Event::Scan(_) | Event::ScanFinished { .. } | Event::ScanFailed { .. } => "scan",
+            Event::Progress { .. } => "progress",
+        }
+    }
+
+    /// The `type` field of the json.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::MangaAdded { .. } => "manga_added",
+            Event::MangaRemoved { .. } => "manga_removed",
+            Event::ChaptersAdded { .. } => "chapters_added",
+            Event::Scan(_) => "scan",
+            Event::ScanFinished { .. } => "scan_finished",
+            Event::ScanFailed { .. } => "scan_failed",
+            Event::Progress { .. } => "progress",
+        }
+    }
+
+    fn visible_to(&self, user: &str) -> bool {
+        match self {
+            Event::Progress { user: u, .. } => u == user,
+            _ => true,
+        }
+    }
+}
+
+fn bus() -> &'static broadcast::Sender<(u64, Event)> {
+    static BUS: OnceLock<broadcast::Sender<(u64, Event)>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Sends `event` to every open stream.
+pub fn publish(event: Event) {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // nobody listening is fine
+    let _ = bus().send((id, event));
+}
+
+/// Every event published from now on, with its id.
+pub fn subscribe() -> broadcast::Receiver<(u64, Event)> {
+    bus().subscribe()
+}
+
+/// Publishes the scan progress every second until the first scan is done.
+pub async fn watch_scan() {
+    let mut interval = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        let progress = manga_list::scan_progress();
+        let ready = progress.ready;
+        publish(Event::Scan(progress));
+        if ready {
+            return;
+        }
+    }
+}
+
+fn frame(id: u64, event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        id,
+        event.name(),
+        data
+    ))
+}
+
+/// The event stream for the user of the request, it ends at shutdown.
+pub fn stream(headers: &HeaderMap) -> Response<Body> {
+    let user = crate::komga::user(headers);
+    let mut events = subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        // the current state first, pages need not wait for the next change
+        let first = Event::Scan(manga_list::scan_progress());
+        if sender.send_data(frame(0, &first)).await.is_err() {
+            return;
+        }
+        let mut keepalive = tokio::time::interval(KEEPALIVE);
+        keepalive.tick().await;
+        let shutdown = crate::lifecycle::shutdown_requested();
+        tokio::pin!(shutdown);
+        loop {
+            let data = tokio::select! {
+                r = events.recv() => match r {
+                    Ok((id, e)) if e.visible_to(&user) => frame(id, &e),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => Bytes::from_static(b"event: resync\ndata: {}\n\n"),
+                    Err(RecvError::Closed) => return,
+                },
+                _ = keepalive.tick() => Bytes::from_static(b": keepalive\n\n"),
+                _ = &mut shutdown => return,
+            };
+            if sender.send_data(data).await.is_err() {
+                // the client went away
+                return;
+            }
+        }
+    });
+    let mut r = Response::new(body);
+    let h = r.headers_mut();
+    h.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/event-stream"),
+    );
+    h.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    // proxies like nginx would otherwise hold events back
+    h.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    r
+}
+
+#[test]
+fn t_frame() {
+    let e = Event::ChaptersAdded {
+        manga: "7".into(),
+        chapters: vec!["102".into()],
+    };
+    assert_eq!(
+        frame(3, &e),
+        "id: 3\nevent: library\ndata: {\"type\":\"chapters_added\",\"manga\":\"7\",\"chapters\":[\"102\"]}\n\n"
+    );
+    let p = Event::Progress {
+        user: "a".into(),
+        manga: "7".into(),
+        chapter: "100".into(),
+        page: Some(1),
+        completed: false,
+    };
+    assert!(p.visible_to("a"));
+    assert!(!p.visible_to("b"));
+    assert!(e.visible_to("b"));
+    assert!(KINDS.contains(&e.kind()));
+}
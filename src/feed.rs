@@ -0,0 +1,158 @@
+//! Atom feeds of newly added chapters under `/feed`, for feed readers to
+//! notice new chapters: of the whole library, of one series or of the series
+//! with a tag. Newest first, by the time the indexer first saw the chapter.
+//!
+//! Links are absolute, built from the origin the request was made to, since
+//! feed readers do not agree on how to resolve relative ones.
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Response,
+};
+
+use crate::{
+    cbz, maintenance,
+    manga_list::{self, ChapterBasicInfo, MangaInfo},
+    utils::{rfc3339, url_segment as seg, xml_escape},
+};
+
+pub const PREFIX: &str = "/feed";
+const ATOM: &str = "application/atom+xml";
+/// Chapters in a feed.
+const ENTRIES: usize = 50;
+
+fn entry(origin: &str, m: &MangaInfo, c: &ChapterBasicInfo, out: &mut String) {
+    let (manga, chapter) = (seg(&m.id), seg(&c.id));
+    let title = match m.chapters.len() {
+        1 => m.name.clone(),
+        _ => format!("{} - {}", m.name, c.name),
+    };
+    let cover = format!("{}/manga/{}/{}/0", origin, manga, chapter);
+    out.push_str("  <entry>\n");
+    out.push_str(&format!(
+        "    <id>urn:manga-server:chapter:{}:{}</id>\n",
+        xml_escape(&m.id),
+        xml_escape(&c.id)
+    ));
+    out.push_str(&format!("    <title>{}</title>\n", xml_escape(&title)));
+    out.push_str(&format!("    <updated>{}</updated>\n", rfc3339(c.added)));
+    out.push_str(&format!(
+        "    <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n",
+        xml_escape(&format!("{}/reader/{}/{}", origin, manga, chapter))
+    ));
+    out.push_str(&format!(
+        "    <link rel=\"enclosure\" type=\"{}\" href=\"{}\"/>\n",
+        cbz::CONTENT_TYPE,
+        xml_escape(&format!("{}/download/{}/{}", origin, manga, chapter))
+    ));
+    out.push_str(&format!(
+        "    <media:thumbnail url=\"{}\"/>\n",
+        xml_escape(&cover)
+    ));
+    for tag in m.tags() {
+        out.push_str(&format!("    <category term=\"{}\"/>\n", xml_escape(&tag)));
+    }
+    out.push_str(&format!(
+        "    <content type=\"html\">{}</content>\n",
+        xml_escape(&format!("<img src=\"{}\" alt=\"\"/>", xml_escape(&cover)))
+    ));
+    out.push_str("  </entry>\n");
+}
+
+/// The newest chapters of `series` as a feed, linking below `origin`, such
+/// as `https://example.com`.
+fn render(origin: &str, id: &str, title: &str, href: &str, series: &[MangaInfo]) -> String {
+    let mut chapters = series
+        .iter()
+        .flat_map(|m| m.chapters.iter().map(move |c| (m, c)))
+        .collect::<Vec<_>>();
+    chapters.sort_by(|a, b| b.1.added.cmp(&a.1.added).then(a.0.name.cmp(&b.0.name)));
+    chapters.truncate(ENTRIES);
+    let updated = chapters.first().map_or(0, |(_, c)| c.added);
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:media=\"http://search.yahoo.com/mrss/\">\n",
+    );
+    out.push_str(&format!(
+        "  <id>urn:manga-server:feed:{}</id>\n",
+        xml_escape(id)
+    ));
+    out.push_str(&format!("  <title>{}</title>\n", xml_escape(title)));
+    out.push_str(&format!("  <updated>{}</updated>\n", rfc3339(updated)));
+    out.push_str("  <author><name>manga-server</name></author>\n");
+    out.push_str(&format!(
+        "  <link rel=\"self\" type=\"{}\" href=\"{}\"/>\n",
+        ATOM,
+        xml_escape(&format!("{}{}", origin, href))
+    ));
+    for (m, c) in chapters {
+        entry(origin, m, c, &mut out);
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn atom(body: String) -> Response<Body> {
+    let mut r = Response::new(Body::from(body));
+    r.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(ATOM));
+    r
+}
+
+pub fn recent(origin: &str) -> Response<Body> {
+    atom(render(
+        origin,
+        "recent",
+        "New chapters",
+        &format!("{}/recent.atom", PREFIX),
+        &maintenance::index(),
+    ))
+}
+
+/// New chapters of one series, `None` if there is no such series.
+pub fn series(origin: &str, id: &str) -> Option<Response<Body>> {
+    let m = manga_list::get_list_ref().get_list_mut().get(id)?.clone();
+    Some(atom(render(
+        origin,
+        &format!("series:{}", m.id),
+        &format!("New chapters of {}", m.name),
+        &format!("{}/series/{}.atom", PREFIX, seg(&m.id)),
+        &[m],
+    )))
+}
+
+/// New chapters of the series tagged `tag`, see [`MangaInfo::tags`].
+pub fn tag(origin: &str, tag: &str) -> Response<Body> {
+    let series = maintenance::index()
+        .into_iter()
+        .filter(|m| m.tags().iter().any(|t| t == tag))
+        .collect::<Vec<_>>();
+    atom(render(
+        origin,
+        &format!("tag:{}", tag),
+        &format!("New chapters tagged {}", tag),
+        &format!("{}/tag/{}.atom", PREFIX, seg(tag)),
+        &series,
+    ))
+}
+
+#[test]
+fn t_render() {
+    let m = crate::manga_list::test_manga(
+        "1 2",
+        "[x] A & B",
+        &[("old", 1_600_000_000), ("new", 1_700_000_000)],
+    );
+    let xml = render(
+        "https://example.com",
+        "recent",
+        "New",
+        "/feed/recent.atom",
+        &[m],
+    );
+    assert!(xml.contains("<updated>2023-11-14T22:13:20Z</updated>"));
+    assert!(xml.find("B - new").unwrap() < xml.find("B - old").unwrap());
+    assert!(xml.contains("<title>[x] A &amp; B - new</title>"));
+    assert!(xml.contains("href=\"https://example.com/feed/recent.atom\""));
+    assert!(xml.contains("href=\"https://example.com/reader/1%202/new\""));
+    assert!(xml.contains("<media:thumbnail url=\"https://example.com/manga/1%202/new/0\"/>"));
+    assert!(xml.contains("<category term=\"x\"/>"));
+}
@@ -0,0 +1,332 @@
+//! GraphQL endpoint at `/graphql` over the library and read progress, so a
+//! dashboard gets series, chapters, page counts and progress in one request.
+//!
+//! Queries deeper or more costly than `[graphql]` in the config allows are
+//! rejected before they run. Progress is the one of the basic auth user.
+use std::{future::Future, pin::Pin, sync::OnceLock};
+
+use async_graphql::{
+    BatchRequest, Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject,
+};
+use hyper::{header::HeaderMap, Body, Method, StatusCode};
+
+use crate::{
+    api::{self, ApiError, ApiResult},
+    backend::BackendTrait,
+    komga, maintenance,
+    manga_list::{self, ChapterBasicInfo, MangaInfo},
+    progress,
+    router::Query,
+};
+
+pub const PATH: &str = "/graphql";
+/// Chapters and pages are counted this many times when the complexity of a
+/// query is worked out, their real number is not known before it runs.
+const LIST_COST: usize = 20;
+const DEFAULT_LIMIT: usize = 50;
+
+type PageCount = fn(
+    String,
+    String,
+    String,
+    usize,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<usize>> + Send>>;
+
+/// The backend's way to count the pages of a chapter, schema data.
+struct Pages(PageCount);
+
+/// The user the request is from, request data.
+#[derive(Clone)]
+struct User(String);
+
+pub type LibrarySchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: OnceLock<LibrarySchema> = OnceLock::new();
+
+fn schema<B: BackendTrait>() -> &'static LibrarySchema {
+    SCHEMA.get_or_init(|| {
+        let config = &crate::config::get().graphql;
+        Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+            .data(Pages(|base_path, manga, chapter, length| {
+                Box::pin(async move {
+                    maintenance::chapter_length::<B>(&base_path, &manga, &chapter, length).await
+                })
+            }))
+            .limit_depth(config.max_depth)
+            .limit_complexity(config.max_complexity)
+            .finish()
+    })
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Every library, currently only the configured backend.
+    async fn libraries(&self) -> Vec<Library> {
+        vec![Library]
+    }
+
+    async fn library(&self, id: String) -> Option<Library> {
+        (id == manga_list::library_name()).then_some(Library)
+    }
+
+    /// Series sorted by name, optionally only the ones whose name contains
+    /// `query` or that have `tag`.
+    #[graphql(complexity = "limit.saturating_mul(child_complexity)")]
+    async fn series_list(
+        &self,
+        query: Option<String>,
+        tag: Option<String>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize,
+    ) -> Vec<Series> {
+        let query = query.map(|q| q.to_lowercase());
+        maintenance::index()
+            .into_iter()
+            .filter(|m| match &query {
+                Some(q) => m.name.to_lowercase().contains(q),
+                None => true,
+            })
+            .filter(|m| match &tag {
+                Some(t) => m.tags().contains(t),
+                None => true,
+            })
+            .skip(offset)
+            .take(limit)
+            .map(Series)
+            .collect()
+    }
+
+    async fn series(&self, id: String) -> Option<Series> {
+        manga_list::get_list_ref()
+            .get_list_mut()
+            .get(&id)
+            .cloned()
+            .map(Series)
+    }
+
+    /// Every tag with the number of series having it, most used first.
+    async fn tags(&self) -> Vec<Tag> {
+        let mut out = Vec::<Tag>::new();
+        for m in maintenance::index() {
+            for name in m.tags() {
+                match out.iter_mut().find(|t| t.name == name) {
+                    Some(t) => t.series_count += 1,
+                    None => out.push(Tag {
+                        name,
+                        series_count: 1,
+                    }),
+                }
+            }
+        }
+        out.sort_by(|a, b| {
+            b.series_count
+                .cmp(&a.series_count)
+                .then(a.name.cmp(&b.name))
+        });
+        out
+    }
+
+    /// Name of the user progress is read for.
+    async fn viewer(&self, ctx: &Context<'_>) -> String {
+        ctx.data_unchecked::<User>().0.clone()
+    }
+}
+
+pub struct Library;
+
+#[Object]
+impl Library {
+    async fn id(&self) -> &'static str {
+        manga_list::library_name()
+    }
+    async fn backend(&self) -> &'static str {
+        manga_list::library_name()
+    }
+    async fn series_count(&self) -> usize {
+        manga_list::get_list_ref().get_list_mut().len()
+    }
+    async fn chapter_count(&self) -> usize {
+        manga_list::get_list_ref()
+            .get_list_mut()
+            .values()
+            .map(|m| m.chapters.len())
+            .sum()
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Tag {
+    name: String,
+    series_count: usize,
+}
+
+pub struct Series(MangaInfo);
+
+#[Object]
+impl Series {
+    async fn id(&self) -> &str {
+        &self.0.id
+    }
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+    async fn tags(&self) -> Vec<String> {
+        self.0.tags()
+    }
+    /// Last change on disk as unix seconds, 0 if unknown.
+    async fn added(&self) -> u64 {
+        self.0.added
+    }
+    /// Url of the first page of the first chapter.
+    async fn cover_url(&self) -> Option<String> {
+        let c = self.0.chapters.first()?;
+        Some(api::page_url(&self.0.id, &c.id, 0))
+    }
+    async fn chapter_count(&self) -> usize {
+        self.0.chapters.len()
+    }
+    /// Chapters in reading order.
+    #[graphql(complexity = "LIST_COST.saturating_mul(child_complexity)")]
+    async fn chapters(&self) -> Vec<Chapter> {
+        (0..self.0.chapters.len())
+            .map(|i| self.chapter_at(i))
+            .collect()
+    }
+    async fn chapter(&self, id: String) -> Option<Chapter> {
+        let i = self.0.chapters.iter().position(|c| c.id == id)?;
+        Some(self.chapter_at(i))
+    }
+}
+impl Series {
+    fn chapter_at(&self, index: usize) -> Chapter {
+        Chapter {
+            series: self.0.id.clone(),
+            index,
+            info: self.0.chapters[index].clone(),
+        }
+    }
+}
+
+pub struct Chapter {
+    series: String,
+    index: usize,
+    info: ChapterBasicInfo,
+}
+
+#[Object]
+impl Chapter {
+    async fn id(&self) -> &str {
+        &self.info.id
+    }
+    async fn name(&self) -> &str {
+        &self.info.name
+    }
+    /// Position in the series, starting at 1.
+    async fn number(&self) -> usize {
+        self.index + 1
+    }
+    async fn series_id(&self) -> &str {
+        &self.series
+    }
+    async fn page_count(&self, ctx: &Context<'_>) -> async_graphql::Result<usize> {
+        Ok(self.count(ctx).await?)
+    }
+    #[graphql(complexity = "LIST_COST.saturating_mul(child_complexity)")]
+    async fn pages(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Page>> {
+        Ok((0..self.count(ctx).await?)
+            .map(|index| Page {
+                index,
+                url: api::page_url(&self.series, &self.info.id, index),
+            })
+            .collect())
+    }
+    /// Where the viewer stopped reading, none if they did not open it yet.
+    async fn progress(&self, ctx: &Context<'_>) -> Option<Progress> {
+        let user = &ctx.data_unchecked::<User>().0;
+        progress::get(user, &self.series, &self.info.id).map(|p| Progress {
+            page: p.page,
+            completed: p.completed,
+            updated: p.updated,
+        })
+    }
+}
+impl Chapter {
+    async fn count(&self, ctx: &Context<'_>) -> anyhow::Result<usize> {
+        let base_path = (*manga_list::get_list_ref().path).to_owned();
+        (ctx.data_unchecked::<Pages>().0)(
+            base_path,
+            self.series.clone(),
+            self.info.id.clone(),
+            self.info.length,
+        )
+        .await
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Page {
+    /// Starting at 0.
+    index: usize,
+    url: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Progress {
+    /// Last page read, starting at 0.
+    page: usize,
+    completed: bool,
+    /// Unix seconds.
+    updated: u64,
+}
+
+/// Runs the query of a `GET` with `query`, `variables` and `operationName`
+/// parameters or of a `POST` with a json body, batches included.
+pub async fn handle<B: BackendTrait>(
+    method: &Method,
+    headers: &HeaderMap,
+    query: &Query,
+    body: Body,
+) -> ApiResult {
+    let bad_request = |e: String| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        message: e,
+    };
+    let request = if method == Method::POST {
+        let body = api::read_body(body).await?;
+        let request = serde_json::from_slice::<BatchRequest>(&body)
+            .map_err(|e| bad_request(format!("invalid graphql request: {}", e)))?;
+        // the limits hold for each operation, a batch must not multiply them
+        let max_batch = crate::config::get().graphql.max_batch;
+        if let BatchRequest::Batch(operations) = &request {
+            if operations.len() > max_batch {
+                return Err(bad_request(format!(
+                    "batch of {} operations, at most {} are allowed",
+                    operations.len(),
+                    max_batch
+                )));
+            }
+        }
+        request
+    } else {
+        let mut r = async_graphql::Request::new(
+            query
+                .get("query")
+                .ok_or_else(|| bad_request("`query` is missing".to_owned()))?,
+        );
+        if let Some(v) = query.get("variables") {
+            r = r.variables(
+                serde_json::from_str(v)
+                    .map_err(|e| bad_request(format!("invalid `variables`: {}", e)))?,
+            );
+        }
+        if let Some(name) = query.get("operationName") {
+            r = r.operation_name(name);
+        }
+        BatchRequest::Single(r)
+    };
+    let request = request.data(User(komga::user(headers)));
+    // errors of the query itself are in the response, as graphql clients expect
+    Ok(api::json(&schema::<B>().execute_batch(request).await))
+}
@@ -0,0 +1,655 @@
+//! The part of the Komga REST api that reader apps with a Komga source use,
+//! under `/komga`. Apps are pointed at `http://host:port/komga`.
+//!
+//! Series are manga and books are chapters. Ids are hex encoded so any id a
+//! backend uses is safe in a path, a book id joins series and chapter id.
+//! Read progress is kept per user, the user being the name sent with basic
+//! auth. There is no password check.
+use base64::Engine;
+use hyper::{
+    header::{self, HeaderMap},
+    Body, Method, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, json, ApiError, ApiResult},
+    backend::{content_type, ArchivePage, BackendTrait},
+    cbz,
+    maintenance::{self, chapter_length},
+    manga_list::{self, ChapterBasicInfo, MangaInfo},
+    progress::{self, Progress},
+    router::{KomgaRoute, Query},
+    utils::rfc3339,
+    webdav,
+};
+
+pub const PREFIX: &str = "/komga";
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LibraryDto {
+    id: String,
+    name: String,
+    root: String,
+    unavailable: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PageWrapper<T> {
+    content: Vec<T>,
+    empty: bool,
+    first: bool,
+    last: bool,
+    number: usize,
+    number_of_elements: usize,
+    size: usize,
+    total_elements: usize,
+    total_pages: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SeriesDto {
+    id: String,
+    library_id: String,
+    name: String,
+    url: String,
+    created: String,
+    last_modified: String,
+    file_last_modified: String,
+    books_count: usize,
+    books_read_count: usize,
+    books_unread_count: usize,
+    books_in_progress_count: usize,
+    metadata: SeriesMetadataDto,
+    books_metadata: BookMetadataAggregationDto,
+    deleted: bool,
+    oneshot: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SeriesMetadataDto {
+    status: &'static str,
+    status_lock: bool,
+    created: String,
+    last_modified: String,
+    title: String,
+    title_lock: bool,
+    title_sort: String,
+    title_sort_lock: bool,
+    summary: String,
+    summary_lock: bool,
+    reading_direction: String,
+    reading_direction_lock: bool,
+    publisher: String,
+    publisher_lock: bool,
+    age_rating: Option<u32>,
+    age_rating_lock: bool,
+    language: String,
+    language_lock: bool,
+    genres: Vec<String>,
+    genres_lock: bool,
+    tags: Vec<String>,
+    tags_lock: bool,
+    total_book_count: Option<usize>,
+    total_book_count_lock: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorDto {
+    name: String,
+    role: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BookMetadataAggregationDto {
+    authors: Vec<AuthorDto>,
+    tags: Vec<String>,
+    release_date: Option<String>,
+    summary: String,
+    summary_number: String,
+    created: String,
+    last_modified: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BookDto {
+    id: String,
+    series_id: String,
+    series_title: String,
+    library_id: String,
+    name: String,
+    url: String,
+    number: usize,
+    created: String,
+    last_modified: String,
+    file_last_modified: String,
+    size_bytes: u64,
+    size: String,
+    media: MediaDto,
+    metadata: BookMetadataDto,
+    read_progress: Option<ReadProgressDto>,
+    deleted: bool,
+    file_hash: String,
+    oneshot: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaDto {
+    status: &'static str,
+    media_type: &'static str,
+    media_profile: &'static str,
+    pages_count: usize,
+    comment: String,
+    epub_divina_compatible: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BookMetadataDto {
+    title: String,
+    title_lock: bool,
+    summary: String,
+    summary_lock: bool,
+    number: String,
+    number_lock: bool,
+    number_sort: f32,
+    number_sort_lock: bool,
+    release_date: Option<String>,
+    release_date_lock: bool,
+    authors: Vec<AuthorDto>,
+    authors_lock: bool,
+    tags: Vec<String>,
+    tags_lock: bool,
+    isbn: String,
+    isbn_lock: bool,
+    created: String,
+    last_modified: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadProgressDto {
+    /// Starting at 1.
+    page: usize,
+    completed: bool,
+    read_date: String,
+    created: String,
+    last_modified: String,
+    device_id: String,
+    device_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PageDto {
+    /// Starting at 1.
+    number: usize,
+    file_name: String,
+    media_type: &'static str,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadProgressUpdate {
+    page: Option<usize>,
+    completed: Option<bool>,
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<String> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn book_id(manga: &str, chapter: &str) -> String {
+    format!("{}-{}", hex(manga), hex(chapter))
+}
+
+/// The user of a request, from basic auth.
+pub(crate) fn user(headers: &HeaderMap) -> String {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| {
+            base64::engine::general_purpose::STANDARD
+                .decode(v.trim())
+                .ok()
+        })
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.split_once(':').map(|(u, _)| u.to_owned()))
+        .filter(|u| !u.is_empty())
+        .unwrap_or_else(|| progress::ANONYMOUS.to_owned())
+}
+
+fn library_id() -> String {
+    manga_list::library_name().to_owned()
+}
+
+fn series_by_id(id: &str) -> Result<MangaInfo, ApiError> {
+    unhex(id)
+        .and_then(|id| manga_list::get_list_ref().get_list_mut().get(&id).cloned())
+        .ok_or_else(|| ApiError::not_found("series"))
+}
+
+/// The series of a book and the position of the chapter in it.
+fn book_by_id(id: &str) -> Result<(MangaInfo, usize), ApiError> {
+    let (series, chapter) = id
+        .split_once('-')
+        .ok_or_else(|| ApiError::not_found("book"))?;
+    let m = series_by_id(series)?;
+    let chapter = unhex(chapter).ok_or_else(|| ApiError::not_found("book"))?;
+    let i = m
+        .chapters
+        .iter()
+        .position(|c| c.id == chapter)
+        .ok_or_else(|| ApiError::not_found("book"))?;
+    Ok((m, i))
+}
+
+fn paged<T>(all: Vec<T>, query: &Query) -> Result<PageWrapper<T>, ApiError> {
+    let total = all.len();
+    let unpaged = query.get("unpaged") == Some("true");
+    let size = match unpaged {
+        true => total.max(1),
+        false => query
+            .parse_value::<usize>("size")?
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .max(1),
+    };
+    let number = match unpaged {
+        true => 0,
+        false => query.parse_value::<usize>("page")?.unwrap_or(0),
+    };
+    let content = all
+        .into_iter()
+        .skip(number.saturating_mul(size))
+        .take(size)
+        .collect::<Vec<_>>();
+    let total_pages = total.div_ceil(size);
+    Ok(PageWrapper {
+        empty: content.is_empty(),
+        first: number == 0,
+        last: number.saturating_add(1) >= total_pages,
+        number,
+        number_of_elements: content.len(),
+        size,
+        total_elements: total,
+        total_pages,
+        content,
+    })
+}
+
+fn series_dto(m: &MangaInfo, user: &str) -> SeriesDto {
+    let date = rfc3339(m.added);
+    let progress = progress::of_manga(user, &m.id);
+    let read = m
+        .chapters
+        .iter()
+        .filter(|c| progress.get(&c.id).is_some_and(|p| p.completed))
+        .count();
+    let in_progress = m
+        .chapters
+        .iter()
+        .filter(|c| progress.get(&c.id).is_some_and(|p| !p.completed))
+        .count();
+    SeriesDto {
+        id: hex(&m.id),
+        library_id: library_id(),
+        name: m.name.clone(),
+        url: m.id.clone(),
+        created: date.clone(),
+        last_modified: date.clone(),
+        file_last_modified: date.clone(),
+        books_count: m.chapters.len(),
+        books_read_count: read,
+        books_unread_count: m.chapters.len() - read - in_progress,
+        books_in_progress_count: in_progress,
+        metadata: SeriesMetadataDto {
+            status: "ONGOING",
+            status_lock: false,
+            created: date.clone(),
+            last_modified: date.clone(),
+            title: m.name.clone(),
+            title_lock: false,
+            title_sort: m.name.clone(),
+            title_sort_lock: false,
+            summary: String::new(),
+            summary_lock: false,
+            reading_direction: String::new(),
+            reading_direction_lock: false,
+            publisher: String::new(),
+            publisher_lock: false,
+            age_rating: None,
+            age_rating_lock: false,
+            language: String::new(),
+            language_lock: false,
+            genres: Vec::new(),
+            genres_lock: false,
+            tags: Vec::new(),
+            tags_lock: false,
+            total_book_count: None,
+            total_book_count_lock: false,
+        },
+        books_metadata: BookMetadataAggregationDto {
+            authors: Vec::new(),
+            tags: Vec::new(),
+            release_date: None,
+            summary: String::new(),
+            summary_number: String::new(),
+            created: date.clone(),
+            last_modified: date,
+        },
+        deleted: false,
+        oneshot: false,
+    }
+}
+
+/// `size` as Komga shows it, e.g. `1.5 MiB`.
+fn human_size(size: u64) -> String {
+    let mut value = size as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return match unit {
+                "B" => format!("{} B", size),
+                _ => format!("{:.1} {}", value, unit),
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1} TiB", value)
+}
+
+async fn book_dto<B: BackendTrait>(
+    m: &MangaInfo,
+    index: usize,
+    user: &str,
+) -> Result<BookDto, ApiError> {
+    let c: &ChapterBasicInfo = &m.chapters[index];
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let pages = chapter_length::<B>(&base_path, &m.id, &c.id, c.length).await?;
+    let (size, _) = webdav::chapter_file_info::<B>(&base_path, m, index).await?;
+    let date = rfc3339(c.added);
+    let read_progress = progress::get(user, &m.id, &c.id).map(|p: Progress| {
+        let date = rfc3339(p.updated);
+        ReadProgressDto {
+            page: p.page + 1,
+            completed: p.completed,
+            read_date: date.clone(),
+            created: date.clone(),
+            last_modified: date,
+            device_id: String::new(),
+            device_name: String::new(),
+        }
+    });
+    Ok(BookDto {
+        id: book_id(&m.id, &c.id),
+        series_id: hex(&m.id),
+        series_title: m.name.clone(),
+        library_id: library_id(),
+        name: c.name.clone(),
+        url: format!("{}/{}", m.id, c.id),
+        number: index + 1,
+        created: date.clone(),
+        last_modified: date.clone(),
+        file_last_modified: date.clone(),
+        size_bytes: size,
+        size: human_size(size),
+        media: MediaDto {
+            status: "READY",
+            media_type: "application/zip",
+            media_profile: "DIVINA",
+            pages_count: pages,
+            comment: String::new(),
+            epub_divina_compatible: false,
+        },
+        metadata: BookMetadataDto {
+            title: c.name.clone(),
+            title_lock: false,
+            summary: String::new(),
+            summary_lock: false,
+            number: (index + 1).to_string(),
+            number_lock: false,
+            number_sort: (index + 1) as f32,
+            number_sort_lock: false,
+            release_date: None,
+            release_date_lock: false,
+            authors: Vec::new(),
+            authors_lock: false,
+            tags: Vec::new(),
+            tags_lock: false,
+            isbn: String::new(),
+            isbn_lock: false,
+            created: date.clone(),
+            last_modified: date,
+        },
+        read_progress,
+        deleted: false,
+        file_hash: String::new(),
+        oneshot: false,
+    })
+}
+
+/// Page `page` of a chapter, starting at 0, as an image response.
+async fn image<B: BackendTrait>(
+    headers: &HeaderMap,
+    m: &MangaInfo,
+    chapter: &str,
+    page: usize,
+) -> ApiResult {
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let pic = B::get_pic_in_chapter(&base_path, &m.id, chapter, page)
+        .await?
+        .ok_or_else(|| ApiError::not_found("page"))?;
+    Ok(pic.respond(headers).await.map_err(anyhow::Error::from)?)
+}
+
+fn no_content() -> Response<Body> {
+    let mut r = Response::new(Body::empty());
+    *r.status_mut() = StatusCode::NO_CONTENT;
+    r
+}
+
+pub async fn handle<B: BackendTrait>(
+    route: KomgaRoute,
+    method: &Method,
+    headers: &HeaderMap,
+    query: &Query,
+    body: Body,
+) -> ApiResult {
+    let user = user(headers);
+    match route {
+        KomgaRoute::Libraries => Ok(json(&[library()])),
+        KomgaRoute::Library { id } => match id == library_id() {
+            true => Ok(json(&library())),
+            false => Err(ApiError::not_found("library")),
+        },
+        KomgaRoute::SeriesList => {
+            let search = query.get("search").map(str::to_lowercase);
+            let mut all = maintenance::index();
+            all.retain(|m| match &search {
+                Some(s) => m.name.to_lowercase().contains(s),
+                None => true,
+            });
+            if query
+                .get("sort")
+                .is_some_and(|s| s.starts_with("lastModified") || s.starts_with("created"))
+            {
+                all.sort_by_key(|m| std::cmp::Reverse(m.added));
+            }
+            let all = all.iter().map(|m| series_dto(m, &user)).collect();
+            Ok(json(&paged(all, query)?))
+        }
+        KomgaRoute::SeriesLatest => {
+            let mut all = maintenance::index();
+            all.sort_by_key(|m| std::cmp::Reverse(m.added));
+            let all = all.iter().map(|m| series_dto(m, &user)).collect();
+            Ok(json(&paged(all, query)?))
+        }
+        KomgaRoute::Series { id } => Ok(json(&series_dto(&series_by_id(&id)?, &user))),
+        KomgaRoute::SeriesThumbnail { id } => {
+            let m = series_by_id(&id)?;
+            let c = m
+                .chapters
+                .first()
+                .ok_or_else(|| ApiError::not_found("thumbnail"))?;
+            image::<B>(headers, &m, &c.id, 0).await
+        }
+        KomgaRoute::SeriesBooks { id } => {
+            let m = series_by_id(&id)?;
+            let mut books = Vec::with_capacity(m.chapters.len());
+            for i in 0..m.chapters.len() {
+                books.push(book_dto::<B>(&m, i, &user).await?);
+            }
+            Ok(json(&paged(books, query)?))
+        }
+        KomgaRoute::Book { id } => {
+            let (m, i) = book_by_id(&id)?;
+            Ok(json(&book_dto::<B>(&m, i, &user).await?))
+        }
+        KomgaRoute::BookPages { id } => {
+            let (m, i) = book_by_id(&id)?;
+            let book = book_dto::<B>(&m, i, &user).await?;
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let chapter = &m.chapters[i].id;
+            let mut pages = Vec::with_capacity(book.media.pages_count);
+            for number in 1..=book.media.pages_count {
+                let file_name =
+                    match B::get_archive_page(&base_path, &m.id, chapter, number - 1).await? {
+                        Some(ArchivePage::Zip { entry, .. }) => entry
+                            .name
+                            .rsplit('/')
+                            .next()
+                            .unwrap_or(&entry.name)
+                            .to_owned(),
+                        Some(ArchivePage::Pic(pic)) => {
+                            format!("{}.{}", number - 1, cbz::extension(pic.content_type))
+                        }
+                        None => return Err(ApiError::not_found("page")),
+                    };
+                pages.push(PageDto {
+                    number,
+                    media_type: content_type(&file_name).unwrap_or("application/octet-stream"),
+                    file_name,
+                });
+            }
+            Ok(json(&pages))
+        }
+        KomgaRoute::BookPage { id, page } => {
+            let (m, i) = book_by_id(&id)?;
+            let page = page
+                .checked_sub(1)
+                .ok_or_else(|| ApiError::not_found("page"))?;
+            image::<B>(headers, &m, &m.chapters[i].id, page).await
+        }
+        KomgaRoute::BookThumbnail { id } => {
+            let (m, i) = book_by_id(&id)?;
+            image::<B>(headers, &m, &m.chapters[i].id, 0).await
+        }
+        KomgaRoute::BookFile { id } => {
+            let (m, i) = book_by_id(&id)?;
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let archive = cbz::plan::<B>(&base_path, &m, Some(i)).await?;
+            Ok(cbz::respond::<B>(headers, archive)
+                .await
+                .map_err(anyhow::Error::from)?)
+        }
+        KomgaRoute::ReadProgress { id } => {
+            let (m, i) = book_by_id(&id)?;
+            let chapter = &m.chapters[i].id;
+            if method == Method::DELETE {
+                progress::clear(&user, &m.id, chapter);
+                return Ok(no_content());
+            }
+            let body = api::read_body(body).await?;
+            let update: ReadProgressUpdate =
+                serde_json::from_slice(&body).map_err(|e| ApiError {
+                    status: StatusCode::BAD_REQUEST,
+                    message: format!("invalid read progress: {}", e),
+                })?;
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let c = &m.chapters[i];
+            let pages = chapter_length::<B>(&base_path, &m.id, &c.id, c.length).await?;
+            let completed = update.completed.unwrap_or(false);
+            let page = match (update.page, completed) {
+                (_, true) => pages.saturating_sub(1),
+                (Some(p), false) => p.saturating_sub(1).min(pages.saturating_sub(1)),
+                (None, false) => 0,
+            };
+            progress::set(&user, &m.id, chapter, page, completed);
+            Ok(no_content())
+        }
+    }
+}
+
+fn library() -> LibraryDto {
+    LibraryDto {
+        id: library_id(),
+        name: library_id(),
+        root: String::new(),
+        unavailable: false,
+    }
+}
+
+#[test]
+fn t_ids() {
+    assert_eq!(hex("7"), "37");
+    assert_eq!(unhex("37").as_deref(), Some("7"));
+    assert_eq!(unhex(&hex("第1话 a-b")).as_deref(), Some("第1话 a-b"));
+    assert_eq!(unhex("3"), None);
+    assert_eq!(unhex("zz"), None);
+    assert_eq!(book_id("7", "100"), "37-313030");
+}
+
+#[test]
+fn t_user() {
+    let mut h = HeaderMap::new();
+    assert_eq!(user(&h), progress::ANONYMOUS);
+    // alice:secret
+    h.insert(
+        header::AUTHORIZATION,
+        "Basic YWxpY2U6c2VjcmV0".parse().unwrap(),
+    );
+    assert_eq!(user(&h), "alice");
+}
+
+#[test]
+fn t_paged() {
+    let q = Query::parse(Some("page=1&size=2"));
+    let p = paged((0..5).collect(), &q).unwrap();
+    assert_eq!(p.content, vec![2, 3]);
+    assert_eq!(p.total_pages, 3);
+    assert!(!p.first && !p.last);
+    let p = paged((0..5).collect(), &Query::parse(Some("unpaged=true"))).unwrap();
+    assert_eq!(p.content.len(), 5);
+    assert!(p.last);
+    let far = Query::parse(Some("page=18446744073709551615&size=1000"));
+    let p = paged((0..5).collect::<Vec<i32>>(), &far).unwrap();
+    assert!(p.empty && p.last);
+}
+
+#[test]
+fn t_human_size() {
+    assert_eq!(human_size(0), "0 B");
+    assert_eq!(human_size(1023), "1023 B");
+    assert_eq!(human_size(1536), "1.5 KiB");
+    assert_eq!(human_size(5 << 30), "5.0 GiB");
+}
@@ -1,10 +1,33 @@
+pub mod api;
 pub mod backend;
+pub mod cbz;
+pub mod compress;
+pub mod config;
 pub mod copy_manga;
 pub mod dmzj;
 pub mod eh;
+pub mod events;
+pub mod feed;
+pub mod graphql;
+pub mod komga;
+pub mod lifecycle;
+pub mod logging;
+pub mod maintenance;
 pub mod manga_list;
+pub mod memory;
+pub mod metrics;
+pub mod net;
+pub mod opds;
+pub mod progress;
+pub mod range;
 pub mod request_resolver;
+pub mod router;
+pub mod server;
 pub mod shaft;
+pub mod tls;
+pub mod webdav;
+pub mod webhooks;
+pub mod zip;
 
 // use copy_manga::CopyManga as SelectedBackend;
 // use dmzj::Dmzj as SelectedBackend;
@@ -0,0 +1,96 @@
+//! Shutdown signalling and the work to do before the process exits.
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+type Hook = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+lazy_static::lazy_static! {
+    static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+    static ref HOOKS: Mutex<Vec<(String, Hook)>> = Mutex::new(Vec::new());
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Tells everything waiting on [`shutdown_requested`] to wind down.
+pub fn request_shutdown() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Resolves once [`request_shutdown`] has been called.
+pub async fn shutdown_requested() {
+    let mut rx = SHUTDOWN.subscribe();
+    while !*rx.borrow_and_update() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Registers work to run after the servers have stopped, such as writing
+/// state to disk. Hooks run in registration order.
+pub fn on_shutdown(name: &str, hook: impl FnOnce() -> anyhow::Result<()> + Send + 'static) {
+    HOOKS
+        .lock()
+        .unwrap()
+        .push((name.to_owned(), Box::new(hook)));
+}
+
+/// Runs every registered hook once, a failing hook does not stop the others.
+/// Returns false if any of them failed.
+pub fn run_hooks() -> bool {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap());
+    let mut ok = true;
+    for (name, hook) in hooks {
+        if let Err(e) = hook() {
+            tracing::error!("shutdown: {} failed: {:#}", name, e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+#[test]
+fn t_hooks() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    let n = Arc::new(AtomicUsize::new(0));
+    let n1 = n.clone();
+    on_shutdown("count", move || {
+        n1.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    on_shutdown("fail", || anyhow::bail!("nope"));
+    assert!(!run_hooks());
+    assert_eq!(n.load(Ordering::SeqCst), 1);
+    // hooks only run once
+    assert!(run_hooks());
+    assert_eq!(n.load(Ordering::SeqCst), 1);
+}
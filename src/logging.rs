@@ -0,0 +1,340 @@
+//! Diagnostics through `tracing` and the optional access log.
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    net::IpAddr,
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    header, Body, HeaderMap, Method, Request, StatusCode, Version,
+};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::config::{AccessLogFormat, LogConfig, LogFormat};
+
+static ACCESS_LOG: OnceLock<(AccessLogFormat, Mutex<LineWriter<File>>)> = OnceLock::new();
+
+/// Sets up the global subscriber, `RUST_LOG` takes precedence over `log.level`.
+pub fn init(config: &LogConfig) -> anyhow::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(v) if !v.is_empty() => EnvFilter::try_new(&v)
+            .with_context(|| format!("invalid log filter in RUST_LOG: `{}`", v))?,
+        _ => EnvFilter::try_new(&config.level)
+            .with_context(|| format!("invalid `log.level`: `{}`", config.level))?,
+    };
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => registry.with(fmt).try_init(),
+        LogFormat::Json => registry.with(fmt.json()).try_init(),
+    }
+    .context("can not set up logging")?;
+
+    if let Some(path) = &config.access_log {
+        let f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("can not open access log `{}`", path))?;
+        let _ = ACCESS_LOG.set((config.access_log_format, Mutex::new(LineWriter::new(f))));
+        crate::lifecycle::on_shutdown("flush access log", || {
+            if let Some((_, f)) = ACCESS_LOG.get() {
+                f.lock().unwrap().flush()?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Span covering one request, the resolver fills in the manga fields once
+/// it knows them.
+pub fn request_span<T>(req: &Request<T>, client: Option<IpAddr>) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        client = client.map(tracing::field::display),
+        manga = tracing::field::Empty,
+        chapter = tracing::field::Empty,
+        page = tracing::field::Empty,
+        status = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+    )
+}
+
+/// What is needed from a request to write its access log line.
+#[derive(Debug, Clone)]
+pub struct RequestSummary {
+    pub client: Option<IpAddr>,
+    pub method: Method,
+    pub uri: String,
+    pub version: Version,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+impl RequestSummary {
+    pub fn new<T>(req: &Request<T>, client: Option<IpAddr>) -> Self {
+        let h = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            client,
+            method: req.method().clone(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.as_str().to_owned())
+                .unwrap_or_else(|| "/".to_owned()),
+            version: req.version(),
+            referer: h(header::REFERER),
+            user_agent: h(header::USER_AGENT),
+        }
+    }
+}
+
+/// Records the outcome on the current request span, logs it and writes the
+/// access log line. `bytes` is the part of the body that was sent, short of
+/// the whole when the client went away first.
+pub fn finish(
+    summary: &RequestSummary,
+    status: StatusCode,
+    bytes: u64,
+    complete: bool,
+    latency: Duration,
+) {
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+    let span = tracing::Span::current();
+    span.record("status", status.as_u16());
+    span.record("bytes", bytes);
+    span.record("latency_ms", latency_ms);
+    if status.is_server_error() {
+        tracing::warn!("request failed");
+    } else if complete {
+        tracing::info!("request done");
+    } else {
+        tracing::info!("request cut short");
+    }
+    let bytes = Some(bytes);
+
+    if let Some((format, f)) = ACCESS_LOG.get() {
+        let line = match format {
+            AccessLogFormat::Combined => combined_line(summary, status, bytes, chrono::Utc::now()),
+            AccessLogFormat::Json => json_line(summary, status, bytes, latency_ms),
+        };
+        if let Err(e) = writeln!(f.lock().unwrap(), "{}", line) {
+            tracing::error!("can not write access log: {}", e);
+        }
+    }
+}
+
+/// Size of a response body, streamed bodies only know it from their header.
+pub fn body_len<T: HttpBody>(response: &hyper::Response<T>) -> Option<u64> {
+    response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    })
+}
+
+type OnEnd = Box<dyn FnOnce(u64, bool) + Send + Sync>;
+
+/// Response body that counts what is sent and calls back once it is done,
+/// with the byte count and whether the end was reached. Dropping it early,
+/// as when the client goes away, counts as done.
+pub struct LoggedBody {
+    inner: Body,
+    /// Length from the response head, hyper stops polling once it is sent.
+    len: Option<u64>,
+    sent: u64,
+    on_end: Option<OnEnd>,
+}
+impl LoggedBody {
+    pub fn new(
+        inner: Body,
+        len: Option<u64>,
+        on_end: impl FnOnce(u64, bool) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            len,
+            sent: 0,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    fn end(&mut self, complete: bool) {
+        if let Some(f) = self.on_end.take() {
+            f(self.sent, complete);
+        }
+    }
+}
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let r = std::task::ready!(Pin::new(&mut self.inner).poll_data(cx));
+        match &r {
+            Some(Ok(b)) => self.sent += b.len() as u64,
+            Some(Err(_)) => self.end(false),
+            None => self.end(true),
+        }
+        // hyper stops polling once the body says it is over
+        if self.inner.is_end_stream() || self.len == Some(self.sent) {
+            self.end(true);
+        }
+        Poll::Ready(r)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // empty bodies are never polled
+        let complete = self.inner.is_end_stream();
+        self.end(complete);
+    }
+}
+
+fn combined_line(
+    s: &RequestSummary,
+    status: StatusCode,
+    bytes: Option<u64>,
+    time: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let quote = |v: &Option<String>| match v {
+        Some(v) => v.replace('\\', "\\\\").replace('"', "\\\""),
+        None => "-".to_owned(),
+    };
+    format!(
+        "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\"",
+        s.client
+            .map(|c| c.to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+        time.format("%d/%b/%Y:%H:%M:%S +0000"),
+        s.method,
+        s.uri.replace('"', "%22"),
+        s.version,
+        status.as_u16(),
+        bytes
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| "-".to_owned()),
+        quote(&s.referer),
+        quote(&s.user_agent),
+    )
+}
+
+fn json_line(
+    s: &RequestSummary,
+    status: StatusCode,
+    bytes: Option<u64>,
+    latency_ms: f64,
+) -> String {
+    serde_json::json!({
+        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "client": s.client.map(|c| c.to_string()),
+        "method": s.method.as_str(),
+        "uri": s.uri,
+        "version": format!("{:?}", s.version),
+        "status": status.as_u16(),
+        "bytes": bytes,
+        "latency_ms": latency_ms,
+        "referer": s.referer,
+        "user_agent": s.user_agent,
+    })
+    .to_string()
+}
+
+#[test]
+fn t_combined_line() {
+    use chrono::TimeZone;
+    let req = Request::builder()
+        .uri("/manga/1/2/3?x=1")
+        .header("user-agent", "curl \"8\"")
+        .body(())
+        .unwrap();
+    let s = RequestSummary::new(&req, Some("1.2.3.4".parse().unwrap()));
+    let time = chrono::Utc.with_ymd_and_hms(2023, 6, 1, 12, 30, 5).unwrap();
+    assert_eq!(
+        combined_line(&s, StatusCode::OK, Some(42), time),
+        r#"1.2.3.4 - - [01/Jun/2023:12:30:05 +0000] "GET /manga/1/2/3?x=1 HTTP/1.1" 200 42 "-" "curl \"8\"""#
+    );
+}
+
+#[tokio::test]
+async fn t_logged_body() {
+    use std::sync::Arc;
+    let ended = Arc::new(Mutex::new(None));
+    let logged = |body: Body| {
+        let ended = ended.clone();
+        LoggedBody::new(body, None, move |sent, complete| {
+            assert!(ended.lock().unwrap().replace((sent, complete)).is_none());
+        })
+    };
+
+    // a streamed body ends when it is read through
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in ["ab", "cde"] {
+            tx.send_data(chunk.into()).await.unwrap();
+        }
+    });
+    let mut body = logged(body);
+    while let Some(chunk) = body.data().await {
+        chunk.unwrap();
+        assert!(ended.lock().unwrap().is_none());
+    }
+    assert_eq!(ended.lock().unwrap().take(), Some((5, true)));
+    drop(body);
+    assert!(ended.lock().unwrap().is_none());
+
+    // a client going away cuts it short
+    let mut body = logged(Body::from("abc"));
+    drop(body);
+    assert_eq!(ended.lock().unwrap().take(), Some((0, false)));
+    body = logged(Body::empty());
+    drop(body);
+    assert_eq!(ended.lock().unwrap().take(), Some((0, true)));
+
+    // a stream of known length ends with its last byte
+    let (mut tx, body) = Body::channel();
+    tx.try_send_data("abc".into()).unwrap();
+    let ended = Arc::new(Mutex::new(None));
+    let mut body = LoggedBody::new(body, Some(3), {
+        let ended = ended.clone();
+        move |sent, complete| *ended.lock().unwrap() = Some((sent, complete))
+    });
+    body.data().await.unwrap().unwrap();
+    assert_eq!(ended.lock().unwrap().take(), Some((3, true)));
+}
@@ -1,47 +1,176 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{path::PathBuf, process::ExitCode};
 
-use hyper::{
-    service::{make_service_fn, service_fn},
-    Body, Error, Response, Server,
-};
+use clap::{Parser, Subcommand, ValueEnum};
 use manga_server::{
-    copy_manga, dmzj, eh,
-    manga_list::{Backend, CONFIG},
+    backend::BackendTrait,
+    config::{self, Backend, Config, Overrides},
+    copy_manga, dmzj, eh, lifecycle, logging, maintenance, memory,
+    server::ServerBuilder,
     shaft,
 };
-use tokio::fs;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Config file, defaults to `$MANGA_SERVER_CONFIG` or `config.toml`
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Backend to use, overrides `backend` in the config
+    #[arg(long, global = true)]
+    backend: Option<Backend>,
+    /// Address to listen on, overrides `bind` in the config
+    #[arg(long, global = true)]
+    bind: Option<std::net::IpAddr>,
+    /// Port to listen on, overrides `port` in the config
+    #[arg(short, long, global = true)]
+    port: Option<u16>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the http server, this is the default
+    Serve,
+    /// Print the library index as json and exit
+    Scan {
+        /// Pretty print the json
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Open every archive and decode every page, exits with 1 if anything is broken
+    Check,
+    /// Print manga, chapter and page counts
+    Stats,
+    /// Export every chapter with its page count
+    Export {
+        #[arg(short, long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        /// Output file, defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Csv,
+}
 
 #[tokio::main]
-async fn main() {
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), CONFIG.port);
-    let make_svc = make_service_fn(|_| async {
-        Ok::<_, Error>(service_fn(|req| async move {
-            let response = match {
-                match CONFIG.backend {
-                    Backend::DMZJ => {
-                        manga_server::request_resolver::resolve::<dmzj::Dmzj>(req).await
-                    }
-                    Backend::CopyManga => {
-                        manga_server::request_resolver::resolve::<copy_manga::CopyManga>(req).await
-                    }
-                    Backend::Eh => manga_server::request_resolver::resolve::<eh::Eh>(req).await,
-                    Backend::Shaft => {
-                        manga_server::request_resolver::resolve::<shaft::Shaft>(req).await
-                    }
-                }
-            } {
-                Ok(r) => r,
-                Err(_e) => {
-                    let f = fs::read("res/html/404.html").await.unwrap();
-                    Response::new(Body::from(f))
-                }
-            };
-            Ok::<_, Error>(response)
-        }))
-    });
-    let server = Server::bind(&addr).serve(make_svc);
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<ExitCode> {
+    let overrides = Overrides {
+        backend: cli.backend,
+        bind: cli.bind,
+        port: cli.port,
+    };
+    let config = Config::load(&config::config_path(cli.config), &overrides)?;
+    logging::init(&config.log)?;
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::Serve = command {
+        let server = ServerBuilder::new(config).build()?;
+        server
+            .run_until(async {
+                lifecycle::signal().await;
+                tokio::spawn(async {
+                    lifecycle::signal().await;
+                    tracing::warn!("second signal, exiting now");
+                    std::process::exit(130);
+                });
+            })
+            .await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    config::init(config)?;
+    match config::get().backend {
+        Backend::DMZJ => run_command::<dmzj::Dmzj>(command).await,
+        Backend::CopyManga => run_command::<copy_manga::CopyManga>(command).await,
+        Backend::Eh => run_command::<eh::Eh>(command).await,
+        Backend::Shaft => run_command::<shaft::Shaft>(command).await,
+        Backend::Memory => run_command::<memory::Memory>(command).await,
+        Backend::Custom => unreachable!("custom backends are not in config files"),
+    }
+}
 
-    let _s = server.await;
+async fn run_command<B: BackendTrait + Send + Sync + 'static>(
+    command: Command,
+) -> anyhow::Result<ExitCode> {
+    match command {
+        Command::Serve => unreachable!("served by `run`"),
+        Command::Scan { pretty } => {
+            let index = maintenance::index();
+            let out = if pretty {
+                serde_json::to_string_pretty(&index)?
+            } else {
+                serde_json::to_string(&index)?
+            };
+            println!("{}", out);
+        }
+        Command::Check => {
+            let report = maintenance::check::<B>(|p| match p.page {
+                Some(page) => eprintln!(
+                    "{} ({}) chapter {} page {}: {}",
+                    p.manga_name, p.manga_id, p.chapter, page, p.error
+                ),
+                None => eprintln!(
+                    "{} ({}) chapter {}: {}",
+                    p.manga_name, p.manga_id, p.chapter, p.error
+                ),
+            })
+            .await;
+            println!(
+                "checked {} manga, {} chapters, {} pages: {} problems",
+                report.manga,
+                report.chapters,
+                report.pages,
+                report.problems.len()
+            );
+            if !report.problems.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Stats => {
+            let stats = maintenance::stats::<B>().await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        Command::Export { format, output } => {
+            let format = match format {
+                Format::Json => maintenance::ExportFormat::Json,
+                Format::Csv => maintenance::ExportFormat::Csv,
+            };
+            match output {
+                Some(path) => {
+                    let mut f =
+                        std::io::BufWriter::new(std::fs::File::create(&path).map_err(|e| {
+                            anyhow::anyhow!("can not create `{}`: {}", path.display(), e)
+                        })?);
+                    maintenance::export::<B>(format, &mut f).await?;
+                }
+                None => maintenance::export::<B>(format, &mut std::io::stdout().lock()).await?,
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    println!("Hello, world!");
+#[test]
+fn t_cli() {
+    let cli = Cli::try_parse_from(["manga-server", "--port", "8080"]).unwrap();
+    assert_eq!(cli.port, Some(8080));
+    assert!(cli.command.is_none());
+    let cli = Cli::try_parse_from(["manga-server", "serve", "--bind", "::1", "-p", "1"]).unwrap();
+    assert_eq!(cli.bind, Some("::1".parse().unwrap()));
+    assert!(matches!(cli.command, Some(Command::Serve)));
 }
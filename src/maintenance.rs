@@ -0,0 +1,377 @@
+//! Library maintenance used by the `scan`, `check`, `stats` and `export`
+//! subcommands, the statistics are also served at `/api/stats`.
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    backend::BackendTrait,
+    manga_list::{self, MangaInfo},
+    metrics::ReadingStats,
+};
+
+/// Every manga in the library, sorted by name.
+pub fn index() -> Vec<MangaInfo> {
+    let mut all = manga_list::get_list_ref()
+        .get_list_mut()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    all.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+    all
+}
+
+/// Page count of a chapter, asks the backend when the index does not know it.
+pub(crate) async fn chapter_length<B: BackendTrait>(
+    base_path: &str,
+    manga_id: &str,
+    chapter_id: &str,
+    indexed: usize,
+) -> anyhow::Result<usize> {
+    if indexed != 0 {
+        return Ok(indexed);
+    }
+    Ok(B::get_chapter_info(base_path, manga_id, chapter_id)
+        .await?
+        .length)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub manga_id: String,
+    pub manga_name: String,
+    pub chapter: String,
+    pub page: Option<usize>,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    pub manga: usize,
+    pub chapters: usize,
+    pub pages: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Opens every chapter and decodes every page, `on_problem` is called as soon
+/// as something broken is found.
+pub async fn check<B: BackendTrait>(mut on_problem: impl FnMut(&Problem)) -> CheckReport {
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let mut report = CheckReport::default();
+    for manga in index() {
+        report.manga += 1;
+        for chapter in &manga.chapters {
+            report.chapters += 1;
+            let problem = |page: Option<usize>, error: String| Problem {
+                manga_id: manga.id.clone(),
+                manga_name: manga.name.clone(),
+                chapter: chapter.id.clone(),
+                page,
+                error,
+            };
+            let length = match B::get_chapter_info(&base_path, &manga.id, &chapter.id).await {
+                Ok(info) => info.length,
+                Err(e) => {
+                    let p = problem(None, format!("can not open chapter: {:#}", e));
+                    on_problem(&p);
+                    report.problems.push(p);
+                    continue;
+                }
+            };
+            for page in 0..length {
+                report.pages += 1;
+                let error =
+                    match B::get_pic_in_chapter(&base_path, &manga.id, &chapter.id, page).await {
+                        Ok(Some(pic)) => match pic.bytes().await {
+                            Ok(data) => match image::load_from_memory(&data) {
+                                Ok(_) => continue,
+                                Err(e) => format!("can not decode page: {}", e),
+                            },
+                            Err(e) => format!("can not read page: {}", e),
+                        },
+                        Ok(None) => "page not found".to_string(),
+                        Err(e) => format!("can not read page: {:#}", e),
+                    };
+                let p = problem(Some(page), error);
+                on_problem(&p);
+                report.problems.push(p);
+            }
+        }
+    }
+    report
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pub backend: String,
+    pub manga: usize,
+    pub chapters: usize,
+    pub pages: usize,
+    /// Size of every file under the library path.
+    pub bytes: u64,
+    /// Pages by image format, pages inside zips included.
+    pub formats: BTreeMap<String, usize>,
+    /// Chapters that could not be read, left out of `pages`.
+    pub unreadable_chapters: usize,
+    /// Files and zips that could not be read, left out of `bytes` and
+    /// `formats`.
+    pub unreadable_files: usize,
+    /// Series with the most pages.
+    pub largest: Vec<SeriesStats>,
+    /// Series most recently changed on disk.
+    pub recently_added: Vec<SeriesStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reading: Option<ReadingStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesStats {
+    pub id: String,
+    pub name: String,
+    pub chapters: usize,
+    pub pages: usize,
+    /// Unix seconds, see [`MangaInfo::added`].
+    pub added: u64,
+}
+
+/// How many series `largest` and `recently_added` list.
+const TOP_SERIES: usize = 10;
+
+pub async fn stats<B: BackendTrait>() -> anyhow::Result<Stats> {
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let mut stats = Stats {
+        backend: crate::manga_list::library_name().to_owned(),
+        ..Default::default()
+    };
+    let mut series = Vec::new();
+    for manga in index() {
+        stats.manga += 1;
+        let mut pages = 0;
+        for chapter in &manga.chapters {
+            stats.chapters += 1;
+            match chapter_length::<B>(&base_path, &manga.id, &chapter.id, chapter.length).await {
+                Ok(n) => pages += n,
+                Err(e) => {
+                    tracing::warn!(manga = %manga.id, chapter = %chapter.id, "can not read chapter: {:#}", e);
+                    stats.unreadable_chapters += 1;
+                }
+            }
+        }
+        stats.pages += pages;
+        series.push(SeriesStats {
+            id: manga.id,
+            name: manga.name,
+            chapters: manga.chapters.len(),
+            pages,
+            added: manga.added,
+        });
+    }
+
+    let root = base_path.clone();
+    let usage = tokio::task::spawn_blocking(move || disk_usage(Path::new(&root))).await?;
+    stats.bytes = usage.bytes;
+    stats.unreadable_files = usage.unreadable;
+    let mut formats = usage.formats;
+    let zips = usage.zips;
+    for zip in zips {
+        let names = match crate::dmzj::zip_entries(&zip.to_string_lossy()).await {
+            Ok(n) => n,
+            Err(e) => {
+                tracing::warn!(path = %zip.display(), "can not read zip: {:#}", e);
+                stats.unreadable_files += 1;
+                continue;
+            }
+        };
+        for name in names {
+            if let Some(f) = image_format(&name) {
+                *formats.entry(f.to_owned()).or_default() += 1;
+            }
+        }
+    }
+    stats.formats = formats;
+
+    series.sort_by(|a, b| b.pages.cmp(&a.pages).then_with(|| a.name.cmp(&b.name)));
+    stats.largest = series.iter().take(TOP_SERIES).cloned().collect();
+    series.sort_by(|a, b| b.added.cmp(&a.added).then_with(|| a.name.cmp(&b.name)));
+    stats.recently_added = series.into_iter().take(TOP_SERIES).collect();
+    Ok(stats)
+}
+
+/// [`stats`] with the reading numbers of this process, computed at most once
+/// a minute since it walks the whole library. While one caller refreshes
+/// them the others get the previous numbers.
+pub async fn cached_stats<B: BackendTrait>() -> anyhow::Result<Stats> {
+    lazy_static::lazy_static! {
+        static ref CACHE: Mutex<Option<(Instant, Stats)>> = Mutex::new(None);
+    }
+    static REFRESHING: AtomicBool = AtomicBool::new(false);
+    let cached = CACHE.lock().unwrap().clone();
+    let mut stats = match cached {
+        Some((at, s)) if at.elapsed() < Duration::from_secs(60) => s,
+        Some((_, s)) if REFRESHING.swap(true, Ordering::SeqCst) => s,
+        _ => {
+            REFRESHING.store(true, Ordering::SeqCst);
+            let s = stats::<B>().await;
+            REFRESHING.store(false, Ordering::SeqCst);
+            let s = s?;
+            *CACHE.lock().unwrap() = Some((Instant::now(), s.clone()));
+            s
+        }
+    };
+    stats.reading = Some(crate::metrics::reading_stats(TOP_SERIES));
+    Ok(stats)
+}
+
+#[derive(Debug, Default)]
+struct DiskUsage {
+    /// Total size of the files.
+    bytes: u64,
+    /// Image files by format.
+    formats: BTreeMap<String, usize>,
+    /// Zip files whose entries still have to be counted.
+    zips: Vec<PathBuf>,
+    /// Files and directories that could not be read.
+    unreadable: usize,
+}
+
+/// What is under `root`, skipping what can not be read.
+fn disk_usage(root: &Path) -> DiskUsage {
+    let mut usage = DiskUsage::default();
+    for e in walkdir::WalkDir::new(root) {
+        let (e, meta) = match e.and_then(|e| e.metadata().map(|m| (e, m))) {
+            Ok(e) => e,
+            Err(e) => {
+                tracing::warn!("can not read {}", e);
+                usage.unreadable += 1;
+                continue;
+            }
+        };
+        if !meta.is_file() {
+            continue;
+        }
+        usage.bytes += meta.len();
+        let name = e.file_name().to_string_lossy();
+        if name.to_ascii_lowercase().ends_with(".zip") {
+            usage.zips.push(e.path().to_owned());
+        } else if let Some(f) = image_format(&name) {
+            *usage.formats.entry(f.to_owned()).or_default() += 1;
+        }
+    }
+    usage
+}
+
+fn image_format(name: &str) -> Option<&'static str> {
+    let ext = name.rsplit_once('.')?.1.to_ascii_lowercase();
+    Some(match ext.as_str() {
+        "jpg" | "jpeg" => "jpg",
+        "png" => "png",
+        "gif" => "gif",
+        "webp" => "webp",
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExportRow {
+    manga_id: String,
+    manga_name: String,
+    chapter_id: String,
+    chapter_name: String,
+    pages: usize,
+}
+
+/// Writes one row per chapter, with page counts filled in.
+pub async fn export<B: BackendTrait>(
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let mut rows = Vec::new();
+    for manga in index() {
+        for chapter in &manga.chapters {
+            rows.push(ExportRow {
+                manga_id: manga.id.clone(),
+                manga_name: manga.name.clone(),
+                chapter_id: chapter.id.clone(),
+                chapter_name: chapter.name.clone(),
+                pages: chapter_length::<B>(&base_path, &manga.id, &chapter.id, chapter.length)
+                    .await?,
+            });
+        }
+    }
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, &rows)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Csv => {
+            writeln!(out, "manga_id,manga_name,chapter_id,chapter_name,pages")?;
+            for r in rows {
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    csv_field(&r.manga_id),
+                    csv_field(&r.manga_name),
+                    csv_field(&r.chapter_id),
+                    csv_field(&r.chapter_name),
+                    r.pages
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[test]
+fn t_disk_usage() {
+    let dir = std::env::temp_dir().join(format!("manga-server-usage-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::write(dir.join("a/1.JPG"), b"123").unwrap();
+    std::fs::write(dir.join("a/b.zip"), b"45").unwrap();
+    let usage = disk_usage(&dir);
+    assert_eq!(usage.bytes, 5);
+    assert_eq!(usage.formats["jpg"], 1);
+    assert_eq!(usage.zips, [dir.join("a/b.zip")]);
+    assert_eq!(usage.unreadable, 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+    // counted instead of failing the stats
+    assert_eq!(disk_usage(&dir).unreadable, 1);
+}
+
+#[test]
+fn t_image_format() {
+    assert_eq!(image_format("001.JPEG"), Some("jpg"));
+    assert_eq!(image_format("a.b.webp"), Some("webp"));
+    assert_eq!(image_format("info.json"), None);
+    assert_eq!(image_format("noext"), None);
+}
+
+#[test]
+fn t_csv_field() {
+    assert_eq!(csv_field("abc"), "abc");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+}
@@ -1,29 +1,27 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{backend::BackendTrait, copy_manga, dmzj, eh, shaft};
+use crate::{
+    backend::BackendTrait,
+    config::Backend,
+    copy_manga, dmzj, eh,
+    events::{self, Event},
+    memory, shaft,
+};
 
 // use super::SelectedBackend;
 
-#[derive(Debug, Serialize, PartialEq, Clone, Eq)]
-pub struct MangaInfo {
-    pub name: String,
-    pub pic: String,
-    pub id: String,
-    pub chapters: Vec<ChapterBasicInfo>,
-}
-#[derive(Debug, Serialize, PartialEq, Clone, Eq)]
-pub struct ChapterBasicInfo {
-    pub id: String,
-    pub name: String,
-    pub length: usize,
-}
+pub use manga_server_types::{ChapterBasicInfo, MangaInfo, ScanProgress};
 
-#[derive(Debug, Serialize, PartialEq, Clone, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq)]
 pub struct MangaBasicInfo {
     pub name: String,
     pub pic: String,
@@ -89,8 +87,66 @@ impl MangaList {
     }
 }
 
-lazy_static::lazy_static! {
-    static ref MANGA_LIST :MangaList = single_mangalist();
+static MANGA_LIST: OnceLock<MangaList> = OnceLock::new();
+static SCAN_STARTED: OnceLock<Instant> = OnceLock::new();
+static SCAN_TOOK: OnceLock<Duration> = OnceLock::new();
+static SCANNED_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+static RESCANNING: AtomicBool = AtomicBool::new(false);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// A backend other than the built-in ones, see [`register_backend`].
+struct Custom {
+    name: &'static str,
+    scan: fn() -> MangaList,
+}
+static CUSTOM: OnceLock<Custom> = OnceLock::new();
+
+/// Makes `B` the backend that scans the library, with `name` as the library
+/// id. Only one backend can be registered and it has to happen before the
+/// first scan.
+pub fn register_backend<B: BackendTrait>(name: &'static str) -> anyhow::Result<()> {
+    if MANGA_LIST.get().is_some() {
+        anyhow::bail!("the library is already scanned");
+    }
+    CUSTOM
+        .set(Custom {
+            name,
+            scan: B::generate_manga_list,
+        })
+        .map_err(|_| anyhow::anyhow!("a backend is already registered"))
+}
+
+/// Id of the library, the name of the backend.
+pub fn library_name() -> &'static str {
+    match CUSTOM.get() {
+        Some(c) => c.name,
+        None => crate::config::get().backend.name(),
+    }
+}
+
+/// Changes whenever a rescan swaps in a new index, for caches built from it.
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Called by the backends for every file or directory they walk over.
+pub fn scanned_entry() {
+    SCANNED_ENTRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn scan_progress() -> ScanProgress {
+    let list = try_get_list_ref();
+    ScanProgress {
+        ready: list.is_some(),
+        entries_scanned: SCANNED_ENTRIES.load(Ordering::Relaxed),
+        manga: list.map(|l| l.get_list_mut().len()),
+        elapsed_ms: SCAN_TOOK
+            .get()
+            .copied()
+            .or_else(|| SCAN_STARTED.get().map(|s| s.elapsed()))
+            .map(|d| d.as_millis()),
+        rescanning: RESCANNING.load(Ordering::Relaxed),
+    }
 }
 
 #[test]
@@ -121,52 +177,172 @@ fn t_s() {
     dbg!(un);
 }
 
-fn single_mangalist() -> MangaList {
-    match CONFIG.backend {
+fn scan() -> MangaList {
+    match crate::config::get().backend {
         Backend::DMZJ => dmzj::Dmzj::generate_manga_list(),
         Backend::CopyManga => copy_manga::CopyManga::generate_manga_list(),
         Backend::Eh => eh::Eh::generate_manga_list(),
         Backend::Shaft => shaft::Shaft::generate_manga_list(),
+        Backend::Memory => memory::Memory::generate_manga_list(),
+        Backend::Custom => (CUSTOM.get().expect("no backend is registered").scan)(),
     }
 }
 
-pub fn get_list_ref() -> &'static MangaList {
-    &MANGA_LIST
-}
-lazy_static::lazy_static!(
-   pub static ref CONFIG: Config = config();
-);
-#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
-pub enum Backend {
-    DMZJ,
-    CopyManga,
-    Eh,
-    Shaft,
-}
-#[derive(Debug, Clone, Copy, serde::Deserialize)]
-pub struct Config {
-    pub port: u16,
-    pub backend: Backend,
-}
-fn config() -> Config {
-    #[derive(Debug, Clone, serde::Deserialize)]
-    struct ConfigD {
-        port: u16,
-        backend: String,
-    }
-    let f = std::fs::read_to_string("config.toml").unwrap();
-    let config: ConfigD = toml::from_str(&f).unwrap();
-    let backend = match config.backend {
-        v if v == "dmzj" => Backend::DMZJ,
-        v if v == "copy_manga" => Backend::CopyManga,
-        v if v == "eh" => Backend::Eh,
-        v if v == "shaft" => Backend::Shaft,
-        _ => panic!(),
+fn single_mangalist() -> MangaList {
+    let start = *SCAN_STARTED.get_or_init(Instant::now);
+    let list = scan();
+    let _ = SCAN_TOOK.set(start.elapsed());
+    crate::metrics::record_scan(library_name(), start.elapsed());
+    finished(&list, 0, start.elapsed());
+    tracing::info!(
+        "indexed {} manga in {:.2?}",
+        list.get_list_mut().len(),
+        start.elapsed()
+    );
+    list
+}
+
+/// Puts a new scan in place of `current`. Chapters that were known keep the
+/// time they were first seen, new ones are stamped with `now`. Returns what
+/// changed.
+fn merge(
+    current: &mut HashMap<String, MangaInfo>,
+    mut fresh: HashMap<String, MangaInfo>,
+    now: u64,
+) -> Vec<Event> {
+    let mut changes = current
+        .keys()
+        .filter(|id| !fresh.contains_key(*id))
+        .map(|id| Event::MangaRemoved { manga: id.clone() })
+        .collect::<Vec<_>>();
+    for (id, m) in fresh.iter_mut() {
+        let known = current.get(id);
+        let mut new = Vec::new();
+        for c in &mut m.chapters {
+            match known.and_then(|k| k.chapters.iter().find(|x| x.id == c.id)) {
+                Some(k) => c.added = k.added,
+                None => {
+                    c.added = c.added.max(now);
+                    new.push(c.id.clone());
+                }
+            }
+        }
+        match known {
+            None => changes.push(Event::MangaAdded {
+                manga: id.clone(),
+                name: m.name.clone(),
+            }),
+            Some(_) if !new.is_empty() => changes.push(Event::ChaptersAdded {
+                manga: id.clone(),
+                chapters: new,
+            }),
+            Some(_) => {}
+        }
+        m.added = m
+            .chapters
+            .iter()
+            .map(|c| c.added)
+            .max()
+            .unwrap_or(0)
+            .max(known.map_or(m.added, |k| k.added));
+    }
+    *current = fresh;
+    changes
+}
+
+/// Scans the library again and swaps the new index in, a no-op before the
+/// first scan is done.
+pub fn rescan() -> usize {
+    let list = match MANGA_LIST.get() {
+        Some(l) => l,
+        None => return 0,
+    };
+    let start = Instant::now();
+    RESCANNING.store(true, Ordering::Relaxed);
+    events::publish(Event::Scan(scan_progress()));
+    let fresh = std::mem::take(&mut *scan().get_list_mut());
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let changes = merge(&mut list.get_list_mut(), fresh, now);
+    *list.all_basic_info.lock().unwrap() = None;
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    RESCANNING.store(false, Ordering::Relaxed);
+    let new = {
+        let list = list.get_list_mut();
+        changes
+            .iter()
+            .map(|e| match e {
+                Event::ChaptersAdded { chapters, .. } => chapters.len(),
+                Event::MangaAdded { manga, .. } => list.get(manga).map_or(0, |m| m.chapters.len()),
+                _ => 0,
+            })
+            .sum::<usize>()
     };
-    Config {
-        port: config.port,
-        backend,
+    for e in changes {
+        events::publish(e);
     }
+    events::publish(Event::Scan(scan_progress()));
+    finished(list, new, start.elapsed());
+    crate::metrics::record_scan(library_name(), start.elapsed());
+    let manga = list.get_list_mut().len();
+    if new > 0 {
+        tracing::info!(
+            "rescanned {} manga in {:.2?}, {} new chapters",
+            manga,
+            start.elapsed(),
+            new
+        );
+    } else {
+        tracing::debug!(
+            "rescanned {} manga in {:.2?}, nothing new",
+            manga,
+            start.elapsed()
+        );
+    }
+    new
+}
+
+fn finished(list: &MangaList, new_chapters: usize, elapsed: Duration) {
+    let list = list.get_list_mut();
+    events::publish(Event::ScanFinished {
+        manga: list.len(),
+        chapters: list.values().map(|m| m.chapters.len()).sum(),
+        new_chapters,
+        elapsed_ms: elapsed.as_millis(),
+    });
+}
+
+/// Rescans the library every `interval`.
+pub async fn rescan_every(interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = tokio::task::spawn_blocking(rescan).await {
+            RESCANNING.store(false, Ordering::Relaxed);
+            // the backends panic on what they can not read
+            let error = match e.try_into_panic() {
+                Ok(p) => p
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| p.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "the scan panicked".to_owned()),
+                Err(e) => e.to_string(),
+            };
+            tracing::error!("library rescan failed: {}", error);
+            events::publish(Event::ScanFailed { error });
+        }
+    }
+}
+
+/// The library index, scanning the library first if that has not happened yet.
+pub fn get_list_ref() -> &'static MangaList {
+    MANGA_LIST.get_or_init(single_mangalist)
+}
+
+/// The library index, or `None` while it is still being built.
+pub fn try_get_list_ref() -> Option<&'static MangaList> {
+    MANGA_LIST.get()
 }
 #[test]
 fn t() {
@@ -197,3 +373,60 @@ fn t() {
     println!("{}", c);
     println!("{}", h.len());
 }
+
+/// A series for tests, `chapters` are the id, also used as the name, and
+/// the time each chapter was added. Every chapter has one page.
+#[cfg(test)]
+pub fn test_manga(id: &str, name: &str, chapters: &[(&str, u64)]) -> MangaInfo {
+    MangaInfo {
+        name: name.into(),
+        pic: String::new(),
+        id: id.into(),
+        chapters: chapters
+            .iter()
+            .map(|(id, added)| ChapterBasicInfo {
+                id: id.to_string(),
+                name: id.to_string(),
+                length: 1,
+                added: *added,
+            })
+            .collect(),
+        added: chapters.iter().map(|c| c.1).max().unwrap_or(0),
+    }
+}
+
+#[test]
+fn t_merge() {
+    let manga = |chapters: &[(&str, u64)]| test_manga("1", "[a] b [c]", chapters);
+    let mut current = HashMap::from([
+        ("1".to_owned(), manga(&[("x", 10)])),
+        ("2".to_owned(), manga(&[])),
+    ]);
+    // x was touched on disk, y is new
+    let fresh = HashMap::from([("1".to_owned(), manga(&[("x", 50), ("y", 40)]))]);
+    assert_eq!(
+        merge(&mut current, fresh, 100),
+        [
+            Event::MangaRemoved { manga: "2".into() },
+            Event::ChaptersAdded {
+                manga: "1".into(),
+                chapters: vec!["y".into()]
+            }
+        ]
+    );
+    let m = &current["1"];
+    assert_eq!(m.chapters[0].added, 10);
+    assert_eq!(m.chapters[1].added, 100);
+    assert_eq!(m.added, 100);
+}
+
+#[test]
+fn t_tags() {
+    let tags = |name: &str| test_manga("", name, &[]).tags();
+    assert_eq!(
+        tags("[作者] 名字 [Chinese]【完结】 [] [作者]"),
+        ["作者", "Chinese", "完结"]
+    );
+    assert!(tags("no tags").is_empty());
+    assert!(tags("[unclosed").is_empty());
+}
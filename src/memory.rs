@@ -0,0 +1,332 @@
+//! A library kept in memory, for tests and demos that have no folders of
+//! manga at hand.
+//!
+//! [`Library::generated`] makes series of plain coloured pages,
+//! [`Library::from_manifest`] reads a toml file listing series, chapters and
+//! their page files. [`seed`] puts a library in place, without it the
+//! `[memory]` section of the config decides what is served.
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use hyper::body::Bytes;
+
+use crate::{
+    backend::{content_type, BackendTrait, ChapterInfo, Pic},
+    manga_list::{ChapterBasicInfo, MangaInfo, MangaList},
+    range::Validators,
+    utils::ToResult,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Memory;
+
+lazy_static::lazy_static! {
+    /// Set by [`seed`] or from the config by the first scan.
+    static ref LIBRARY: RwLock<Option<Arc<Library>>> = RwLock::new(None);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    pub series: Vec<Series>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub id: String,
+    pub name: String,
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub id: String,
+    pub name: String,
+    /// Unix seconds.
+    pub added: u64,
+    pub pages: Vec<Page>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// File name, the content type follows its extension.
+    pub name: String,
+    pub data: Bytes,
+}
+impl Page {
+    /// A `width` x `height` png of one colour, `seed` picks the colour.
+    pub fn generated(seed: usize, width: u32, height: u32) -> Self {
+        // spread neighbouring seeds over the colour wheel
+        let hue = (seed * 47 % 360) as f32;
+        let x = 1.0 - ((hue / 60.0) % 2.0 - 1.0).abs();
+        let (r, g, b) = match hue as u32 / 60 {
+            0 => (1.0, x, 0.0),
+            1 => (x, 1.0, 0.0),
+            2 => (0.0, 1.0, x),
+            3 => (0.0, x, 1.0),
+            4 => (x, 0.0, 1.0),
+            _ => (1.0, 0.0, x),
+        };
+        let pixel = image::Rgb([(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]);
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(width, height, pixel)
+            .write_to(&mut data, image::ImageOutputFormat::Png)
+            .expect("png encoding into memory does not fail");
+        Self {
+            name: format!("{:03}.png", seed),
+            data: data.into_inner().into(),
+        }
+    }
+}
+
+/// The manifest read by [`Library::from_manifest`].
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    series: Vec<ManifestSeries>,
+}
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestSeries {
+    id: String,
+    name: String,
+    #[serde(default)]
+    chapters: Vec<ManifestChapter>,
+}
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestChapter {
+    id: String,
+    name: String,
+    added: Option<u64>,
+    /// Image files, relative to the manifest.
+    #[serde(default)]
+    pages: Vec<String>,
+    /// Generated pages put after `pages`.
+    #[serde(default)]
+    generated: usize,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Library {
+    /// `series` series of `chapters` chapters with `pages` generated pages
+    /// each. Ids count from 1, chapters were added a minute apart.
+    pub fn generated(series: usize, chapters: usize, pages: usize) -> Self {
+        let now = now();
+        let series = (1..=series)
+            .map(|s| Series {
+                id: s.to_string(),
+                name: format!("Series {}", s),
+                chapters: (1..=chapters)
+                    .map(|c| Chapter {
+                        id: c.to_string(),
+                        name: format!("Chapter {}", c),
+                        added: now - (chapters - c) as u64 * 60,
+                        pages: (0..pages)
+                            .map(|p| Page::generated((s * chapters + c) * pages + p, 60, 80))
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Self { series }
+    }
+
+    /// Reads a manifest like
+    ///
+    /// ```toml
+    /// [[series]]
+    /// id = '1'
+    /// name = 'Demo'
+    /// [[series.chapters]]
+    /// id = '1'
+    /// name = 'Chapter 1'
+    /// pages = ['demo/001.jpg', 'demo/002.jpg']
+    /// generated = 3
+    /// ```
+    pub fn from_manifest(path: &Path) -> anyhow::Result<Self> {
+        let f = std::fs::read_to_string(path)
+            .with_context(|| format!("can not read manifest `{}`", path.display()))?;
+        let manifest: Manifest =
+            toml::from_str(&f).with_context(|| format!("invalid manifest `{}`", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let now = now();
+        let mut seed = 0;
+        let mut series = Vec::with_capacity(manifest.series.len());
+        for s in manifest.series {
+            let mut chapters = Vec::with_capacity(s.chapters.len());
+            for c in s.chapters {
+                let mut pages = Vec::with_capacity(c.pages.len() + c.generated);
+                for p in &c.pages {
+                    let file = base.join(p);
+                    let data = std::fs::read(&file)
+                        .with_context(|| format!("can not read page `{}`", file.display()))?;
+                    pages.push(Page {
+                        name: p.rsplit(['/', '\\']).next().unwrap_or(p).to_owned(),
+                        data: data.into(),
+                    });
+                }
+                for _ in 0..c.generated {
+                    seed += 1;
+                    pages.push(Page::generated(seed, 60, 80));
+                }
+                chapters.push(Chapter {
+                    id: c.id,
+                    name: c.name,
+                    added: c.added.unwrap_or(now),
+                    pages,
+                });
+            }
+            series.push(Series {
+                id: s.id,
+                name: s.name,
+                chapters,
+            });
+        }
+        Ok(Self { series })
+    }
+
+    fn chapter(&self, manga_id: &str, chapter: &str) -> Option<&Chapter> {
+        self.series
+            .iter()
+            .find(|s| s.id == manga_id)?
+            .chapters
+            .iter()
+            .find(|c| c.id == chapter)
+    }
+}
+
+/// Serves `library` from now on, it shows up in the index with the next scan.
+pub fn seed(library: Library) {
+    *LIBRARY.write().unwrap() = Some(Arc::new(library));
+}
+
+fn library() -> anyhow::Result<Arc<Library>> {
+    if let Some(l) = LIBRARY.read().unwrap().clone() {
+        return Ok(l);
+    }
+    let c = crate::config::get().memory.clone().unwrap_or_default();
+    let library = match &c.manifest {
+        Some(p) => Library::from_manifest(Path::new(p))?,
+        None => Library::generated(c.series, c.chapters, c.pages),
+    };
+    Ok(LIBRARY
+        .write()
+        .unwrap()
+        .get_or_insert(Arc::new(library))
+        .clone())
+}
+
+#[async_trait::async_trait]
+impl BackendTrait for Memory {
+    fn generate_manga_list() -> MangaList {
+        let library = library().expect("can not load the memory library");
+        let list: HashMap<String, MangaInfo> = library
+            .series
+            .iter()
+            .map(|s| {
+                let chapters = s
+                    .chapters
+                    .iter()
+                    .map(|c| ChapterBasicInfo {
+                        id: c.id.clone(),
+                        name: c.name.clone(),
+                        length: c.pages.len(),
+                        added: c.added,
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    s.id.clone(),
+                    MangaInfo {
+                        name: s.name.clone(),
+                        pic: chapters
+                            .first()
+                            .map(|c| format!("/manga/{}/{}/0", s.id, c.id))
+                            .unwrap_or_default(),
+                        id: s.id.clone(),
+                        added: chapters.iter().map(|c| c.added).max().unwrap_or(0),
+                        chapters,
+                    },
+                )
+            })
+            .collect();
+        MangaList {
+            list: Arc::new(Mutex::new(list)),
+            path: Arc::new("memory".to_owned()),
+            all_basic_info: Arc::new(Mutex::new(None)),
+        }
+    }
+    async fn get_pic_in_chapter(
+        _base_path: &str,
+        manga_id: &str,
+        chapter: &str,
+        pic_id: usize,
+    ) -> anyhow::Result<Option<Pic>> {
+        let library = library()?;
+        let c = library.chapter(manga_id, chapter).to_result()?;
+        let page = match c.pages.get(pic_id) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let validators = Validators {
+            etag: Some(format!("\"{:08x}\"", crc32fast::hash(&page.data))),
+            last_modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(c.added)),
+        };
+        Ok(Some(Pic::from_reader(
+            Box::pin(std::io::Cursor::new(page.data.clone())),
+            page.data.len() as u64,
+            validators,
+            content_type(&page.name),
+        )))
+    }
+    async fn get_chapter_info(
+        _base_path: &str,
+        manga_id: &str,
+        chapter: &str,
+    ) -> anyhow::Result<ChapterInfo> {
+        let library = library()?;
+        let c = library.chapter(manga_id, chapter).to_result()?;
+        Ok(ChapterInfo {
+            length: c.pages.len(),
+            name: c.name.clone(),
+        })
+    }
+}
+
+#[test]
+fn t_manifest() {
+    let dir = std::env::temp_dir().join(format!("memory-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("pages")).unwrap();
+    std::fs::write(dir.join("pages/a.jpg"), b"not really a jpeg").unwrap();
+    std::fs::write(
+        dir.join("library.toml"),
+        "[[series]]\nid = 'x'\nname = 'X'\n[[series.chapters]]\nid = '1'\nname = 'One'\n\
+         added = 5\npages = ['pages/a.jpg']\ngenerated = 2\n",
+    )
+    .unwrap();
+    let l = Library::from_manifest(&dir.join("library.toml")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let c = l.chapter("x", "1").unwrap();
+    assert_eq!(c.added, 5);
+    assert_eq!(c.pages.len(), 3);
+    assert_eq!(c.pages[0].name, "a.jpg");
+    assert_eq!(&c.pages[0].data[..], b"not really a jpeg");
+    assert!(c.pages[1].data.starts_with(b"\x89PNG"));
+    assert_ne!(c.pages[1].data, c.pages[2].data);
+    assert!(l.chapter("x", "2").is_none());
+
+    let g = Library::generated(2, 3, 4);
+    assert_eq!(g.series.len(), 2);
+    assert_eq!(g.series[1].chapters[2].pages.len(), 4);
+    assert!(g.series[0].chapters[0].added < g.series[0].chapters[2].added);
+}
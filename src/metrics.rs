@@ -0,0 +1,345 @@
+//! Counters behind the `/metrics` endpoint, rendered in the prometheus text format.
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{manga_list, router::RouteError, utils::ToResultErr};
+
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+impl Histogram {
+    fn observe(&mut self, v: f64) {
+        for (i, b) in LATENCY_BUCKETS.iter().enumerate() {
+            if v <= *b {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += v;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// (route, method, status) -> count
+    requests: BTreeMap<(&'static str, String, u16), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    bytes: BTreeMap<&'static str, u64>,
+    errors: BTreeMap<&'static str, u64>,
+    scan_seconds: BTreeMap<String, f64>,
+    /// manga id -> pages served
+    pages_read: BTreeMap<String, u64>,
+    chapters_opened: u64,
+}
+
+/// What has been read since the server started.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReadingStats {
+    pub pages_served: u64,
+    pub chapters_opened: u64,
+    /// Most read first.
+    pub most_read: Vec<MangaReads>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct MangaReads {
+    pub id: String,
+    pub name: String,
+    pub pages: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+static ARCHIVE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static ARCHIVE_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_request(
+    route: &'static str,
+    method: &str,
+    status: u16,
+    bytes: Option<u64>,
+    latency: Duration,
+) {
+    let mut r = REGISTRY.lock().unwrap();
+    *r.requests
+        .entry((route, method.to_owned(), status))
+        .or_default() += 1;
+    r.latency
+        .entry(route)
+        .or_default()
+        .observe(latency.as_secs_f64());
+    if let Some(b) = bytes {
+        *r.bytes.entry(route).or_default() += b;
+    }
+}
+
+pub fn record_error(kind: &'static str) {
+    *REGISTRY.lock().unwrap().errors.entry(kind).or_default() += 1;
+}
+
+pub fn record_scan(backend: &str, took: Duration) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .scan_seconds
+        .insert(backend.to_owned(), took.as_secs_f64());
+}
+
+pub fn record_page_read(manga_id: &str) {
+    *REGISTRY
+        .lock()
+        .unwrap()
+        .pages_read
+        .entry(manga_id.to_owned())
+        .or_default() += 1;
+}
+
+pub fn record_chapter_opened() {
+    REGISTRY.lock().unwrap().chapters_opened += 1;
+}
+
+pub fn reading_stats(top: usize) -> ReadingStats {
+    let r = REGISTRY.lock().unwrap();
+    let mut most_read = r.pages_read.iter().collect::<Vec<_>>();
+    most_read.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    let names = manga_list::try_get_list_ref().map(|l| l.get_list_mut());
+    ReadingStats {
+        pages_served: r.pages_read.values().sum(),
+        chapters_opened: r.chapters_opened,
+        most_read: most_read
+            .into_iter()
+            .take(top)
+            .map(|(id, pages)| MangaReads {
+                id: id.clone(),
+                name: names
+                    .as_ref()
+                    .and_then(|n| n.get(id))
+                    .map(|m| m.name.clone())
+                    .unwrap_or_default(),
+                pages: *pages,
+            })
+            .collect(),
+    }
+}
+
+pub fn archive_cache_hit() {
+    ARCHIVE_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+pub fn archive_cache_miss() {
+    ARCHIVE_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Label for the metrics of a request path, keeps the label set small.
+pub fn route_label(path: &str) -> &'static str {
+    crate::router::route_name(path)
+}
+
+/// Sorts a resolver error into a small set of kinds for `errors_total`.
+pub fn error_kind(e: &anyhow::Error) -> &'static str {
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return match e.kind() {
+            std::io::ErrorKind::NotFound => "not_found",
+            // broken zips, see `zip`
+            std::io::ErrorKind::InvalidData => "archive",
+            _ => "io",
+        };
+    }
+    if e.downcast_ref::<std::num::ParseIntError>().is_some() {
+        return "bad_request";
+    }
+    if let Some(e) = e.downcast_ref::<RouteError>() {
+        return match e {
+            RouteError::BadRequest(_) => "bad_request",
+            _ => "not_found",
+        };
+    }
+    if e.downcast_ref::<ToResultErr>().is_some() {
+        return "not_found";
+    }
+    if e.downcast_ref::<serde_json::Error>().is_some() {
+        return "serialize";
+    }
+    "other"
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Everything in the prometheus text exposition format.
+pub fn render() -> String {
+    let mut o = String::new();
+    {
+        let r = REGISTRY.lock().unwrap();
+
+        o += "# HELP manga_server_http_requests_total Requests handled.\n";
+        o += "# TYPE manga_server_http_requests_total counter\n";
+        for ((route, method, status), v) in &r.requests {
+            let _ = writeln!(
+                o,
+                "manga_server_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                route,
+                escape(method),
+                status,
+                v
+            );
+        }
+
+        o += "# HELP manga_server_http_request_duration_seconds Time until the response head is ready.\n";
+        o += "# TYPE manga_server_http_request_duration_seconds histogram\n";
+        for (route, h) in &r.latency {
+            for (b, c) in LATENCY_BUCKETS.iter().zip(h.buckets) {
+                let _ = writeln!(
+                    o,
+                    "manga_server_http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, b, c
+                );
+            }
+            let _ = writeln!(
+                o,
+                "manga_server_http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route, h.count
+            );
+            let _ = writeln!(
+                o,
+                "manga_server_http_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route, h.sum
+            );
+            let _ = writeln!(
+                o,
+                "manga_server_http_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, h.count
+            );
+        }
+
+        o += "# HELP manga_server_http_response_bytes_total Response body bytes sent.\n";
+        o += "# TYPE manga_server_http_response_bytes_total counter\n";
+        for (route, v) in &r.bytes {
+            let _ = writeln!(
+                o,
+                "manga_server_http_response_bytes_total{{route=\"{}\"}} {}",
+                route, v
+            );
+        }
+
+        o += "# HELP manga_server_errors_total Failed requests by kind.\n";
+        o += "# TYPE manga_server_errors_total counter\n";
+        for (kind, v) in &r.errors {
+            let _ = writeln!(o, "manga_server_errors_total{{kind=\"{}\"}} {}", kind, v);
+        }
+
+        o += "# HELP manga_server_scan_duration_seconds Duration of the last library scan.\n";
+        o += "# TYPE manga_server_scan_duration_seconds gauge\n";
+        for (backend, v) in &r.scan_seconds {
+            let _ = writeln!(
+                o,
+                "manga_server_scan_duration_seconds{{backend=\"{}\"}} {}",
+                escape(backend),
+                v
+            );
+        }
+
+        o += "# HELP manga_server_pages_served_total Pages sent to readers.\n";
+        o += "# TYPE manga_server_pages_served_total counter\n";
+        let _ = writeln!(
+            o,
+            "manga_server_pages_served_total {}",
+            r.pages_read.values().sum::<u64>()
+        );
+        o += "# HELP manga_server_chapters_opened_total Chapter infos sent to readers.\n";
+        o += "# TYPE manga_server_chapters_opened_total counter\n";
+        let _ = writeln!(
+            o,
+            "manga_server_chapters_opened_total {}",
+            r.chapters_opened
+        );
+    }
+
+    o += "# HELP manga_server_archive_cache_hits_total Archive index lookups served from the cache.\n";
+    o += "# TYPE manga_server_archive_cache_hits_total counter\n";
+    let _ = writeln!(
+        o,
+        "manga_server_archive_cache_hits_total {}",
+        ARCHIVE_CACHE_HITS.load(Ordering::Relaxed)
+    );
+    o += "# HELP manga_server_archive_cache_misses_total Archive index lookups that had to open the archive.\n";
+    o += "# TYPE manga_server_archive_cache_misses_total counter\n";
+    let _ = writeln!(
+        o,
+        "manga_server_archive_cache_misses_total {}",
+        ARCHIVE_CACHE_MISSES.load(Ordering::Relaxed)
+    );
+
+    let backend = manga_list::library_name();
+    let (manga, chapters, pages) = match manga_list::try_get_list_ref() {
+        Some(list) => {
+            let list = list.get_list_mut();
+            let chapters = list.values().map(|m| m.chapters.len()).sum::<usize>();
+            let pages = list
+                .values()
+                .flat_map(|m| m.chapters.iter())
+                .map(|c| c.length)
+                .sum::<usize>();
+            (list.len(), chapters, pages)
+        }
+        None => (0, 0, 0),
+    };
+    for (name, help, v) in [
+        ("manga", "Manga in the index.", manga),
+        ("chapters", "Chapters in the index.", chapters),
+        (
+            "pages",
+            "Pages in the index, chapters with an unknown length count as 0.",
+            pages,
+        ),
+    ] {
+        let _ = writeln!(o, "# HELP manga_server_library_{} {}", name, help);
+        let _ = writeln!(o, "# TYPE manga_server_library_{} gauge", name);
+        let _ = writeln!(
+            o,
+            "manga_server_library_{}{{backend=\"{}\"}} {}",
+            name, backend, v
+        );
+    }
+    o
+}
+
+#[test]
+fn t_route_label() {
+    assert_eq!(route_label("/"), "index");
+    assert_eq!(route_label("/css/index.css"), "static");
+    assert_eq!(route_label("/manga/1"), "manga");
+    assert_eq!(route_label("/manga/1/2"), "chapter");
+    assert_eq!(route_label("/manga/1/2/3"), "page");
+    assert_eq!(route_label("/api/v1/series"), "api");
+    assert_eq!(route_label("/wp-login.php"), "other");
+}
+
+#[test]
+fn t_histogram() {
+    let mut h = Histogram::default();
+    h.observe(0.007);
+    h.observe(3.0);
+    assert_eq!(h.buckets[0], 0);
+    assert_eq!(h.buckets[1], 1);
+    assert_eq!(h.buckets[LATENCY_BUCKETS.len() - 1], 2);
+    assert_eq!(h.count, 2);
+}
@@ -0,0 +1,283 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use hyper::{
+    header::{self, HeaderValue},
+    http::{request::Parts, uri::Authority},
+    Request,
+};
+
+/// One address to accept connections on, written as `0.0.0.0:24317`,
+/// `[::1]:24317` or `unix:/run/manga-server.sock` in the config.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+impl FromStr for ListenAddr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("`{}`: unix socket path is empty", s));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse::<SocketAddr>().map(Self::Tcp).map_err(|_| {
+            format!(
+                "`{}` is not a listen address, expected `ip:port`, `[ipv6]:port` or `unix:/path`",
+                s
+            )
+        })
+    }
+}
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(a) => write!(f, "{}", a),
+            ListenAddr::Unix(p) => write!(f, "unix:{}", p.display()),
+        }
+    }
+}
+
+/// An address or a network in cidr notation, like `10.0.0.0/8` or `::1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an ipv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+fn prefix_eq(a: u128, b: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    shift == bits || (a >> shift) == (b >> shift)
+}
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("`{}` is not an ip address or network", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p.parse::<u8>().map_err(|_| err())?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| err())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(err());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+impl TryFrom<String> for Cidr {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Where a connection came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Unix sockets are only reachable by local processes such as a reverse
+    /// proxy, so they are always trusted to set forwarding headers.
+    Unix,
+}
+
+/// The client behind a request, after looking through trusted proxies.
+/// Inserted into the request extensions before it reaches the resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub peer: Peer,
+    /// Best known address of the client, `None` when a unix socket peer did
+    /// not forward one.
+    pub addr: Option<IpAddr>,
+    /// `http` or `https`, as seen by the client. Absolute links, such as
+    /// those in the atom feeds, use it through [`origin`](Self::origin).
+    pub scheme: String,
+}
+impl ClientInfo {
+    pub fn new<T>(req: &Request<T>, peer: Peer, tls: bool, trusted: &[Cidr]) -> Self {
+        let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+        let mut addr = match peer {
+            Peer::Tcp(a) => Some(a.ip()),
+            Peer::Unix => None,
+        };
+        let mut scheme = if tls { "https" } else { "http" }.to_string();
+
+        let peer_trusted = match peer {
+            Peer::Tcp(a) => is_trusted(a.ip()),
+            Peer::Unix => true,
+        };
+        if peer_trusted {
+            // walk from the nearest hop, the first untrusted address is the client
+            let forwarded = req
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .filter_map(|a| a.trim().parse::<IpAddr>().ok())
+                .collect::<Vec<_>>();
+            for a in forwarded.into_iter().rev() {
+                addr = Some(a);
+                if !is_trusted(a) {
+                    break;
+                }
+            }
+            if let Some(proto) = req
+                .headers()
+                .get("x-forwarded-proto")
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .map(|v| v.trim().to_ascii_lowercase())
+            {
+                if proto == "http" || proto == "https" {
+                    scheme = proto;
+                }
+            }
+        }
+        Self { peer, addr, scheme }
+    }
+
+    /// Scheme and host the client made the request to, such as
+    /// `https://example.com`, for absolute links.
+    pub fn origin(&self, req: &Parts) -> String {
+        let host = req
+            .headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<Authority>().ok())
+            .or_else(|| req.uri.authority().cloned());
+        match host {
+            Some(host) => format!("{}://{}", self.scheme, host),
+            None => format!("{}://localhost", self.scheme),
+        }
+    }
+}
+
+/// Binds a tcp listener, ipv6 sockets only take ipv6 connections so that
+/// `0.0.0.0:port` and `[::]:port` can be listened on at the same time.
+pub fn bind_tcp(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Binds a unix socket, a stale socket file left by a previous run is removed.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(m) = std::fs::symlink_metadata(path) {
+        if m.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+#[test]
+fn t_cidr() {
+    let c: Cidr = "10.0.0.0/8".parse().unwrap();
+    assert!(c.contains("10.1.2.3".parse().unwrap()));
+    assert!(c.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!c.contains("11.0.0.1".parse().unwrap()));
+    let c: Cidr = "::1".parse().unwrap();
+    assert!(c.contains("::1".parse().unwrap()));
+    assert!(!c.contains("127.0.0.1".parse().unwrap()));
+    let c: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(c.contains("1.2.3.4".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("localhost".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn t_listen_addr() {
+    assert_eq!(
+        "[::]:80".parse::<ListenAddr>(),
+        Ok(ListenAddr::Tcp("[::]:80".parse().unwrap()))
+    );
+    assert_eq!(
+        "unix:/tmp/a.sock".parse::<ListenAddr>(),
+        Ok(ListenAddr::Unix("/tmp/a.sock".into()))
+    );
+    assert!("0.0.0.0".parse::<ListenAddr>().is_err());
+}
+
+#[test]
+fn t_client_info() {
+    let trusted = ["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+    let req = Request::builder()
+        .header("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")
+        .header("x-forwarded-proto", "https")
+        .body(())
+        .unwrap();
+
+    let c = ClientInfo::new(
+        &req,
+        Peer::Tcp("127.0.0.1:5000".parse().unwrap()),
+        false,
+        &trusted,
+    );
+    assert_eq!(c.addr, Some("1.2.3.4".parse().unwrap()));
+    assert_eq!(c.scheme, "https");
+    let (proxied, _) = Request::builder()
+        .header("host", "example.com:8080")
+        .body(())
+        .unwrap()
+        .into_parts();
+    assert_eq!(c.origin(&proxied), "https://example.com:8080");
+
+    // headers from an untrusted peer are ignored
+    let c = ClientInfo::new(
+        &req,
+        Peer::Tcp("8.8.8.8:5000".parse().unwrap()),
+        false,
+        &trusted,
+    );
+    assert_eq!(c.addr, Some("8.8.8.8".parse().unwrap()));
+    assert_eq!(c.scheme, "http");
+
+    let c = ClientInfo::new(&req, Peer::Unix, false, &[]);
+    assert_eq!(c.addr, Some("10.0.0.2".parse().unwrap()));
+}
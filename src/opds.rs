@@ -0,0 +1,399 @@
+//! OPDS 1.2 catalog under `/opds` for reading apps, with the OPDS-PSE
+//! extension so pages can be streamed one by one.
+//!
+//! Feeds are atom documents built from the manga list: a navigation feed
+//! for the start, all series, recently added and search results, and an
+//! acquisition feed per series with one entry per chapter.
+use std::{collections::HashMap, sync::Mutex};
+
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Response,
+};
+
+use crate::{
+    backend::{content_type, ArchivePage, BackendTrait},
+    cbz,
+    maintenance::{self, chapter_length},
+    manga_list::{self, MangaInfo},
+    router::Query,
+    utils::{rfc3339, url_segment as seg, xml_escape},
+};
+
+pub const PREFIX: &str = "/opds";
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH: &str = "application/opensearchdescription+xml";
+const PSE_STREAM: &str = "http://vaemendis.net/opds-pse/stream";
+/// Series in the recently added feed.
+const RECENT: usize = 50;
+/// Series per page of the all series and search feeds.
+const SERIES_PAGE: usize = 100;
+
+/// Type of the first page by manga and chapter id.
+type CoverTypes = HashMap<(String, String), &'static str>;
+
+lazy_static::lazy_static! {
+    /// Cover types of the index generation they were looked up in.
+    static ref COVER_TYPES: Mutex<(usize, CoverTypes)> = Mutex::new((0, HashMap::new()));
+}
+
+struct Link {
+    rel: &'static str,
+    href: String,
+    kind: &'static str,
+    /// Further attributes, already escaped.
+    extra: String,
+}
+impl Link {
+    fn new(rel: &'static str, href: String, kind: &'static str) -> Self {
+        Self {
+            rel,
+            href,
+            kind,
+            extra: String::new(),
+        }
+    }
+    fn render(&self, out: &mut String) {
+        out.push_str(&format!(
+            "  <link rel=\"{}\" href=\"{}\" type=\"{}\"{}/>\n",
+            self.rel,
+            xml_escape(&self.href),
+            self.kind,
+            self.extra
+        ));
+    }
+}
+
+struct Entry {
+    id: String,
+    title: String,
+    updated: u64,
+    content: Option<String>,
+    links: Vec<Link>,
+}
+
+struct Feed {
+    id: String,
+    title: String,
+    kind: &'static str,
+    href: String,
+    /// More links of the feed, such as to the next page.
+    links: Vec<Link>,
+    entries: Vec<Entry>,
+}
+impl Feed {
+    fn new(id: &str, title: &str, kind: &'static str, href: String) -> Self {
+        Self {
+            id: format!("urn:manga-server:{}", id),
+            title: title.to_owned(),
+            kind,
+            href,
+            links: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn render(&self) -> String {
+        let updated = self.entries.iter().map(|e| e.updated).max().unwrap_or(0);
+        let mut out = String::from(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:opds=\"http://opds-spec.org/2010/catalog\" xmlns:pse=\"http://vaemendis.net/opds-pse/ns\">\n",
+        );
+        out.push_str(&format!("  <id>{}</id>\n", xml_escape(&self.id)));
+        out.push_str(&format!("  <title>{}</title>\n", xml_escape(&self.title)));
+        out.push_str(&format!("  <updated>{}</updated>\n", rfc3339(updated)));
+        out.push_str("  <author><name>manga-server</name></author>\n");
+        let links = [
+            Link::new("self", self.href.clone(), self.kind),
+            Link::new("start", PREFIX.to_owned(), NAVIGATION),
+            Link::new("search", format!("{}/search.xml", PREFIX), OPENSEARCH),
+        ];
+        for l in links.iter().chain(&self.links) {
+            l.render(&mut out);
+        }
+        for e in &self.entries {
+            out.push_str("  <entry>\n");
+            out.push_str(&format!("    <id>{}</id>\n", xml_escape(&e.id)));
+            out.push_str(&format!("    <title>{}</title>\n", xml_escape(&e.title)));
+            out.push_str(&format!("    <updated>{}</updated>\n", rfc3339(e.updated)));
+            if let Some(c) = &e.content {
+                out.push_str(&format!(
+                    "    <content type=\"text\">{}</content>\n",
+                    xml_escape(c)
+                ));
+            }
+            for l in &e.links {
+                out.push_str("  ");
+                l.render(&mut out);
+            }
+            out.push_str("  </entry>\n");
+        }
+        out.push_str("</feed>\n");
+        out
+    }
+
+    fn response(&self) -> Response<Body> {
+        xml(self.render(), self.kind)
+    }
+}
+
+fn xml(body: String, content_type: &'static str) -> Response<Body> {
+    let mut r = Response::new(Body::from(body));
+    r.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    r
+}
+
+fn page_href(manga: &str, chapter: &str, page: &str) -> String {
+    format!("/manga/{}/{}/{}", seg(manga), seg(chapter), page)
+}
+
+/// Cover links of a series or chapter, the first page of type `kind`.
+fn image_links(manga: &str, chapter: &str, kind: &'static str) -> [Link; 2] {
+    let href = page_href(manga, chapter, "0");
+    [
+        Link::new("http://opds-spec.org/image", href.clone(), kind),
+        Link::new("http://opds-spec.org/image/thumbnail", href, kind),
+    ]
+}
+
+/// Content type of the first page of a chapter, jpeg when it can not be
+/// told. Looked up once per index generation.
+async fn first_page_type<B: BackendTrait>(manga: &str, chapter: &str) -> &'static str {
+    let generation = manga_list::generation();
+    let key = (manga.to_owned(), chapter.to_owned());
+    {
+        let mut cache = COVER_TYPES.lock().unwrap();
+        if cache.0 != generation {
+            *cache = (generation, HashMap::new());
+        }
+        if let Some(kind) = cache.1.get(&key) {
+            return kind;
+        }
+    }
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let kind = match B::get_archive_page(&base_path, manga, chapter, 0).await {
+        Ok(Some(ArchivePage::Pic(pic))) => pic.content_type,
+        Ok(Some(ArchivePage::Zip { entry, .. })) => content_type(&entry.name),
+        _ => None,
+    }
+    .unwrap_or("image/jpeg");
+    let mut cache = COVER_TYPES.lock().unwrap();
+    if cache.0 == generation {
+        cache.1.insert(key, kind);
+    }
+    kind
+}
+
+/// Entries of `list`, with the type of each cover.
+async fn series_entries<B: BackendTrait>(list: &[MangaInfo]) -> Vec<Entry> {
+    let mut entries = Vec::with_capacity(list.len());
+    for m in list {
+        let kind = match m.chapters.first() {
+            Some(c) => first_page_type::<B>(&m.id, &c.id).await,
+            None => "image/jpeg",
+        };
+        entries.push(series_entry(m, kind));
+    }
+    entries
+}
+
+/// `cover` is the type of the first page of the first chapter.
+fn series_entry(m: &MangaInfo, cover: &'static str) -> Entry {
+    let mut links = vec![Link::new(
+        "subsection",
+        format!("{}/series/{}", PREFIX, seg(&m.id)),
+        ACQUISITION,
+    )];
+    if let Some(c) = m.chapters.first() {
+        links.extend(image_links(&m.id, &c.id, cover));
+    }
+    Entry {
+        id: format!("urn:manga-server:series:{}", m.id),
+        title: m.name.clone(),
+        updated: m.added,
+        content: Some(format!("{} chapters", m.chapters.len())),
+        links,
+    }
+}
+
+/// The start of the catalog.
+pub fn root() -> Response<Body> {
+    let mut feed = Feed::new("root", "manga-server", NAVIGATION, PREFIX.to_owned());
+    let list = maintenance::index();
+    let updated = list.iter().map(|m| m.added).max().unwrap_or(0);
+    for (id, title, href, content) in [
+        (
+            "all",
+            "All series",
+            "series",
+            format!(
+                "{} series of the {} library",
+                list.len(),
+                manga_list::library_name()
+            ),
+        ),
+        (
+            "recent",
+            "Recently added",
+            "recent",
+            "Series with new chapters first".to_owned(),
+        ),
+    ] {
+        feed.entries.push(Entry {
+            id: format!("urn:manga-server:{}", id),
+            title: title.to_owned(),
+            updated,
+            content: Some(content),
+            links: vec![Link::new(
+                "subsection",
+                format!("{}/{}", PREFIX, href),
+                NAVIGATION,
+            )],
+        });
+    }
+    feed.response()
+}
+
+/// All series by name, or the ones matching `q`, [`SERIES_PAGE`] at a time.
+/// `page` counts from 1.
+pub async fn series_list<B: BackendTrait>(query: &Query) -> Response<Body> {
+    let q = query.get("q");
+    let (id, title, href) = match q {
+        Some(q) => (
+            format!("search:{}", q),
+            format!("Search: {}", q),
+            format!("{}/series?q={}", PREFIX, seg(q)),
+        ),
+        None => (
+            "all".to_owned(),
+            "All series".to_owned(),
+            format!("{}/series", PREFIX),
+        ),
+    };
+    let page = query
+        .get("page")
+        .and_then(|p| p.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let page_href = |page: usize| {
+        let sep = if href.contains('?') { '&' } else { '?' };
+        format!("{}{}page={}", href, sep, page)
+    };
+    let q = q.map(str::to_lowercase);
+    let list = maintenance::index()
+        .into_iter()
+        .filter(|m| match &q {
+            Some(q) => m.name.to_lowercase().contains(q),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    let start = (page - 1).saturating_mul(SERIES_PAGE).min(list.len());
+    let end = (start + SERIES_PAGE).min(list.len());
+    let self_href = match page {
+        1 => href.clone(),
+        _ => page_href(page),
+    };
+    let mut feed = Feed::new(&id, &title, NAVIGATION, self_href);
+    if page > 1 {
+        feed.links
+            .push(Link::new("previous", page_href(page - 1), NAVIGATION));
+    }
+    if end < list.len() {
+        feed.links
+            .push(Link::new("next", page_href(page + 1), NAVIGATION));
+    }
+    feed.entries = series_entries::<B>(&list[start..end]).await;
+    feed.response()
+}
+
+pub async fn recent<B: BackendTrait>() -> Response<Body> {
+    let mut list = maintenance::index();
+    list.sort_by_key(|m| std::cmp::Reverse(m.added));
+    let mut feed = Feed::new(
+        "recent",
+        "Recently added",
+        NAVIGATION,
+        format!("{}/recent", PREFIX),
+    );
+    list.truncate(RECENT);
+    feed.entries = series_entries::<B>(&list).await;
+    feed.response()
+}
+
+/// Chapters of a series, to download or to stream page by page. `None` if
+/// there is no such series.
+pub async fn series<B: BackendTrait>(id: &str) -> anyhow::Result<Option<Response<Body>>> {
+    let m = match manga_list::get_list_ref().get_list_mut().get(id) {
+        Some(m) => m.clone(),
+        None => return Ok(None),
+    };
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let mut feed = Feed::new(
+        &format!("series:{}", m.id),
+        &m.name,
+        ACQUISITION,
+        format!("{}/series/{}", PREFIX, seg(&m.id)),
+    );
+    feed.entries.push(Entry {
+        id: format!("urn:manga-server:series:{}:all", m.id),
+        title: format!("{} (all chapters)", m.name),
+        updated: m.added,
+        content: None,
+        links: vec![Link::new(
+            "http://opds-spec.org/acquisition",
+            format!("/download/{}", seg(&m.id)),
+            cbz::CONTENT_TYPE,
+        )],
+    });
+    for c in &m.chapters {
+        let pages = chapter_length::<B>(&base_path, &m.id, &c.id, c.length).await?;
+        let kind = first_page_type::<B>(&m.id, &c.id).await;
+        let mut links = vec![
+            Link::new(
+                "http://opds-spec.org/acquisition",
+                format!("/download/{}/{}", seg(&m.id), seg(&c.id)),
+                cbz::CONTENT_TYPE,
+            ),
+            Link {
+                extra: format!(" pse:count=\"{}\"", pages),
+                ..Link::new(PSE_STREAM, page_href(&m.id, &c.id, "{pageNumber}"), kind)
+            },
+        ];
+        links.extend(image_links(&m.id, &c.id, kind));
+        feed.entries.push(Entry {
+            id: format!("urn:manga-server:chapter:{}:{}", m.id, c.id),
+            title: c.name.clone(),
+            updated: c.added,
+            content: Some(format!("{} pages", pages)),
+            links,
+        });
+    }
+    Ok(Some(feed.response()))
+}
+
+/// OpenSearch description pointing at the series search.
+pub fn search_description() -> Response<Body> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\">\n  <ShortName>manga-server</ShortName>\n  <Description>Search series by name</Description>\n  <InputEncoding>UTF-8</InputEncoding>\n  <OutputEncoding>UTF-8</OutputEncoding>\n  <Url type=\"{}\" template=\"{}/series?q={{searchTerms}}\"/>\n</OpenSearchDescription>\n",
+        xml_escape(NAVIGATION),
+        PREFIX
+    );
+    xml(body, OPENSEARCH)
+}
+
+#[test]
+fn t_feed() {
+    let m = crate::manga_list::test_manga("1 2", "A & B", &[("3", 1_700_000_000)]);
+    let mut feed = Feed::new("all", "All", NAVIGATION, "/opds/series".into());
+    feed.entries.push(series_entry(&m, "image/png"));
+    let xml = feed.render();
+    assert!(xml.contains("<title>A &amp; B</title>"));
+    assert!(xml.contains("<updated>2023-11-14T22:13:20Z</updated>"));
+    assert!(xml.contains("href=\"/opds/series/1%202\""));
+    assert!(xml.contains("href=\"/manga/1%202/3/0\" type=\"image/png\""));
+    assert_eq!(
+        page_href("1", "3", "{pageNumber}"),
+        "/manga/1/3/{pageNumber}"
+    );
+}
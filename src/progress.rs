@@ -0,0 +1,205 @@
+//! Where each user stopped reading, per chapter. Kept in memory and written
+//! to `progress_file` as json, periodically and at shutdown.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::events::{self, Event};
+
+/// Name used when a client does not say who it is.
+pub const ANONYMOUS: &str = "anonymous";
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub use manga_server_types::Progress;
+
+/// user -> manga -> chapter -> progress
+type Data = HashMap<String, HashMap<String, HashMap<String, Progress>>>;
+
+#[derive(Default)]
+struct Store {
+    /// Set by [`init`], which may come after progress was read or written.
+    path: OnceLock<PathBuf>,
+    data: Mutex<Data>,
+    dirty: AtomicBool,
+}
+
+static STORE: OnceLock<Store> = OnceLock::new();
+
+fn store() -> &'static Store {
+    STORE.get_or_init(Store::default)
+}
+
+/// Loads the progress saved at `path` and saves to it at shutdown, without a
+/// path progress only lives as long as the process. [`save_every`] saves in
+/// between.
+pub fn init(path: Option<PathBuf>) -> anyhow::Result<()> {
+    let data = match &path {
+        Some(p) if p.exists() => {
+            let s = std::fs::read_to_string(p)
+                .with_context(|| format!("can not read {}", p.display()))?;
+            serde_json::from_str(&s).with_context(|| format!("can not parse {}", p.display()))?
+        }
+        _ => Data::new(),
+    };
+    let store = store();
+    if let Some(p) = path {
+        store
+            .path
+            .set(p)
+            .map_err(|_| anyhow::anyhow!("read progress is already initialized"))?;
+    }
+    {
+        // what was recorded before `init` is newer than the file
+        let mut current = store.data.lock().unwrap();
+        let earlier = std::mem::replace(&mut *current, data);
+        if !earlier.is_empty() {
+            store.dirty.store(true, Ordering::SeqCst);
+        }
+        for (user, mangas) in earlier {
+            let saved = current.entry(user).or_default();
+            for (manga, chapters) in mangas {
+                saved.entry(manga).or_default().extend(chapters);
+            }
+        }
+    }
+    if store.path.get().is_some() {
+        crate::lifecycle::on_shutdown("save read progress", save);
+    }
+    Ok(())
+}
+
+/// Saves the progress every minute, returns at once without a progress file.
+pub async fn save_every() {
+    if store().path.get().is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match tokio::task::spawn_blocking(save).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("can not save read progress: {:#}", e),
+            Err(e) => tracing::error!("can not save read progress: {}", e),
+        }
+    }
+}
+
+/// Writes the progress if it changed since the last save.
+pub fn save() -> anyhow::Result<()> {
+    let store = store();
+    let path = match store.path.get() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if !store.dirty.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let json = serde_json::to_vec(&*store.data.lock().unwrap())?;
+    // a crash while writing must not lose what was saved before
+    let tmp = path.with_extension("json.tmp");
+    let result = std::fs::write(&tmp, json)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .with_context(|| format!("can not write {}", path.display()));
+    if result.is_err() {
+        store.dirty.store(true, Ordering::SeqCst);
+    }
+    result
+}
+
+pub fn get(user: &str, manga: &str, chapter: &str) -> Option<Progress> {
+    store()
+        .data
+        .lock()
+        .unwrap()
+        .get(user)?
+        .get(manga)?
+        .get(chapter)
+        .copied()
+}
+
+/// Progress of `user` in every chapter of `manga`.
+pub fn of_manga(user: &str, manga: &str) -> HashMap<String, Progress> {
+    store()
+        .data
+        .lock()
+        .unwrap()
+        .get(user)
+        .and_then(|m| m.get(manga))
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub fn set(user: &str, manga: &str, chapter: &str, page: usize, completed: bool) {
+    let updated = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let store = store();
+    store
+        .data
+        .lock()
+        .unwrap()
+        .entry(user.to_owned())
+        .or_default()
+        .entry(manga.to_owned())
+        .or_default()
+        .insert(
+            chapter.to_owned(),
+            Progress {
+                page,
+                completed,
+                updated,
+            },
+        );
+    store.dirty.store(true, Ordering::SeqCst);
+    events::publish(Event::Progress {
+        user: user.to_owned(),
+        manga: manga.to_owned(),
+        chapter: chapter.to_owned(),
+        page: Some(page),
+        completed,
+    });
+}
+
+pub fn clear(user: &str, manga: &str, chapter: &str) {
+    let store = store();
+    if let Some(m) = store
+        .data
+        .lock()
+        .unwrap()
+        .get_mut(user)
+        .and_then(|m| m.get_mut(manga))
+    {
+        if m.remove(chapter).is_some() {
+            store.dirty.store(true, Ordering::SeqCst);
+            events::publish(Event::Progress {
+                user: user.to_owned(),
+                manga: manga.to_owned(),
+                chapter: chapter.to_owned(),
+                page: None,
+                completed: false,
+            });
+        }
+    }
+}
+
+#[test]
+fn t_progress() {
+    set("t_progress", "m", "c", 3, false);
+    assert_eq!(get("t_progress", "m", "c").map(|p| p.page), Some(3));
+    assert_eq!(of_manga("t_progress", "m").len(), 1);
+    assert_eq!(get("other", "m", "c"), None);
+    // progress read or written before `init` does not stop it, nor get lost
+    init(None).unwrap();
+    assert_eq!(get("t_progress", "m", "c").map(|p| p.page), Some(3));
+    clear("t_progress", "m", "c");
+    assert_eq!(get("t_progress", "m", "c"), None);
+}
@@ -0,0 +1,220 @@
+//! `Range` and `If-Range` handling for pages and files.
+use std::{ops::RangeInclusive, time::SystemTime};
+
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Response, StatusCode,
+};
+
+/// What identifies a version of a response body, sent as `ETag` and
+/// `Last-Modified` and compared against `If-Range`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    /// A strong entity tag, quotes included.
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+impl Validators {
+    /// Validators of a file from its metadata.
+    pub fn from_metadata(m: &std::fs::Metadata) -> Self {
+        let modified = m.modified().ok();
+        let secs = modified
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            etag: Some(format!("\"{:x}-{:x}\"", secs, m.len())),
+            last_modified: modified,
+        }
+    }
+}
+
+/// What to send for a body of `len` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    Full,
+    Partial(RangeInclusive<u64>),
+    Unsatisfiable,
+}
+
+/// Decides between the full body, one range of it or a 416. Multiple ranges
+/// and malformed headers are answered with the full body, which is allowed.
+pub fn plan(headers: &HeaderMap, len: u64, validators: &Validators) -> Plan {
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(r) => r,
+        None => return Plan::Full,
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        if !if_range_matches(if_range.trim(), validators) {
+            return Plan::Full;
+        }
+    }
+    match parse_range(range, len) {
+        Some(Some(r)) => Plan::Partial(r),
+        Some(None) => Plan::Unsatisfiable,
+        None => Plan::Full,
+    }
+}
+
+fn if_range_matches(if_range: &str, v: &Validators) -> bool {
+    if if_range.starts_with('"') {
+        // only strong comparison is allowed for If-Range
+        return v.etag.as_deref() == Some(if_range);
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    match (httpdate::parse_http_date(if_range), v.last_modified) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date)
+        }
+        _ => false,
+    }
+}
+
+/// `None` when the header is not a single byte range we understand,
+/// `Some(None)` when it can not be satisfied.
+fn parse_range(header: &str, len: u64) -> Option<Option<RangeInclusive<u64>>> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // suffix: the last n bytes
+        let n = end.parse::<u64>().ok()?;
+        if n == 0 || len == 0 {
+            return Some(None);
+        }
+        len.saturating_sub(n)..=len - 1
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            end.parse::<u64>().ok()?
+        };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(None);
+        }
+        start..=end.min(len - 1)
+    };
+    Some(Some(range))
+}
+
+/// Response for data held in memory, sliced according to the request headers.
+pub fn respond(headers: &HeaderMap, data: Vec<u8>, validators: &Validators) -> Response<Body> {
+    let len = data.len() as u64;
+    match plan(headers, len, validators) {
+        Plan::Full => with_headers(Response::new(Body::from(data)), validators),
+        Plan::Partial(r) => {
+            let body = data[*r.start() as usize..=*r.end() as usize].to_vec();
+            partial(Body::from(body), r, len, validators)
+        }
+        Plan::Unsatisfiable => unsatisfiable(len),
+    }
+}
+
+/// Adds `Accept-Ranges` and the validators to a full response.
+pub fn with_headers(mut r: Response<Body>, validators: &Validators) -> Response<Body> {
+    let h = r.headers_mut();
+    h.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(etag) = validators
+        .etag
+        .as_deref()
+        .and_then(|e| HeaderValue::from_str(e).ok())
+    {
+        h.insert(header::ETAG, etag);
+    }
+    if let Some(m) = validators.last_modified {
+        if let Ok(v) = HeaderValue::from_str(&httpdate::fmt_http_date(m)) {
+            h.insert(header::LAST_MODIFIED, v);
+        }
+    }
+    r
+}
+
+/// A 206 carrying `range` of a body that is `len` bytes long.
+pub fn partial(
+    body: Body,
+    range: RangeInclusive<u64>,
+    len: u64,
+    validators: &Validators,
+) -> Response<Body> {
+    let mut r = with_headers(Response::new(body), validators);
+    *r.status_mut() = StatusCode::PARTIAL_CONTENT;
+    let content_range = format!("bytes {}-{}/{}", range.start(), range.end(), len);
+    if let Ok(v) = HeaderValue::from_str(&content_range) {
+        r.headers_mut().insert(header::CONTENT_RANGE, v);
+    }
+    r
+}
+
+pub fn unsatisfiable(len: u64) -> Response<Body> {
+    let mut r = Response::new(Body::empty());
+    *r.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+    if let Ok(v) = HeaderValue::from_str(&format!("bytes */{}", len)) {
+        r.headers_mut().insert(header::CONTENT_RANGE, v);
+    }
+    r.headers_mut()
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    r
+}
+
+#[test]
+fn t_parse_range() {
+    assert_eq!(parse_range("bytes=0-9", 100), Some(Some(0..=9)));
+    assert_eq!(parse_range("bytes=90-", 100), Some(Some(90..=99)));
+    assert_eq!(parse_range("bytes=90-200", 100), Some(Some(90..=99)));
+    assert_eq!(parse_range("bytes=-10", 100), Some(Some(90..=99)));
+    assert_eq!(parse_range("bytes=-200", 100), Some(Some(0..=99)));
+    assert_eq!(parse_range("bytes=100-", 100), Some(None));
+    assert_eq!(parse_range("bytes=-0", 100), Some(None));
+    assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+    assert_eq!(parse_range("bytes=5-1", 100), None);
+    assert_eq!(parse_range("items=0-1", 100), None);
+}
+
+#[test]
+fn t_if_range() {
+    let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    let v = Validators {
+        etag: Some("\"abc\"".into()),
+        last_modified: Some(modified),
+    };
+    let headers = |if_range: &str| {
+        let mut h = HeaderMap::new();
+        h.insert(header::RANGE, HeaderValue::from_static("bytes=0-0"));
+        h.insert(header::IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+        h
+    };
+    assert_eq!(plan(&headers("\"abc\""), 10, &v), Plan::Partial(0..=0));
+    assert_eq!(plan(&headers("\"old\""), 10, &v), Plan::Full);
+    assert_eq!(plan(&headers("W/\"abc\""), 10, &v), Plan::Full);
+    let date = httpdate::fmt_http_date(modified);
+    assert_eq!(plan(&headers(&date), 10, &v), Plan::Partial(0..=0));
+    assert_eq!(
+        plan(&headers("Sat, 01 Jan 2000 00:00:00 GMT"), 10, &v),
+        Plan::Full
+    );
+}
+
+#[test]
+fn t_respond() {
+    let mut h = HeaderMap::new();
+    h.insert(header::RANGE, HeaderValue::from_static("bytes=2-4"));
+    let r = respond(&h, b"0123456789".to_vec(), &Validators::default());
+    assert_eq!(r.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(r.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+    let body = pollster::block_on(hyper::body::to_bytes(r.into_body())).unwrap();
+    assert_eq!(&body[..], b"234");
+
+    h.insert(header::RANGE, HeaderValue::from_static("bytes=20-"));
+    let r = respond(&h, b"0123456789".to_vec(), &Validators::default());
+    assert_eq!(r.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(r.headers()[header::CONTENT_RANGE], "bytes */10");
+}
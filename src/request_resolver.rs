@@ -1,12 +1,21 @@
-use hyper::{Body, Request, Response, Uri};
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Method, Request, Response, StatusCode,
+};
 use std::error::Error;
 use std::fmt::Display;
 
-use tokio::fs;
+use crate::backend::{content_type, BackendTrait, Pic};
 
-use crate::backend::BackendTrait;
-
-use crate::manga_list;
+use crate::{
+    api, cbz,
+    compress::{self, Encoding},
+    feed, graphql, komga, manga_list,
+    net::{ClientInfo, Peer},
+    opds,
+    router::{self, Route, RouteError},
+    webdav,
+};
 
 #[derive(Debug, Clone)]
 struct StringError {
@@ -31,135 +40,249 @@ pub fn err<T>(s: &str) -> anyhow::Result<T> {
 pub async fn resolve<SelectedBackend: BackendTrait>(
     req: Request<Body>,
 ) -> anyhow::Result<Response<Body>> {
-    let uri = req.uri();
-    let path = uri.path().to_owned();
-    //dbg!(&path);
-    let p: Vec<&str> = path.split('/').collect();
-    //dbg!(&p);
-    let first_path = match p.get(1) {
-        Some(v) => v.to_owned().to_owned(),
-        None => return err("why no 1"),
+    let (parts, body) = req.into_parts();
+    let (route, query) = match router::route(&parts.method, &parts.uri) {
+        Ok(r) => r,
+        Err(e) => return route_error(parts.uri.path(), e),
     };
 
-    let response = match &first_path {
-        v if v == "favicon.ico" => {
-            let f = fs::read("res/favicon.ico").await?;
-            Response::new(Body::from(f))
-        }
-        v if v.is_empty() => {
-            let f = fs::read("res/html/index.html").await?;
-            Response::new(Body::from(f))
-        }
-        v if v == "pic" => {
-            let name = p.get(2);
-            if let Some(name) = name {
-                let f = get_res("pic", name).await?;
-                Response::new(Body::from(f))
-            } else {
-                return err("img not found");
+    let headers = &parts.headers;
+    let compression = &crate::config::get().compression;
+    if route.needs_index() && manga_list::try_get_list_ref().is_none() {
+        let mut r = Response::new(Body::from("library index is still being built"));
+        *r.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        r.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static("5"));
+        return Ok(r);
+    }
+
+    // absolute links follow the scheme the client used
+    let origin = || match parts.extensions.get::<ClientInfo>() {
+        Some(client) => client.origin(&parts),
+        None => ClientInfo::new(&Request::new(()), Peer::Unix, false, &[]).origin(&parts),
+    };
+    let res = crate::config::get().resources();
+    let response = match route {
+        Route::Favicon => file(headers, &format!("{}/favicon.ico", res)).await?,
+        Route::Index => file(headers, &format!("{}/html/index.html", res)).await?,
+        Route::Static { dir, name } => file(headers, &format!("{}/{}/{}", res, dir, name)).await?,
+        Route::Reader => file(headers, &format!("{}/html/reader.html", res)).await?,
+        Route::MangaPage => file(headers, &format!("{}/html/manga.html", res)).await?,
+
+        Route::Healthz => Response::new(Body::from("ok")),
+
+        Route::Events => crate::events::stream(headers),
+
+        Route::Readyz => {
+            let progress = manga_list::scan_progress();
+            let mut r = Response::new(Body::from(serde_json::to_string(&progress)?));
+            if !progress.ready || crate::lifecycle::is_shutting_down() {
+                *r.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
             }
+            r.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            r
         }
 
-        v if v == "css" => {
-            let name = p.get(2);
-            if let Some(name) = name {
-                let f = get_res("css", name).await?;
-                Response::new(Body::from(f))
-            } else {
-                return err("css not found");
-            }
+        Route::Metrics => {
+            let mut r = Response::new(Body::from(crate::metrics::render()));
+            r.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            r
         }
-        v if v == "html" => {
-            let name = p.get(2);
-            if let Some(name) = name {
-                let f = get_res("html", name).await?;
-                Response::new(Body::from(f))
-            } else {
-                return err("html not found");
-            }
+
+        Route::Stats => {
+            let stats = crate::maintenance::cached_stats::<SelectedBackend>().await?;
+            let mut r = Response::new(Body::from(serde_json::to_string(&stats)?));
+            r.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            r
         }
 
-        v if v == "reader" => Response::new(Body::from(fs::read(r"res/html/reader.html").await?)),
+        // `/info` and `/manga` predate `/api/v1` and are kept for the bundled pages
+        Route::Info { name } => json(get_info(&name)),
 
-        v if v == "manga_page" => {
-            Response::new(Body::from(fs::read(r"res/html/manga.html").await?))
+        Route::Manga { manga } => {
+            tracing::Span::current().record("manga", manga.as_str());
+            let out = {
+                serde_json::to_string(
+                    match { manga_list::get_list_ref().get_list_mut().get(&manga) } {
+                        Some(v) => v,
+                        None => return err("manga not found"),
+                    },
+                )?
+            };
+            json(out)
         }
 
-        v if v == "info" => {
-            let info = p.get(2);
-            if let Some(info) = info {
-                let i = get_info(info, uri).await?;
-                Response::new(Body::from(i))
-            } else {
-                return err("img not found");
-            }
+        Route::Chapter { manga, chapter } => {
+            tracing::Span::current().record("manga", manga.as_str());
+            tracing::Span::current().record("chapter", chapter.as_str());
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let info = SelectedBackend::get_chapter_info(&base_path, &manga, &chapter).await?;
+            crate::metrics::record_chapter_opened();
+            json(serde_json::to_string(&info)?)
         }
 
-        v if v == "manga" => {
-            let manga_id = match p.get(2) {
-                Some(v) => v,
-                None => return err("manga id needed"),
-            }
-            .to_string();
-
-            let chapter = match p.get(3) {
-                Some(v) => v,
-                None => {
-                    let out = {
-                        serde_json::to_string(
-                            match { manga_list::get_list_ref().get_list_mut().get(&manga_id) } {
-                                Some(v) => v,
-                                None => return err("manga not found"),
-                            },
-                        )?
-                    };
-                    return Ok(Response::new(Body::from(out)));
-                }
-            }
-            .to_string();
+        Route::Page {
+            manga,
+            chapter,
+            page,
+        } => {
+            tracing::Span::current().record("manga", manga.as_str());
+            tracing::Span::current().record("chapter", chapter.as_str());
+            tracing::Span::current().record("page", page);
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let pic = SelectedBackend::get_pic_in_chapter(&base_path, &manga, &chapter, page)
+                .await?
+                .ok_or_else(|| StringError::new("page not found"))?;
+            crate::metrics::record_page_read(&manga);
+            pic.respond(headers).await?
+        }
 
+        Route::Download { manga, chapter } => {
+            tracing::Span::current().record("manga", manga.as_str());
+            let m = match manga_list::get_list_ref().get_list_mut().get(&manga) {
+                Some(v) => v.clone(),
+                None => return err("manga not found"),
+            };
+            let index = match chapter {
+                Some(c) => match m.chapters.iter().position(|x| x.id == c) {
+                    Some(i) => Some(i),
+                    None => return err("chapter not found"),
+                },
+                None => None,
+            };
             let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let archive = cbz::plan::<SelectedBackend>(&base_path, &m, index).await?;
+            cbz::respond::<SelectedBackend>(headers, archive).await?
+        }
 
-            let pic_id = match p.get(4) {
-                Some(v) => v,
-                None => {
-                    let info =
-                        SelectedBackend::get_chapter_info(&base_path, &manga_id, &chapter).await?;
-                    let out = serde_json::to_string(&info).unwrap();
-                    return Ok(Response::new(Body::from(out)));
-                }
-            }
-            .parse::<usize>()?;
+        Route::RecentFeed => feed::recent(&origin()),
+        Route::SeriesFeed { id } => match feed::series(&origin(), &id) {
+            Some(r) => r,
+            None => return err("manga not found"),
+        },
+        Route::TagFeed { tag } => feed::tag(&origin(), &tag),
 
-            Response::new(Body::from(
-                SelectedBackend::get_pic_in_chapter(&base_path, &manga_id, &chapter, pic_id)
-                    .await?
-                    .unwrap(),
-            ))
-        }
+        Route::OpdsRoot => opds::root(),
+        Route::OpdsSearch => opds::search_description(),
+        Route::OpdsSeriesList => opds::series_list::<SelectedBackend>(&query).await,
+        Route::OpdsRecent => opds::recent::<SelectedBackend>().await,
+        Route::OpdsSeries { id } => match opds::series::<SelectedBackend>(&id).await? {
+            Some(r) => r,
+            None => return err("manga not found"),
+        },
+
+        Route::Komga(route) => api_response(
+            komga::handle::<SelectedBackend>(route, &parts.method, headers, &query, body).await,
+        ),
 
-        _ => {
-            let f = fs::read("res/html/404.html").await?;
+        Route::Graphql => api_response(
+            graphql::handle::<SelectedBackend>(&parts.method, headers, &query, body).await,
+        ),
 
-            Response::new(Body::from(f))
+        Route::Dav { path } => {
+            webdav::handle::<SelectedBackend>(&path, &parts.method, headers).await?
+        }
+
+        Route::OpenApi => api::openapi(),
+        Route::Libraries => api_response(api::list_libraries().await),
+        Route::Library { id } => api_response(api::get_library(&id).await),
+        Route::SeriesList => api_response(api::list_series(&query).await),
+        Route::Series { id } => api_response(api::get_series(&id).await),
+        Route::Chapters { series } => {
+            api_response(api::list_chapters::<SelectedBackend>(&series).await)
+        }
+        Route::ApiChapter { series, chapter } => {
+            api_response(api::get_chapter::<SelectedBackend>(&series, &chapter).await)
         }
+        Route::Pages { series, chapter } => {
+            api_response(api::list_pages::<SelectedBackend>(&series, &chapter).await)
+        }
+        Route::ApiPage {
+            series,
+            chapter,
+            page,
+        } => api_response(api::get_page::<SelectedBackend>(headers, &series, &chapter, page).await),
+        Route::ApiProgress { series, chapter } => api_response(match parts.method {
+            Method::PUT => {
+                api::put_progress::<SelectedBackend>(headers, &series, &chapter, body).await
+            }
+            Method::DELETE => api::delete_progress(headers, &series, &chapter).await,
+            _ => api::get_progress(headers, &series, &chapter).await,
+        }),
     };
 
-    Ok(response)
+    if !compression.enabled {
+        return Ok(response);
+    }
+    compress::encode(headers, response, compression.min_size).await
 }
 
-async fn get_res(t: &str, name: &str) -> Result<Vec<u8>, std::io::Error> {
-    let f = fs::read(&format!("res/{}/{}", t, name)).await?;
-    Ok(f)
+fn json(body: impl Into<Body>) -> Response<Body> {
+    let mut r = Response::new(body.into());
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    r
 }
 
-async fn get_info(info: &str, _uri: &Uri) -> Result<Vec<u8>, std::io::Error> {
-    let out = match info {
-        i if i == "all_manga" => manga_list::get_list_ref().all_json().into_bytes(),
-        _ => {
-            return Ok("".as_bytes().to_owned());
+fn api_response(r: api::ApiResult) -> Response<Body> {
+    r.unwrap_or_else(api::ApiError::into_response)
+}
+
+/// Api paths get a json error, other unknown paths fall through to the 404 page.
+fn route_error(path: &str, e: RouteError) -> anyhow::Result<Response<Body>> {
+    let mut r =
+        if path.starts_with("/api/") || path.starts_with(komga::PREFIX) || path == graphql::PATH {
+            api::ApiError::from(e.clone()).into_response()
+        } else if matches!(e, RouteError::MethodNotAllowed(_)) {
+            let mut r = Response::new(Body::from(e.to_string()));
+            *r.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            r
+        } else {
+            return Err(e.into());
+        };
+    if let RouteError::MethodNotAllowed(methods) = e {
+        let allow = methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(v) = HeaderValue::from_str(&allow) {
+            r.headers_mut().insert(header::ALLOW, v);
         }
-    };
+    }
+    Ok(r)
+}
+
+/// A file from disk, streamed, ranges are honoured. Text files are sent
+/// compressed from memory when the client accepts it and wants all of it.
+async fn file(headers: &HeaderMap, path: &str) -> Result<Response<Body>, std::io::Error> {
+    let encoding = compress::negotiate(headers);
+    if let Some(t) = content_type(path) {
+        if crate::config::get().compression.enabled
+            && compress::compressible(t)
+            && encoding != Encoding::Identity
+            && !headers.contains_key(header::RANGE)
+        {
+            return compress::static_file(path, encoding, t).await;
+        }
+    }
+    Pic::open(path).await?.respond(headers).await
+}
 
-    Ok(out)
+fn get_info(info: &str) -> Vec<u8> {
+    match info {
+        i if i == "all_manga" => manga_list::get_list_ref().all_json().into_bytes(),
+        _ => Vec::new(),
+    }
 }
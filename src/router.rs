@@ -0,0 +1,690 @@
+//! Turns the method and uri of a request into a [`Route`].
+//!
+//! Path segments are percent-decoded, a trailing slash is ignored and the
+//! query string is parsed into [`Query`].
+use std::{collections::HashMap, error::Error, fmt::Display, str::FromStr, sync::OnceLock};
+
+use hyper::{Method, Uri};
+
+/// Everything the server answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Index,
+    Favicon,
+    /// A file below `{resources}/{dir}`.
+    Static {
+        dir: &'static str,
+        name: String,
+    },
+    Reader,
+    MangaPage,
+    Info {
+        name: String,
+    },
+    Manga {
+        manga: String,
+    },
+    Chapter {
+        manga: String,
+        chapter: String,
+    },
+    Page {
+        manga: String,
+        chapter: String,
+        page: usize,
+    },
+    /// A chapter, or the whole series without `chapter`, as CBZ.
+    Download {
+        manga: String,
+        chapter: Option<String>,
+    },
+    RecentFeed,
+    SeriesFeed {
+        id: String,
+    },
+    TagFeed {
+        tag: String,
+    },
+    OpdsRoot,
+    OpdsSearch,
+    OpdsSeriesList,
+    OpdsRecent,
+    OpdsSeries {
+        id: String,
+    },
+    /// The Komga compatible api.
+    Komga(KomgaRoute),
+    Graphql,
+    /// Below `/dav`, see [`crate::webdav`].
+    Dav {
+        path: Vec<String>,
+    },
+    /// Server-sent events, see [`crate::events`].
+    Events,
+    Healthz,
+    Readyz,
+    Metrics,
+    Stats,
+    OpenApi,
+    Libraries,
+    Library {
+        id: String,
+    },
+    SeriesList,
+    Series {
+        id: String,
+    },
+    Chapters {
+        series: String,
+    },
+    ApiChapter {
+        series: String,
+        chapter: String,
+    },
+    Pages {
+        series: String,
+        chapter: String,
+    },
+    ApiPage {
+        series: String,
+        chapter: String,
+        page: usize,
+    },
+    ApiProgress {
+        series: String,
+        chapter: String,
+    },
+}
+
+/// Routes below `/komga/api/v1`, ids are the hex encoded ones of [`crate::komga`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KomgaRoute {
+    Libraries,
+    Library {
+        id: String,
+    },
+    SeriesList,
+    /// Newest series first, also answers `series/new`.
+    SeriesLatest,
+    Series {
+        id: String,
+    },
+    SeriesThumbnail {
+        id: String,
+    },
+    SeriesBooks {
+        id: String,
+    },
+    Book {
+        id: String,
+    },
+    BookPages {
+        id: String,
+    },
+    /// Pages start at 1.
+    BookPage {
+        id: String,
+        page: usize,
+    },
+    BookThumbnail {
+        id: String,
+    },
+    BookFile {
+        id: String,
+    },
+    ReadProgress {
+        id: String,
+    },
+}
+impl KomgaRoute {
+    fn parse(s: &[&str]) -> Result<Self, RouteError> {
+        let o = |v: &str| v.to_owned();
+        Ok(match s {
+            ["libraries"] => KomgaRoute::Libraries,
+            ["libraries", id] => KomgaRoute::Library { id: o(id) },
+            ["series"] => KomgaRoute::SeriesList,
+            ["series", "latest" | "new"] => KomgaRoute::SeriesLatest,
+            ["series", id] => KomgaRoute::Series { id: o(id) },
+            ["series", id, "thumbnail"] => KomgaRoute::SeriesThumbnail { id: o(id) },
+            ["series", id, "books"] => KomgaRoute::SeriesBooks { id: o(id) },
+            ["books", id] => KomgaRoute::Book { id: o(id) },
+            ["books", id, "pages"] => KomgaRoute::BookPages { id: o(id) },
+            ["books", id, "pages", p] => KomgaRoute::BookPage {
+                id: o(id),
+                page: page_number(p)?,
+            },
+            ["books", id, "thumbnail"] => KomgaRoute::BookThumbnail { id: o(id) },
+            ["books", id, "file"] => KomgaRoute::BookFile { id: o(id) },
+            ["books", id, "read-progress"] => KomgaRoute::ReadProgress { id: o(id) },
+            _ => return Err(RouteError::NotFound),
+        })
+    }
+}
+
+const READ: &[Method] = &[Method::GET, Method::HEAD];
+const PROGRESS: &[Method] = &[Method::PATCH, Method::DELETE];
+const GRAPHQL: &[Method] = &[Method::GET, Method::POST];
+const API_PROGRESS: &[Method] = &[Method::GET, Method::HEAD, Method::PUT, Method::DELETE];
+
+fn dav_methods() -> &'static [Method] {
+    static DAV: OnceLock<Vec<Method>> = OnceLock::new();
+    DAV.get_or_init(|| {
+        vec![
+            Method::OPTIONS,
+            Method::GET,
+            Method::HEAD,
+            Method::from_bytes(b"PROPFIND").unwrap(),
+        ]
+    })
+}
+
+impl Route {
+    /// Methods the route answers to.
+    pub fn methods(&self) -> &'static [Method] {
+        match self {
+            Route::Komga(KomgaRoute::ReadProgress { .. }) => PROGRESS,
+            Route::ApiProgress { .. } => API_PROGRESS,
+            Route::Graphql => GRAPHQL,
+            Route::Dav { .. } => dav_methods(),
+            _ => READ,
+        }
+    }
+
+    /// Label for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Route::Index => "index",
+            Route::Favicon | Route::Static { .. } => "static",
+            Route::Reader => "reader",
+            Route::MangaPage => "manga_page",
+            Route::Info { .. } => "info",
+            Route::Manga { .. } => "manga",
+            Route::Chapter { .. } => "chapter",
+            Route::Page { .. } => "page",
+            Route::Download { .. } => "download",
+            Route::RecentFeed | Route::SeriesFeed { .. } | Route::TagFeed { .. } => "feed",
+            Route::Komga(_) => "komga",
+            Route::Graphql => "graphql",
+            Route::Dav { .. } => "webdav",
+            Route::OpdsRoot
+            | Route::OpdsSearch
+            | Route::OpdsSeriesList
+            | Route::OpdsRecent
+            | Route::OpdsSeries { .. } => "opds",
+            Route::Events => "events",
+            Route::Healthz => "healthz",
+            Route::Readyz => "readyz",
+            Route::Metrics => "metrics",
+            Route::Stats
+            | Route::OpenApi
+            | Route::Libraries
+            | Route::Library { .. }
+            | Route::SeriesList
+            | Route::Series { .. }
+            | Route::Chapters { .. }
+            | Route::ApiChapter { .. }
+            | Route::Pages { .. }
+            | Route::ApiPage { .. }
+            | Route::ApiProgress { .. } => "api",
+        }
+    }
+
+    /// Whether answering needs the library index.
+    pub fn needs_index(&self) -> bool {
+        !matches!(
+            self,
+            Route::Index
+                | Route::Favicon
+                | Route::Static { .. }
+                | Route::Reader
+                | Route::MangaPage
+                | Route::OpdsSearch
+                | Route::Events
+                | Route::Healthz
+                | Route::Readyz
+                | Route::Metrics
+                | Route::OpenApi
+        )
+    }
+
+    fn parse(segments: &[String]) -> Result<Self, RouteError> {
+        let s = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        let o = |v: &str| v.to_owned();
+        Ok(match s.as_slice() {
+            [] => Route::Index,
+            ["favicon.ico"] => Route::Favicon,
+            ["pic", name] => Route::Static {
+                dir: "pic",
+                name: o(name),
+            },
+            ["css", name] => Route::Static {
+                dir: "css",
+                name: o(name),
+            },
+            ["html", name] => Route::Static {
+                dir: "html",
+                name: o(name),
+            },
+            // the pages read the manga and chapter from the rest of the path
+            ["reader", ..] => Route::Reader,
+            ["manga_page", ..] => Route::MangaPage,
+            ["info", name] => Route::Info { name: o(name) },
+            ["manga", m] => Route::Manga { manga: o(m) },
+            ["manga", m, c] => Route::Chapter {
+                manga: o(m),
+                chapter: o(c),
+            },
+            ["manga", m, c, p] => Route::Page {
+                manga: o(m),
+                chapter: o(c),
+                page: page_number(p)?,
+            },
+            ["download", m] => Route::Download {
+                manga: o(m),
+                chapter: None,
+            },
+            ["download", m, c] => Route::Download {
+                manga: o(m),
+                chapter: Some(o(c)),
+            },
+            ["feed", "recent.atom"] => Route::RecentFeed,
+            ["feed", "series", f] if f.ends_with(".atom") => Route::SeriesFeed {
+                id: o(&f[..f.len() - 5]),
+            },
+            ["feed", "tag", f] if f.ends_with(".atom") => Route::TagFeed {
+                tag: o(&f[..f.len() - 5]),
+            },
+            ["opds"] => Route::OpdsRoot,
+            ["opds", "search.xml"] => Route::OpdsSearch,
+            ["opds", "series"] => Route::OpdsSeriesList,
+            ["opds", "recent"] => Route::OpdsRecent,
+            ["opds", "series", id] => Route::OpdsSeries { id: o(id) },
+            ["graphql"] => Route::Graphql,
+            ["dav", rest @ ..] => Route::Dav {
+                path: rest.iter().map(|v| o(v)).collect(),
+            },
+            ["komga", "api", "v1", rest @ ..] => Route::Komga(KomgaRoute::parse(rest)?),
+            ["events"] => Route::Events,
+            ["healthz"] => Route::Healthz,
+            ["readyz"] => Route::Readyz,
+            ["metrics"] => Route::Metrics,
+            ["api", "stats"] => Route::Stats,
+            ["api", "v1", rest @ ..] => match rest {
+                ["openapi.json"] => Route::OpenApi,
+                ["libraries"] => Route::Libraries,
+                ["libraries", id] => Route::Library { id: o(id) },
+                ["series"] => Route::SeriesList,
+                ["series", id] => Route::Series { id: o(id) },
+                ["series", id, "chapters"] => Route::Chapters { series: o(id) },
+                ["series", id, "chapters", c] => Route::ApiChapter {
+                    series: o(id),
+                    chapter: o(c),
+                },
+                ["series", id, "chapters", c, "pages"] => Route::Pages {
+                    series: o(id),
+                    chapter: o(c),
+                },
+                ["series", id, "chapters", c, "pages", p] => Route::ApiPage {
+                    series: o(id),
+                    chapter: o(c),
+                    page: page_number(p)?,
+                },
+                ["series", id, "chapters", c, "progress"] => Route::ApiProgress {
+                    series: o(id),
+                    chapter: o(c),
+                },
+                _ => return Err(RouteError::NotFound),
+            },
+            _ => return Err(RouteError::NotFound),
+        })
+    }
+}
+
+fn page_number(s: &str) -> Result<usize, RouteError> {
+    s.parse()
+        .map_err(|_| RouteError::BadRequest(format!("`{}` is not a page number", s)))
+}
+
+/// Why a request did not match a route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    NotFound,
+    MethodNotAllowed(&'static [Method]),
+    BadRequest(String),
+}
+impl Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::NotFound => write!(f, "no such route"),
+            RouteError::MethodNotAllowed(_) => write!(f, "method not allowed"),
+            RouteError::BadRequest(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for RouteError {}
+
+/// Decoded query string parameters, the last value wins for repeated keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query(HashMap<String, String>);
+impl Query {
+    pub fn parse(query: Option<&str>) -> Self {
+        Self(
+            url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+    /// A parameter parsed as `T`, a value that does not parse is a bad request.
+    pub fn parse_value<T: FromStr>(&self, key: &str) -> Result<Option<T>, RouteError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => v.parse().map(Some).map_err(|_| {
+                RouteError::BadRequest(format!("invalid value `{}` for `{}`", v, key))
+            }),
+        }
+    }
+}
+
+/// Path segments with percent-decoding applied. Segments that would step out
+/// of a directory once decoded are rejected.
+fn segments(path: &str) -> Result<Vec<String>, RouteError> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    path.split('/')
+        .map(|s| {
+            let s = percent_encoding::percent_decode_str(s)
+                .decode_utf8()
+                .map_err(|_| RouteError::BadRequest("path is not valid utf-8".to_owned()))?;
+            if s == "." || s == ".." || s.contains(['/', '\\', '\0']) {
+                return Err(RouteError::BadRequest(format!(
+                    "invalid path segment `{}`",
+                    s
+                )));
+            }
+            Ok(s.into_owned())
+        })
+        .collect()
+}
+
+/// Finds the route for a request.
+pub fn route(method: &Method, uri: &Uri) -> Result<(Route, Query), RouteError> {
+    let route = Route::parse(&segments(uri.path())?)?;
+    if !route.methods().contains(method) {
+        return Err(RouteError::MethodNotAllowed(route.methods()));
+    }
+    Ok((route, Query::parse(uri.query())))
+}
+
+/// Route name of a path for metrics, without looking at the method.
+pub fn route_name(path: &str) -> &'static str {
+    match segments(path).and_then(|s| Route::parse(&s)) {
+        Ok(r) => r.name(),
+        Err(_) => "other",
+    }
+}
+
+#[cfg(test)]
+fn get(uri: &str) -> Result<(Route, Query), RouteError> {
+    route(&Method::GET, &uri.parse().unwrap())
+}
+
+#[test]
+fn t_pages() {
+    assert_eq!(get("/").unwrap().0, Route::Index);
+    assert_eq!(get("/favicon.ico").unwrap().0, Route::Favicon);
+    assert_eq!(
+        get("/css/index.css").unwrap().0,
+        Route::Static {
+            dir: "css",
+            name: "index.css".into()
+        }
+    );
+    assert_eq!(
+        get("/pic/a.png").unwrap().0,
+        Route::Static {
+            dir: "pic",
+            name: "a.png".into()
+        }
+    );
+    assert_eq!(
+        get("/html/404.html").unwrap().0,
+        Route::Static {
+            dir: "html",
+            name: "404.html".into()
+        }
+    );
+    assert_eq!(get("/reader").unwrap().0, Route::Reader);
+    assert_eq!(get("/reader/1/2").unwrap().0, Route::Reader);
+    assert_eq!(get("/manga_page").unwrap().0, Route::MangaPage);
+    assert_eq!(get("/manga_page/1").unwrap().0, Route::MangaPage);
+    assert_eq!(
+        get("/info/all_manga").unwrap().0,
+        Route::Info {
+            name: "all_manga".into()
+        }
+    );
+    assert_eq!(get("/events").unwrap().0, Route::Events);
+    assert_eq!(get("/healthz").unwrap().0, Route::Healthz);
+    assert_eq!(get("/readyz").unwrap().0, Route::Readyz);
+    assert_eq!(get("/metrics").unwrap().0, Route::Metrics);
+}
+
+#[test]
+fn t_manga() {
+    assert_eq!(
+        get("/manga/1").unwrap().0,
+        Route::Manga { manga: "1".into() }
+    );
+    assert_eq!(
+        get("/manga/1/2").unwrap().0,
+        Route::Chapter {
+            manga: "1".into(),
+            chapter: "2".into()
+        }
+    );
+    assert_eq!(
+        get("/manga/1/2/3").unwrap().0,
+        Route::Page {
+            manga: "1".into(),
+            chapter: "2".into(),
+            page: 3
+        }
+    );
+    assert!(matches!(
+        get("/manga/1/2/x"),
+        Err(RouteError::BadRequest(_))
+    ));
+    assert_eq!(get("/manga/1/2/3/4"), Err(RouteError::NotFound));
+    assert_eq!(
+        get("/download/1").unwrap().0,
+        Route::Download {
+            manga: "1".into(),
+            chapter: None
+        }
+    );
+    assert_eq!(
+        get("/download/1/2").unwrap().0,
+        Route::Download {
+            manga: "1".into(),
+            chapter: Some("2".into())
+        }
+    );
+}
+
+#[test]
+fn t_feed() {
+    assert_eq!(get("/feed/recent.atom").unwrap().0, Route::RecentFeed);
+    assert_eq!(
+        get("/feed/series/7.atom").unwrap().0,
+        Route::SeriesFeed { id: "7".into() }
+    );
+    assert_eq!(
+        get("/feed/tag/a%20b.atom").unwrap().0,
+        Route::TagFeed { tag: "a b".into() }
+    );
+    assert_eq!(get("/feed/tag/a"), Err(RouteError::NotFound));
+}
+
+#[test]
+fn t_opds() {
+    assert_eq!(get("/opds/").unwrap().0, Route::OpdsRoot);
+    assert_eq!(get("/opds/search.xml").unwrap().0, Route::OpdsSearch);
+    let (route, query) = get("/opds/series?q=one%20piece").unwrap();
+    assert_eq!(route, Route::OpdsSeriesList);
+    assert_eq!(query.get("q"), Some("one piece"));
+    assert_eq!(get("/opds/recent").unwrap().0, Route::OpdsRecent);
+    assert_eq!(
+        get("/opds/series/7").unwrap().0,
+        Route::OpdsSeries { id: "7".into() }
+    );
+}
+
+#[test]
+fn t_komga() {
+    assert_eq!(
+        get("/komga/api/v1/series/37/books?unpaged=true").unwrap().0,
+        Route::Komga(KomgaRoute::SeriesBooks { id: "37".into() })
+    );
+    assert_eq!(
+        get("/komga/api/v1/series/new").unwrap().0,
+        Route::Komga(KomgaRoute::SeriesLatest)
+    );
+    assert_eq!(
+        get("/komga/api/v1/books/37-31/pages/2").unwrap().0,
+        Route::Komga(KomgaRoute::BookPage {
+            id: "37-31".into(),
+            page: 2
+        })
+    );
+    let progress = "/komga/api/v1/books/37-31/read-progress".parse().unwrap();
+    assert!(route(&Method::PATCH, &progress).is_ok());
+    assert!(route(&Method::DELETE, &progress).is_ok());
+    assert_eq!(
+        route(&Method::GET, &progress),
+        Err(RouteError::MethodNotAllowed(PROGRESS))
+    );
+}
+
+#[test]
+fn t_graphql() {
+    let uri = "/graphql".parse().unwrap();
+    assert_eq!(route(&Method::POST, &uri).unwrap().0, Route::Graphql);
+    assert_eq!(
+        route(&Method::PUT, &uri),
+        Err(RouteError::MethodNotAllowed(GRAPHQL))
+    );
+}
+
+#[test]
+fn t_dav() {
+    assert_eq!(get("/dav").unwrap().0, Route::Dav { path: vec![] });
+    let uri = "/dav/dmzj/A%20B/001%20c.cbz".parse().unwrap();
+    let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+    assert_eq!(
+        route(&propfind, &uri).unwrap().0,
+        Route::Dav {
+            path: vec!["dmzj".into(), "A B".into(), "001 c.cbz".into()]
+        }
+    );
+    assert!(route(&Method::OPTIONS, &uri).is_ok());
+    assert!(matches!(
+        route(&Method::PUT, &uri),
+        Err(RouteError::MethodNotAllowed(_))
+    ));
+}
+
+#[test]
+fn t_api() {
+    assert_eq!(get("/api/stats").unwrap().0, Route::Stats);
+    assert_eq!(get("/api/v1/openapi.json").unwrap().0, Route::OpenApi);
+    assert_eq!(get("/api/v1/libraries").unwrap().0, Route::Libraries);
+    assert_eq!(
+        get("/api/v1/libraries/dmzj").unwrap().0,
+        Route::Library { id: "dmzj".into() }
+    );
+    assert_eq!(get("/api/v1/series").unwrap().0, Route::SeriesList);
+    assert_eq!(
+        get("/api/v1/series/7").unwrap().0,
+        Route::Series { id: "7".into() }
+    );
+    assert_eq!(
+        get("/api/v1/series/7/chapters").unwrap().0,
+        Route::Chapters { series: "7".into() }
+    );
+    assert_eq!(
+        get("/api/v1/series/7/chapters/100").unwrap().0,
+        Route::ApiChapter {
+            series: "7".into(),
+            chapter: "100".into()
+        }
+    );
+    assert_eq!(
+        get("/api/v1/series/7/chapters/100/pages").unwrap().0,
+        Route::Pages {
+            series: "7".into(),
+            chapter: "100".into()
+        }
+    );
+    assert_eq!(
+        get("/api/v1/series/7/chapters/100/pages/2").unwrap().0,
+        Route::ApiPage {
+            series: "7".into(),
+            chapter: "100".into(),
+            page: 2
+        }
+    );
+    let progress = "/api/v1/series/7/chapters/100/progress".parse().unwrap();
+    assert!(route(&Method::PUT, &progress).is_ok());
+    assert!(route(&Method::DELETE, &progress).is_ok());
+    assert!(matches!(
+        route(&Method::POST, &progress),
+        Err(RouteError::MethodNotAllowed(_))
+    ));
+    assert_eq!(get("/api/v2/series"), Err(RouteError::NotFound));
+}
+
+#[test]
+fn t_decode_and_normalize() {
+    assert_eq!(
+        get("/manga/%E4%B8%80%20a/").unwrap().0,
+        Route::Manga {
+            manga: "一 a".into()
+        }
+    );
+    assert_eq!(get("/reader/").unwrap().0, Route::Reader);
+    assert!(matches!(
+        get("/css/..%2F..%2Fconfig.toml"),
+        Err(RouteError::BadRequest(_))
+    ));
+    assert!(matches!(get("/css/.."), Err(RouteError::BadRequest(_))));
+    assert!(matches!(get("/manga/%FF"), Err(RouteError::BadRequest(_))));
+    assert_eq!(get("/wp-login.php"), Err(RouteError::NotFound));
+    assert_eq!(get("//reader"), Err(RouteError::NotFound));
+}
+
+#[test]
+fn t_method() {
+    let uri = "/manga/1".parse().unwrap();
+    assert!(route(&Method::HEAD, &uri).is_ok());
+    assert_eq!(
+        route(&Method::POST, &uri),
+        Err(RouteError::MethodNotAllowed(READ))
+    );
+    let uri = "/nope".parse().unwrap();
+    assert_eq!(route(&Method::POST, &uri), Err(RouteError::NotFound));
+}
+
+#[test]
+fn t_query() {
+    let (_, q) = get("/api/v1/series?limit=10&q=a%20b&offset=x").unwrap();
+    assert_eq!(q.get("q"), Some("a b"));
+    assert_eq!(q.parse_value::<usize>("limit"), Ok(Some(10)));
+    assert_eq!(q.parse_value::<usize>("missing"), Ok(None));
+    assert!(q.parse_value::<usize>("offset").is_err());
+}
@@ -0,0 +1,554 @@
+//! The http server as a library. [`ServerBuilder`] sets it up from a
+//! [`Config`], with an own backend, extra routes and middleware if wanted,
+//! and [`Server::run_until`] is the future serving it.
+//!
+//! The config, the library index and read progress are process wide, so
+//! [`ServerBuilder::build`] succeeds once per process and with at most one
+//! custom backend. A process embedding several libraries runs several
+//! processes.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use manga_server::{config::{Backend, Config}, net::ListenAddr, server::ServerBuilder};
+//!
+//! let mut config = Config::new(Backend::CopyManga);
+//! config.copy_manga = Some(manga_server::config::PathConfig { path: "/srv/manga".into() });
+//! let server = ServerBuilder::new(config)
+//!     .listen(ListenAddr::Tcp("127.0.0.1:8080".parse()?))
+//!     .route("/hello", |_req| async { Ok(hyper::Response::new("hi".into())) })
+//!     .layer(|req, next| async move {
+//!         tracing::info!("{}", req.uri());
+//!         next.run(req).await
+//!     })
+//!     .build()?;
+//! let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+//! tokio::spawn(server.run_until(async { stopped.await.ok(); }));
+//! // later
+//! stop.send(()).ok();
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use hyper::{
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server as HyperServer, StatusCode,
+};
+use tokio::{net::TcpStream, task::JoinSet};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::Instrument;
+
+use crate::{
+    backend::BackendTrait,
+    config::{self, Backend, Config},
+    copy_manga, dmzj, eh, events, lifecycle,
+    logging::{self, LoggedBody, RequestSummary},
+    manga_list, memory, metrics,
+    net::{self, ClientInfo, ListenAddr, Peer},
+    progress, request_resolver, shaft, tls, webhooks,
+};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+/// Answers a request, an error is answered with the 404 page.
+pub type Handler =
+    Arc<dyn Fn(Request<Body>) -> BoxFuture<anyhow::Result<Response<Body>>> + Send + Sync>;
+type Middleware =
+    Arc<dyn Fn(Request<Body>, Next) -> BoxFuture<anyhow::Result<Response<Body>>> + Send + Sync>;
+
+/// The rest of the middleware and the route, for a middleware to pass the
+/// request on.
+pub struct Next {
+    middleware: Arc<Vec<Middleware>>,
+    index: usize,
+    endpoint: Handler,
+}
+impl Next {
+    pub fn run(self, req: Request<Body>) -> BoxFuture<anyhow::Result<Response<Body>>> {
+        match self.middleware.clone().get(self.index) {
+            Some(m) => m(
+                req,
+                Next {
+                    index: self.index + 1,
+                    ..self
+                },
+            ),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+fn resolver<B: BackendTrait>() -> Handler {
+    Arc::new(|req| Box::pin(request_resolver::resolve::<B>(req)))
+}
+
+fn builtin(backend: Backend) -> Option<Handler> {
+    match backend {
+        Backend::DMZJ => Some(resolver::<dmzj::Dmzj>()),
+        Backend::CopyManga => Some(resolver::<copy_manga::CopyManga>()),
+        Backend::Eh => Some(resolver::<eh::Eh>()),
+        Backend::Shaft => Some(resolver::<shaft::Shaft>()),
+        Backend::Memory => Some(resolver::<memory::Memory>()),
+        Backend::Custom => None,
+    }
+}
+
+/// What every connection shares.
+struct App {
+    middleware: Arc<Vec<Middleware>>,
+    endpoint: Handler,
+}
+
+/// Extra routes first, by longest prefix, then the built-in ones.
+fn endpoint(mut routes: Vec<(String, Handler)>, fallback: Handler) -> Handler {
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    Arc::new(move |req| {
+        let path = req.uri().path();
+        let handler = routes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map_or(&fallback, |(_, h)| h);
+        handler(req)
+    })
+}
+
+/// Name, registration and resolver of a backend given to the builder.
+type CustomBackend = (
+    &'static str,
+    fn(&'static str) -> anyhow::Result<()>,
+    Handler,
+);
+
+/// Sets up a [`Server`]. The config is kept process wide by
+/// [`build`](Self::build), so it succeeds once per process, later calls fail.
+pub struct ServerBuilder {
+    config: Config,
+    backend: Option<CustomBackend>,
+    routes: Vec<(String, Handler)>,
+    middleware: Vec<Middleware>,
+    listen: Vec<ListenAddr>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            backend: None,
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            listen: Vec::new(),
+        }
+    }
+
+    /// Serves the library of `B` instead of the backend of the config,
+    /// `name` is the library id in the apis.
+    pub fn backend<B: BackendTrait>(mut self, name: &'static str) -> Self {
+        self.config.backend = Backend::Custom;
+        self.backend = Some((name, manga_list::register_backend::<B>, resolver::<B>()));
+        self
+    }
+
+    /// Answers the requests for `prefix` and the paths below it with
+    /// `handler`, before the built-in routes.
+    pub fn route<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(Request<Body>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Response<Body>>> + Send + 'static,
+    {
+        let prefix = format!("/{}", prefix.trim_matches('/'));
+        self.routes
+            .push((prefix, Arc::new(move |req| Box::pin(handler(req)))));
+        self
+    }
+
+    /// Runs `middleware` around every request, it passes the request on with
+    /// [`Next::run`]. The first added is the outermost.
+    pub fn layer<F, Fut>(mut self, middleware: F) -> Self
+    where
+        F: Fn(Request<Body>, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Response<Body>>> + Send + 'static,
+    {
+        self.middleware
+            .push(Arc::new(move |req, next| Box::pin(middleware(req, next))));
+        self
+    }
+
+    /// Listens on `addr` instead of the listeners of the config, can be
+    /// called several times. Port 0 picks a free port, see
+    /// [`Server::local_addrs`].
+    pub fn listen(mut self, addr: ListenAddr) -> Self {
+        self.listen.push(addr);
+        self
+    }
+
+    /// Stores the config for the process and binds the listeners, it has to
+    /// be called inside a tokio runtime. It fails when a server was built
+    /// before in this process.
+    pub fn build(self) -> anyhow::Result<Server> {
+        let mut config = self.config;
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        config.validate()?;
+        let backend = config.backend;
+        config::init(config).context("there can be one server per process")?;
+        let resolve = match self.backend {
+            Some((name, register, resolve)) => {
+                register(name)?;
+                resolve
+            }
+            None => builtin(backend).ok_or_else(|| anyhow::anyhow!("no backend is registered"))?,
+        };
+        let config = config::get();
+
+        let tls = match &config.tls {
+            Some(c) => Some(tls::acceptor(c)?),
+            None => None,
+        };
+        let mut listeners = Vec::new();
+        for listen in config.listeners() {
+            let listener = match &listen {
+                ListenAddr::Tcp(addr) => Listener::Tcp(
+                    net::bind_tcp(*addr)
+                        .map_err(|e| anyhow::anyhow!("can not listen on {}: {}", listen, e))?,
+                ),
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => {
+                    let listener = net::bind_unix(path)
+                        .map_err(|e| anyhow::anyhow!("can not listen on {}: {}", listen, e))?;
+                    let path = path.clone();
+                    lifecycle::on_shutdown("remove unix socket", move || {
+                        std::fs::remove_file(&path)?;
+                        Ok(())
+                    });
+                    Listener::Unix(listener)
+                }
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => {
+                    anyhow::bail!(
+                        "can not listen on {}: unix sockets are not supported",
+                        listen
+                    )
+                }
+            };
+            let https = tls.is_some() && matches!(listener, Listener::Tcp(_));
+            tracing::info!(
+                "listening on {}{}",
+                match &listener {
+                    Listener::Tcp(l) =>
+                        l.local_addr().map_or(listen.to_string(), |a| a.to_string()),
+                    #[cfg(unix)]
+                    Listener::Unix(_) => listen.to_string(),
+                },
+                if https { " (https)" } else { "" }
+            );
+            listeners.push(listener);
+        }
+
+        let redirect = match config.tls.as_ref().and_then(|c| c.redirect_http) {
+            Some(addr) => {
+                let https_port = listeners
+                    .iter()
+                    .find_map(|l| match l {
+                        Listener::Tcp(l) => l.local_addr().ok().map(|a| a.port()),
+                        #[cfg(unix)]
+                        Listener::Unix(_) => None,
+                    })
+                    .unwrap_or(443);
+                let listener = net::bind_tcp(addr)
+                    .map_err(|e| anyhow::anyhow!("can not listen on {}: {}", addr, e))?;
+                tracing::info!("redirecting http on {} to https", addr);
+                Some((listener, https_port))
+            }
+            None => None,
+        };
+
+        Ok(Server {
+            app: Arc::new(App {
+                middleware: Arc::new(self.middleware),
+                endpoint: endpoint(self.routes, resolve),
+            }),
+            listeners,
+            tls,
+            redirect,
+        })
+    }
+}
+
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// A server with its listeners bound, not answering yet.
+pub struct Server {
+    app: Arc<App>,
+    listeners: Vec<Listener>,
+    tls: Option<(TlsAcceptor, Arc<tls::CertResolver>)>,
+    redirect: Option<(tokio::net::TcpListener, u16)>,
+}
+
+impl Server {
+    /// Addresses of the tcp listeners.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|l| match l {
+                Listener::Tcp(l) => l.local_addr().ok(),
+                #[cfg(unix)]
+                Listener::Unix(_) => None,
+            })
+            .collect()
+    }
+
+    /// Serves until `shutdown` completes, then waits for open requests up to
+    /// `shutdown_timeout` and runs the shutdown hooks. Signals are left to the
+    /// application, e.g. `run_until(lifecycle::signal())`.
+    ///
+    /// The background work, rescans, saving progress, webhooks and reloading
+    /// the certificate, ends with the returned future, also when it is
+    /// dropped.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let config = config::get();
+        let mut background = JoinSet::new();
+        webhooks::start(
+            &config.webhooks,
+            config.webhook_log.as_deref(),
+            &mut background,
+        )?;
+        // scan in the background, `/readyz` reports when it is done
+        let mut scan = tokio::task::spawn_blocking(manga_list::get_list_ref);
+        background.spawn(events::watch_scan());
+        let mut scanned = false;
+        progress::init(config.progress_file.clone())?;
+        background.spawn(progress::save_every());
+        if let (Some((_, resolver)), Some(c)) = (&self.tls, &config.tls) {
+            background.spawn(tls::watch(
+                resolver.clone(),
+                Duration::from_secs(c.reload_interval),
+            ));
+        }
+
+        let mut servers = JoinSet::new();
+        for listener in self.listeners {
+            let app = self.app.clone();
+            match listener {
+                Listener::Tcp(listener) => {
+                    if let Some((acceptor, _)) = &self.tls {
+                        let make_svc = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                            let peer = conn.get_ref().0.peer_addr();
+                            let app = app.clone();
+                            async move {
+                                let peer = Peer::Tcp(peer?);
+                                Ok::<_, std::io::Error>(service_fn(move |req| {
+                                    handle(app.clone(), req, peer, true)
+                                }))
+                            }
+                        });
+                        let incoming = tls::incoming(listener, acceptor.clone());
+                        servers.spawn(
+                            HyperServer::builder(incoming)
+                                .serve(make_svc)
+                                .with_graceful_shutdown(lifecycle::shutdown_requested()),
+                        );
+                    } else {
+                        let make_svc = make_service_fn(move |conn: &AddrStream| {
+                            let peer = Peer::Tcp(conn.remote_addr());
+                            let app = app.clone();
+                            async move {
+                                Ok::<_, hyper::Error>(service_fn(move |req| {
+                                    handle(app.clone(), req, peer, false)
+                                }))
+                            }
+                        });
+                        servers.spawn(
+                            HyperServer::builder(AddrIncoming::from_listener(listener)?)
+                                .serve(make_svc)
+                                .with_graceful_shutdown(lifecycle::shutdown_requested()),
+                        );
+                    }
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    let incoming = hyper::server::accept::poll_fn(move |cx| {
+                        listener.poll_accept(cx).map(|r| Some(r.map(|(s, _)| s)))
+                    });
+                    let make_svc = make_service_fn(move |_| {
+                        let app = app.clone();
+                        async move {
+                            Ok::<_, hyper::Error>(service_fn(move |req| {
+                                handle(app.clone(), req, Peer::Unix, false)
+                            }))
+                        }
+                    });
+                    servers.spawn(
+                        HyperServer::builder(incoming)
+                            .serve(make_svc)
+                            .with_graceful_shutdown(lifecycle::shutdown_requested()),
+                    );
+                }
+            }
+        }
+
+        if let Some((listener, https_port)) = self.redirect {
+            let make_svc = make_service_fn(move |_| async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| async move {
+                    Ok::<_, hyper::Error>(tls::redirect(&req, https_port))
+                }))
+            });
+            servers.spawn(
+                HyperServer::builder(AddrIncoming::from_listener(listener)?)
+                    .serve(make_svc)
+                    .with_graceful_shutdown(lifecycle::shutdown_requested()),
+            );
+        }
+
+        tokio::pin!(shutdown);
+        let result = loop {
+            tokio::select! {
+                r = servers.join_next() => match r {
+                    None => break Ok(()),
+                    Some(Ok(Ok(()))) => continue,
+                    Some(Ok(Err(e))) => break Err(anyhow::anyhow!("server error: {}", e)),
+                    Some(Err(e)) => break Err(anyhow::anyhow!("server task failed: {}", e)),
+                },
+                r = &mut scan, if !scanned => match r {
+                    Ok(_) => {
+                        scanned = true;
+                        if let Some(secs) = config.rescan_interval {
+                            background.spawn(manga_list::rescan_every(Duration::from_secs(secs)));
+                        }
+                    }
+                    Err(e) => break Err(anyhow::anyhow!("library scan failed: {}", e)),
+                },
+                _ = &mut shutdown => break Ok(()),
+            }
+        };
+
+        tracing::info!("shutting down, waiting for open requests");
+        lifecycle::request_shutdown();
+        let timeout = Duration::from_secs(config.shutdown_timeout);
+        let drained = tokio::time::timeout(timeout, async {
+            while let Some(r) = servers.join_next().await {
+                match r {
+                    Ok(Err(e)) => tracing::error!("server error: {}", e),
+                    Err(e) => tracing::error!("server task failed: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                "requests still open after {}s, closing them",
+                config.shutdown_timeout
+            );
+            servers.abort_all();
+        }
+        background.abort_all();
+        while background.join_next().await.is_some() {}
+
+        if !lifecycle::run_hooks() {
+            anyhow::bail!("shutdown did not finish cleanly");
+        }
+        result
+    }
+}
+
+async fn not_found() -> Response<Body> {
+    let page = PathBuf::from(config::get().resources()).join("html/404.html");
+    let f = tokio::fs::read(page)
+        .await
+        .unwrap_or_else(|_| b"404 Not Found".to_vec());
+    let mut r = Response::new(Body::from(f));
+    *r.status_mut() = StatusCode::NOT_FOUND;
+    r
+}
+
+async fn handle(
+    app: Arc<App>,
+    mut req: Request<Body>,
+    peer: Peer,
+    tls: bool,
+) -> Result<Response<LoggedBody>, hyper::Error> {
+    let client = ClientInfo::new(&req, peer, tls, &config::get().trusted_proxies);
+    let span = logging::request_span(&req, client.addr);
+    let summary = RequestSummary::new(&req, client.addr);
+    let route = metrics::route_label(req.uri().path());
+    req.extensions_mut().insert(client);
+    async move {
+        let start = Instant::now();
+        let next = Next {
+            middleware: app.middleware.clone(),
+            index: 0,
+            endpoint: app.endpoint.clone(),
+        };
+        let response = match next.run(req).await {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::debug!("not found: {:#}", e);
+                metrics::record_error(metrics::error_kind(&e));
+                not_found().await
+            }
+        };
+        // logged once the body is sent, so that streamed ones are counted
+        let status = response.status();
+        let len = logging::body_len(&response);
+        let span = tracing::Span::current();
+        Ok(response.map(|body| {
+            LoggedBody::new(body, len, move |sent, complete| {
+                let latency = start.elapsed();
+                // head responses never send their body
+                let complete = complete || summary.method == Method::HEAD;
+                span.in_scope(|| logging::finish(&summary, status, sent, complete, latency));
+                metrics::record_request(
+                    route,
+                    summary.method.as_str(),
+                    status.as_u16(),
+                    Some(sent),
+                    latency,
+                );
+            })
+        }))
+    }
+    .instrument(span)
+    .await
+}
+
+#[tokio::test]
+async fn t_middleware_and_routes() {
+    let hello: Handler = Arc::new(|_| Box::pin(async { Ok(Response::new(Body::from("hello"))) }));
+    let fallback: Handler = Arc::new(|_| Box::pin(async { anyhow::bail!("no such route") }));
+    let endpoint = endpoint(vec![("/hello".into(), hello)], fallback);
+    let tag: Middleware = Arc::new(|req, next| {
+        Box::pin(async move {
+            let mut r = next.run(req).await?;
+            r.headers_mut().insert("x-tag", "1".parse().unwrap());
+            Ok(r)
+        })
+    });
+    let next = |uri: &str| {
+        let next = Next {
+            middleware: Arc::new(vec![tag.clone()]),
+            index: 0,
+            endpoint: endpoint.clone(),
+        };
+        next.run(Request::get(uri).body(Body::empty()).unwrap())
+    };
+    let r = next("/hello/x").await.unwrap();
+    assert_eq!(r.headers()["x-tag"], "1");
+    assert_eq!(hyper::body::to_bytes(r).await.unwrap(), "hello");
+    assert!(next("/hellox").await.is_err());
+}
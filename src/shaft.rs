@@ -1,19 +1,24 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
 use crate::{
-    backend::{BackendTrait, ChapterInfo},
+    backend::{BackendTrait, ChapterInfo, Pic},
     manga_list::{ChapterBasicInfo, MangaInfo, MangaList},
-    utils::ToResult,
+    utils::{modified_secs, ToResult},
 };
 use anyhow::Result;
 #[derive(Debug, Clone, Copy)]
 pub struct Shaft;
 
 lazy_static::lazy_static! {
-    static ref INFO: HashMap<String, MangaInfoLocal> = singel_info();
+    /// Filled by every scan of the library.
+    static ref INFO: RwLock<Arc<HashMap<String, MangaInfoLocal>>> = RwLock::new(Arc::new(HashMap::new()));
+}
+
+fn info() -> Arc<HashMap<String, MangaInfoLocal>> {
+    INFO.read().unwrap().clone()
 }
 
 #[test]
@@ -23,16 +28,19 @@ fn t_impl() {
 #[async_trait::async_trait]
 impl BackendTrait for Shaft {
     fn generate_manga_list() -> MangaList {
-        #[derive(Debug, Default, serde::Deserialize)]
-        struct Config {
-            path: String,
-        }
-        let f: String = std::fs::read_to_string("shaft.toml").unwrap();
-        let config: Config = toml::from_str(&f).unwrap();
-        let infos = read_all_info(&config.path);
-        let out_map: HashMap<String, MangaInfo> = infos
+        let config = crate::config::get().shaft();
+        // the page lookups share this scan
+        let scanned = singel_info();
+        *INFO.write().unwrap() = Arc::new(scanned.clone());
+        let out_map: HashMap<String, MangaInfo> = scanned
             .into_iter()
             .map(|(_k, v)| {
+                let added = v
+                    .full_paths
+                    .iter()
+                    .map(|(_, p)| modified_secs(p))
+                    .max()
+                    .unwrap_or(0);
                 (
                     format!("{}", v.id),
                     MangaInfo {
@@ -43,16 +51,17 @@ impl BackendTrait for Shaft {
                             length: v.all_pages,
                             name: v.name,
                             id: "single".to_string(),
+                            added,
                         }],
+                        added,
                     },
                 )
             })
             .collect();
 
-        // dbg!(infos);
         MangaList {
             list: Arc::new(Mutex::new(out_map)),
-            path: Arc::new(config.path),
+            path: Arc::new(config.path.clone()),
             all_basic_info: Arc::new(Mutex::new(None)),
         }
     }
@@ -61,8 +70,9 @@ impl BackendTrait for Shaft {
         manga_id: &str,
         _chapter: &str,
         pic_id: usize,
-    ) -> Result<Option<Vec<u8>>> {
-        let info = INFO.get(manga_id).to_result()?;
+    ) -> Result<Option<Pic>> {
+        let info = info();
+        let info = info.get(manga_id).to_result()?;
         let path = if info.is_single {
             &info.full_paths.get(0).to_result()?.1
         } else {
@@ -74,16 +84,16 @@ impl BackendTrait for Shaft {
                 .to_result()?
                 .1
         };
-        // dbg!(path);
-        let out = tokio::fs::read(path).await?;
-        Ok(Some(out))
+        tracing::trace!(path, "reading page");
+        Ok(Some(Pic::open(path).await?))
     }
     async fn get_chapter_info(
         _base_path: &str,
         manga_id: &str,
         _chapter: &str,
     ) -> Result<ChapterInfo> {
-        let info = INFO.get(manga_id).to_result()?;
+        let info = info();
+        let info = info.get(manga_id).to_result()?;
         Ok(ChapterInfo {
             length: info.all_pages,
             name: info.name.clone(),
@@ -112,28 +122,25 @@ fn read_all_info(path: &str) -> HashMap<usize, MangaInfoLocal> {
     let all_extend_name = vec!["jpg", "png", "gif"];
     for e in walkdir::WalkDir::new(path) {
         let e = e.unwrap();
+        crate::manga_list::scanned_entry();
         if !e.file_type().is_file() {
             continue;
         }
         let full_name = e.file_name().to_str().unwrap();
         let extend_name = full_name.split('.').last().unwrap();
         if !all_extend_name.contains(&extend_name) {
-            // dbg!(extend_name);
+            tracing::debug!(path = %e.path().display(), "skipping file that is not an image");
             continue;
         }
         let name = full_name[..(full_name.len() - 4)].to_owned();
         if name.ends_with(')') {
-            // dbg!(name);
+            tracing::debug!(path = %e.path().display(), "skipping duplicate image");
             continue;
         }
-        // dbg!(&name);
         let splited = name.split('_').collect::<Vec<_>>();
         if !splited.last().u().starts_with('p') {
-            // dbg!(&name);
             let id: usize = splited[splited.len() - 1].parse().u();
             let pic_name: String = name[..name.len() - (splited.last().u().len() + 1)].to_owned();
-            // dbg!(id);
-            // dbg!(&pic_name);
             match map.get_mut(&id) {
                 Some(v) => {
                     v.all_pages += 1;
@@ -153,7 +160,6 @@ fn read_all_info(path: &str) -> HashMap<usize, MangaInfoLocal> {
                 }
             }
         } else {
-            // dbg!(&name);
             let split_len = splited.len();
             let id: usize = splited[splited.len() - 2].parse().u();
             let pic_name: String = name
@@ -161,9 +167,6 @@ fn read_all_info(path: &str) -> HashMap<usize, MangaInfoLocal> {
                 .to_owned();
 
             let page: usize = splited[split_len - 1][1..].parse().u();
-            // dbg!(page);
-            // dbg!(id);
-            // dbg!(&pic_name);
             match map.get_mut(&id) {
                 Some(v) => {
                     v.all_pages += 1;
@@ -194,12 +197,7 @@ fn read_all_info(path: &str) -> HashMap<usize, MangaInfoLocal> {
 }
 
 fn singel_info() -> HashMap<String, MangaInfoLocal> {
-    #[derive(Debug, Default, serde::Deserialize)]
-    struct Config {
-        path: String,
-    }
-    let f: String = std::fs::read_to_string("shaft.toml").unwrap();
-    let config: Config = toml::from_str(&f).unwrap();
+    let config = crate::config::get().shaft();
     let infos = read_all_info(&config.path);
     infos
         .into_iter()
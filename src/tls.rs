@@ -0,0 +1,254 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use hyper::{header, Body, Request, Response, StatusCode};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::{config::TlsConfig, lifecycle};
+
+/// Hands out the current certificate, swapped by [`watch`] when the files change.
+pub struct CertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+impl CertResolver {
+    pub fn load(cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert, key)?)),
+        })
+    }
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let key = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let m = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+        Some((m(&self.cert)?, m(&self.key)?))
+    }
+}
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = {
+        let f = std::fs::File::open(cert)
+            .with_context(|| format!("can not open certificate `{}`", cert.display()))?;
+        rustls_pemfile::certs(&mut std::io::BufReader::new(f))
+            .with_context(|| format!("can not parse certificate `{}`", cert.display()))?
+    };
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in `{}`", cert.display());
+    }
+    let key_der = {
+        let f = std::fs::File::open(key)
+            .with_context(|| format!("can not open private key `{}`", key.display()))?;
+        let mut r = std::io::BufReader::new(f);
+        loop {
+            match rustls_pemfile::read_one(&mut r)
+                .with_context(|| format!("can not parse private key `{}`", key.display()))?
+            {
+                Some(rustls_pemfile::Item::PKCS8Key(k))
+                | Some(rustls_pemfile::Item::RSAKey(k))
+                | Some(rustls_pemfile::Item::ECKey(k)) => break k,
+                Some(_) => continue,
+                None => anyhow::bail!("no private key found in `{}`", key.display()),
+            }
+        }
+    };
+    let signing_key = rustls::sign::any_supported_type(&rustls::PrivateKey(key_der))
+        .map_err(|_| anyhow::anyhow!("unsupported private key type in `{}`", key.display()))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(rustls::Certificate).collect(),
+        signing_key,
+    ))
+}
+
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<(TlsAcceptor, Arc<CertResolver>)> {
+    let resolver = Arc::new(CertResolver::load(
+        Path::new(&config.cert),
+        Path::new(&config.key),
+    )?);
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok((TlsAcceptor::from(Arc::new(server_config)), resolver))
+}
+
+/// Polls the certificate and key files and reloads them after they change.
+/// A broken file keeps the old certificate in use. Ends at shutdown.
+pub async fn watch(resolver: Arc<CertResolver>, interval: Duration) {
+    let mut last = resolver.modified();
+    let shutdown = lifecycle::shutdown_requested();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = &mut shutdown => return,
+        }
+        let now = resolver.modified();
+        if now.is_none() || now == last {
+            continue;
+        }
+        match resolver.reload() {
+            Ok(()) => {
+                tracing::info!("reloaded certificate `{}`", resolver.cert.display());
+                last = now;
+            }
+            Err(e) => tracing::error!("can not reload certificate: {:#}", e),
+        }
+    }
+}
+
+/// Accepts tcp connections and does the tls handshakes in the background, a
+/// slow or failing handshake does not hold up other connections. The
+/// listener is dropped at shutdown or once the server stops taking
+/// connections.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl hyper::server::accept::Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        let shutdown = lifecycle::shutdown_requested();
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                r = listener.accept() => r,
+                _ = &mut shutdown => return,
+                _ = tx.closed() => return,
+            };
+            let stream = match accepted {
+                Ok((s, _)) => s,
+                Err(e) => {
+                    if tx.send(Err(e)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let handshake =
+                    tokio::time::timeout(Duration::from_secs(10), acceptor.accept(stream)).await;
+                match handshake {
+                    Ok(Ok(s)) => {
+                        let _ = tx.send(Ok(s)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!("tls handshake failed: {}", e),
+                    Err(_) => tracing::debug!("tls handshake timed out"),
+                }
+            });
+        }
+    });
+    hyper::server::accept::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+/// Response of the plain http listener, sends clients to the same path over https.
+pub fn redirect(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host())
+        .map(strip_port)
+        .unwrap_or("localhost");
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // [::1]:8080
+        match host.find(']') {
+            Some(i) => &host[..=i],
+            None => host,
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+#[test]
+fn t_redirect() {
+    let req = Request::builder()
+        .uri("/manga/1?a=b")
+        .header("host", "example.com:8080")
+        .body(Body::empty())
+        .unwrap();
+    let r = redirect(&req, 8443);
+    assert_eq!(r.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        r.headers()[header::LOCATION],
+        "https://example.com:8443/manga/1?a=b"
+    );
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "[::1]:80")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        redirect(&req, 443).headers()[header::LOCATION],
+        "https://[::1]/"
+    );
+}
+
+#[tokio::test]
+async fn t_incoming_ends() {
+    struct NoCert;
+    impl ResolvesServerCert for NoCert {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(NoCert));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accept = incoming(listener, TlsAcceptor::from(Arc::new(config)));
+    assert!(TcpStream::connect(addr).await.is_ok());
+    // the server stopped taking connections, the port is freed
+    drop(accept);
+    let mut refused = false;
+    for _ in 0..50 {
+        if TcpStream::connect(addr).await.is_err() {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(refused);
+}
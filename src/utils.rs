@@ -1,4 +1,34 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, path::Path, time::UNIX_EPOCH};
+
+/// Modification time of a file as unix seconds, 0 if it can not be read.
+pub fn modified_secs(path: impl AsRef<Path>) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Unix seconds as an rfc 3339 date in utc, e.g. `2023-11-14T22:13:20Z`.
+pub fn rfc3339(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Percent-encodes `s` as one url path segment or query value.
+pub fn url_segment(s: &str) -> String {
+    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+}
+
+/// Escapes text for xml content and attribute values.
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
 pub trait ToResult<V, E> {
     fn to_result(self) -> Result<V, E>;
@@ -0,0 +1,408 @@
+//! Read-only WebDAV view of the library under `/dav`, for file managers and
+//! readers with WebDAV sources.
+//!
+//! The tree is `/dav/{library}/{series}/{chapter}.cbz`. Chapters that are
+//! zips on disk are sent as they are, the others as a CBZ generated while it
+//! is sent. Only `PROPFIND` with depth 0 or 1 and `GET` are answered, depth
+//! infinity gets the 403 of RFC 4918.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    Body, Method, Response, StatusCode,
+};
+
+use crate::{
+    backend::{BackendTrait, Pic},
+    cbz::{self, file_name},
+    maintenance,
+    manga_list::{self, MangaInfo},
+    range::Validators,
+    utils::{url_segment, xml_escape},
+};
+
+pub const PREFIX: &str = "/dav";
+pub const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+/// Folder name of every series, names used more than once get the id added.
+fn folders(list: Vec<MangaInfo>) -> Vec<(String, MangaInfo)> {
+    let names = list.iter().map(|m| file_name(&m.name)).collect::<Vec<_>>();
+    let mut uses = HashMap::<&str, usize>::new();
+    for n in &names {
+        *uses.entry(n).or_default() += 1;
+    }
+    list.into_iter()
+        .zip(names.iter())
+        .map(|(m, name)| {
+            let folder = if name.is_empty() || uses[name.as_str()] > 1 {
+                format!("{} [{}]", name, file_name(&m.id))
+            } else {
+                name.clone()
+            };
+            (folder.trim().to_owned(), m)
+        })
+        .collect()
+}
+
+/// The folders of one index generation.
+struct Tree {
+    generation: usize,
+    folders: Vec<(String, MangaInfo)>,
+    by_name: HashMap<String, usize>,
+    /// Length and validators of the chapter files listed so far, by manga
+    /// and chapter id.
+    chapters: Mutex<HashMap<(String, String), (u64, Validators)>>,
+}
+
+lazy_static::lazy_static! {
+    static ref TREE: Mutex<Option<Arc<Tree>>> = Mutex::new(None);
+}
+
+/// The folders of the current index, built again after a rescan.
+fn tree() -> Arc<Tree> {
+    let generation = manga_list::generation();
+    let mut cached = TREE.lock().unwrap();
+    match &*cached {
+        Some(t) if t.generation == generation => t.clone(),
+        _ => {
+            let folders = folders(maintenance::index());
+            let by_name = folders
+                .iter()
+                .enumerate()
+                .map(|(i, (f, _))| (f.clone(), i))
+                .collect();
+            let t = Arc::new(Tree {
+                generation,
+                folders,
+                by_name,
+                chapters: Mutex::new(HashMap::new()),
+            });
+            *cached = Some(t.clone());
+            t
+        }
+    }
+}
+
+fn chapter_file(m: &MangaInfo, index: usize) -> String {
+    format!(
+        "{:03} {}.cbz",
+        index + 1,
+        file_name(&m.chapters[index].name)
+    )
+}
+
+enum Node {
+    Root,
+    Library,
+    /// The index of the series in [`Tree::folders`].
+    Series(usize),
+    Chapter(usize, usize),
+}
+
+fn find(tree: &Tree, path: &[String]) -> Option<Node> {
+    let library = manga_list::library_name();
+    match path {
+        [] => Some(Node::Root),
+        [l] if *l == library => Some(Node::Library),
+        [l, series, rest @ ..] if *l == library && rest.len() <= 1 => {
+            let s = *tree.by_name.get(series)?;
+            let m = &tree.folders[s].1;
+            match rest {
+                [] => Some(Node::Series(s)),
+                [file] => {
+                    let index = (0..m.chapters.len()).find(|i| chapter_file(m, *i) == *file)?;
+                    Some(Node::Chapter(s, index))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn href(path: &[&str], collection: bool) -> String {
+    let mut out = PREFIX.to_owned();
+    for p in path {
+        out.push('/');
+        out.push_str(&url_segment(p));
+    }
+    if collection {
+        out.push('/');
+    }
+    out
+}
+
+/// A file or folder as listed by `PROPFIND`.
+struct Resource {
+    href: String,
+    name: String,
+    /// Length of a file, folders have none.
+    len: Option<u64>,
+    validators: Validators,
+}
+impl Resource {
+    fn folder(path: &[&str], modified: Option<SystemTime>) -> Self {
+        Self {
+            href: href(path, true),
+            name: path.last().copied().unwrap_or("").to_owned(),
+            len: None,
+            validators: Validators {
+                etag: None,
+                last_modified: modified,
+            },
+        }
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str("<D:response>\n");
+        out.push_str(&format!("  <D:href>{}</D:href>\n", xml_escape(&self.href)));
+        out.push_str("  <D:propstat>\n    <D:prop>\n");
+        out.push_str(&format!(
+            "      <D:displayname>{}</D:displayname>\n",
+            xml_escape(&self.name)
+        ));
+        match self.len {
+            Some(len) => {
+                out.push_str("      <D:resourcetype/>\n");
+                out.push_str(&format!(
+                    "      <D:getcontentlength>{}</D:getcontentlength>\n",
+                    len
+                ));
+                out.push_str(&format!(
+                    "      <D:getcontenttype>{}</D:getcontenttype>\n",
+                    cbz::CONTENT_TYPE
+                ));
+            }
+            None => out.push_str("      <D:resourcetype><D:collection/></D:resourcetype>\n"),
+        }
+        if let Some(m) = self.validators.last_modified {
+            out.push_str(&format!(
+                "      <D:getlastmodified>{}</D:getlastmodified>\n",
+                httpdate::fmt_http_date(m)
+            ));
+        }
+        if let Some(e) = &self.validators.etag {
+            out.push_str(&format!("      <D:getetag>{}</D:getetag>\n", xml_escape(e)));
+        }
+        out.push_str("    </D:prop>\n    <D:status>HTTP/1.1 200 OK</D:status>\n  </D:propstat>\n</D:response>\n");
+    }
+}
+
+fn modified(secs: u64) -> Option<SystemTime> {
+    (secs > 0).then(|| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+/// Length and validators of a chapter file, without generating it.
+/// Length and validators of the file of a chapter, the zip on disk or the
+/// planned CBZ. Kept until the next rescan.
+pub(crate) async fn chapter_file_info<B: BackendTrait>(
+    base_path: &str,
+    m: &MangaInfo,
+    index: usize,
+) -> anyhow::Result<(u64, Validators)> {
+    let tree = tree();
+    let key = (m.id.clone(), m.chapters[index].id.clone());
+    if let Some(c) = tree.chapters.lock().unwrap().get(&key) {
+        return Ok(c.clone());
+    }
+    let c = match B::chapter_archive(base_path, &m.id, &m.chapters[index].id) {
+        Some(path) => {
+            let meta = tokio::fs::metadata(&path).await?;
+            (meta.len(), Validators::from_metadata(&meta))
+        }
+        None => {
+            let archive = cbz::plan::<B>(base_path, m, Some(index)).await?;
+            (archive.len, archive.validators)
+        }
+    };
+    tree.chapters.lock().unwrap().insert(key, c.clone());
+    Ok(c)
+}
+
+async fn chapter_resource<B: BackendTrait>(
+    base_path: &str,
+    tree: &Tree,
+    series: usize,
+    index: usize,
+) -> anyhow::Result<Resource> {
+    let library = manga_list::library_name();
+    let (folder, m) = &tree.folders[series];
+    let name = chapter_file(m, index);
+    let (len, validators) = chapter_file_info::<B>(base_path, m, index).await?;
+    Ok(Resource {
+        href: href(&[library, folder, &name], false),
+        name,
+        len: Some(len),
+        validators,
+    })
+}
+
+/// The resource at `node` and with depth 1 its children.
+async fn resources<B: BackendTrait>(
+    tree: &Tree,
+    node: &Node,
+    children: bool,
+) -> anyhow::Result<Vec<Resource>> {
+    let library = manga_list::library_name();
+    let base_path = (*manga_list::get_list_ref().path).to_owned();
+    let mut out = Vec::new();
+    match *node {
+        Node::Root => {
+            out.push(Resource::folder(&[], None));
+            if children {
+                out.push(Resource::folder(&[library], None));
+            }
+        }
+        Node::Library => {
+            out.push(Resource::folder(&[library], None));
+            if children {
+                for (folder, m) in &tree.folders {
+                    out.push(Resource::folder(&[library, folder], modified(m.added)));
+                }
+            }
+        }
+        Node::Series(s) => {
+            let (folder, m) = &tree.folders[s];
+            out.push(Resource::folder(&[library, folder], modified(m.added)));
+            if children {
+                for i in 0..m.chapters.len() {
+                    out.push(chapter_resource::<B>(&base_path, tree, s, i).await?);
+                }
+            }
+        }
+        Node::Chapter(s, i) => out.push(chapter_resource::<B>(&base_path, tree, s, i).await?),
+    }
+    Ok(out)
+}
+
+fn multistatus(resources: &[Resource]) -> Response<Body> {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for r in resources {
+        r.render(&mut body);
+    }
+    body.push_str("</D:multistatus>\n");
+    let mut r = Response::new(Body::from(body));
+    *r.status_mut() = StatusCode::MULTI_STATUS;
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    r
+}
+
+/// A plain page linking the children of a folder, for browsers.
+fn listing(resources: &[Resource]) -> Response<Body> {
+    let mut body = String::from("<!DOCTYPE html>\n<html><body><ul>\n");
+    for r in resources.iter().skip(1) {
+        body.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            xml_escape(&r.href),
+            xml_escape(&r.name)
+        ));
+    }
+    body.push_str("</ul></body></html>\n");
+    let mut r = Response::new(Body::from(body));
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    r
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut r = Response::new(Body::empty());
+    *r.status_mut() = code;
+    r
+}
+
+pub async fn handle<B: BackendTrait>(
+    path: &[String],
+    method: &Method,
+    headers: &HeaderMap,
+) -> anyhow::Result<Response<Body>> {
+    if method == Method::OPTIONS {
+        let mut r = status(StatusCode::OK);
+        let h = r.headers_mut();
+        h.insert("dav", HeaderValue::from_static("1"));
+        h.insert(header::ALLOW, HeaderValue::from_static(ALLOW));
+        return Ok(r);
+    }
+    let tree = tree();
+    let node = match find(&tree, path) {
+        Some(n) => n,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    if method.as_str() == "PROPFIND" {
+        let children = match headers.get("depth").and_then(|v| v.to_str().ok()) {
+            Some("0") => false,
+            // infinite depth would plan every chapter at once
+            Some(d) if d.eq_ignore_ascii_case("infinity") => return Ok(finite_depth()),
+            _ => true,
+        };
+        return Ok(multistatus(&resources::<B>(&tree, &node, children).await?));
+    }
+    match node {
+        Node::Chapter(s, i) => {
+            let base_path = (*manga_list::get_list_ref().path).to_owned();
+            let m = &tree.folders[s].1;
+            let c = &m.chapters[i];
+            match B::chapter_archive(&base_path, &m.id, &c.id) {
+                Some(path) => {
+                    let mut pic = Pic::open(path).await?;
+                    pic.content_type = Some(cbz::CONTENT_TYPE);
+                    Ok(pic.respond(headers).await?)
+                }
+                None => {
+                    let archive = cbz::plan::<B>(&base_path, m, Some(i)).await?;
+                    Ok(cbz::respond::<B>(headers, archive).await?)
+                }
+            }
+        }
+        node => Ok(listing(&resources::<B>(&tree, &node, true).await?)),
+    }
+}
+
+/// The 403 of RFC 4918 for `Depth: infinity`.
+fn finite_depth() -> Response<Body> {
+    let mut r = Response::new(Body::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n",
+    ));
+    *r.status_mut() = StatusCode::FORBIDDEN;
+    r.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    r
+}
+
+#[test]
+fn t_names() {
+    let m = |id: &str, name: &str| crate::manga_list::test_manga(id, name, &[("ch: 1", 0)]);
+    let f = folders(vec![m("1", "A/B"), m("2", "C"), m("3", "C"), m("4", "")]);
+    let names = f.iter().map(|(f, _)| f.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["A_B", "C [2]", "C [3]", "[4]"]);
+    assert_eq!(chapter_file(&f[0].1, 0), "001 ch_ 1.cbz");
+    assert_eq!(href(&["dmzj", "C [2]"], true), "/dav/dmzj/C%20%5B2%5D/");
+
+    let mut out = String::new();
+    Resource {
+        href: "/dav/a.cbz".into(),
+        name: "a & b.cbz".into(),
+        len: Some(3),
+        validators: Validators {
+            etag: Some("\"x\"".into()),
+            last_modified: modified(1_700_000_000),
+        },
+    }
+    .render(&mut out);
+    assert!(out.contains("<D:displayname>a &amp; b.cbz</D:displayname>"));
+    assert!(out.contains("<D:getcontentlength>3</D:getcontentlength>"));
+    assert!(out.contains("<D:getlastmodified>Tue, 14 Nov 2023 22:13:20 GMT</D:getlastmodified>"));
+    assert!(out.contains("<D:getetag>&quot;x&quot;</D:getetag>"));
+}
@@ -0,0 +1,369 @@
+//! Webhooks configured with `[[webhooks]]`, events of the bus posted as json
+//! to other services, e.g. to announce new chapters in a chat.
+//!
+//! Every hook has its own queue and sends in order. Network errors, timeouts,
+//! 408, 429 and 5xx are retried with doubling waits, other statuses are not.
+//! Every attempt goes to `webhook_log` when it is set.
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use hyper::{
+    body::Bytes, client::HttpConnector, header::HeaderValue, Body, Client, Method, Request,
+    StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use sha2::Sha256;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinSet,
+};
+
+use crate::{
+    config::WebhookConfig,
+    events::{self, Event},
+    utils::rfc3339,
+};
+
+/// Sent when a hook lists no events, `scan_finished` would come with every
+/// periodic rescan.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "manga_added",
+    "manga_removed",
+    "chapters_added",
+    "scan_failed",
+];
+const ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every further one.
+const BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries waiting per hook, more are dropped.
+const QUEUE: usize = 256;
+
+pub const EVENT_HEADER: &str = "x-manga-server-event";
+pub const DELIVERY_HEADER: &str = "x-manga-server-delivery";
+pub const SIGNATURE_HEADER: &str = "x-manga-server-signature";
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+static DELIVERY_LOG: OnceLock<Mutex<LineWriter<File>>> = OnceLock::new();
+
+/// One event to post, the body is the same for every attempt.
+#[derive(Debug, Clone)]
+struct Delivery {
+    id: String,
+    kind: &'static str,
+    body: Bytes,
+}
+impl Delivery {
+    fn new(id: String, event: &Event) -> Self {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let body = serde_json::json!({
+            "id": id,
+            "time": rfc3339(time),
+            "event": event,
+        });
+        Self {
+            id,
+            kind: event.kind(),
+            body: Bytes::from(body.to_string()),
+        }
+    }
+}
+
+fn wants(hook: &WebhookConfig, event: &Event) -> bool {
+    let kind = event.kind();
+    if hook.events.is_empty() {
+        DEFAULT_EVENTS.contains(&kind)
+    } else {
+        hook.events.iter().any(|e| e == kind)
+    }
+}
+
+/// `sha256=` and the hex hmac of `body` keyed with `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(body);
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+fn client() -> HttpClient {
+    Client::builder().build(
+        HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+    )
+}
+
+/// Starts posting events to `hooks` from tasks in `tasks`, call it before
+/// the first scan so its `scan_finished` is sent too.
+pub fn start(
+    hooks: &[WebhookConfig],
+    log: Option<&str>,
+    tasks: &mut JoinSet<()>,
+) -> anyhow::Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    if let Some(path) = log {
+        let f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("can not open webhook log `{}`", path))?;
+        let _ = DELIVERY_LOG.set(Mutex::new(LineWriter::new(f)));
+        crate::lifecycle::on_shutdown("flush webhook log", || {
+            if let Some(f) = DELIVERY_LOG.get() {
+                f.lock().unwrap().flush()?;
+            }
+            Ok(())
+        });
+    }
+    let client = client();
+    let queues = hooks
+        .iter()
+        .map(|hook| {
+            let (tx, rx) = mpsc::channel(QUEUE);
+            tasks.spawn(worker(client.clone(), hook.clone(), rx, BACKOFF));
+            (hook.clone(), tx)
+        })
+        .collect::<Vec<_>>();
+    // event ids start at 1 in every process, the start time keeps deliveries apart
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut events = events::subscribe();
+    tasks.spawn(async move {
+        loop {
+            let (id, event) = match events.recv().await {
+                Ok(e) => e,
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("webhooks missed {} events", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let delivery = Delivery::new(format!("{:x}-{}", started, id), &event);
+            for (hook, tx) in &queues {
+                if wants(hook, &event) && tx.try_send(delivery.clone()).is_err() {
+                    tracing::warn!(
+                        "webhook queue of {} is full, dropping {} {}",
+                        hook.url,
+                        delivery.kind,
+                        delivery.id
+                    );
+                }
+            }
+        }
+    });
+    tracing::info!("sending events to {} webhooks", hooks.len());
+    Ok(())
+}
+
+async fn send(
+    client: &HttpClient,
+    hook: &WebhookConfig,
+    d: &Delivery,
+) -> anyhow::Result<StatusCode> {
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(&hook.url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(
+            hyper::header::USER_AGENT,
+            concat!("manga-server/", env!("CARGO_PKG_VERSION")),
+        )
+        .header(EVENT_HEADER, d.kind)
+        .header(DELIVERY_HEADER, &d.id)
+        .body(Body::from(d.body.clone()))?;
+    if let Some(secret) = &hook.secret {
+        req.headers_mut().insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_str(&sign(secret, &d.body))?,
+        );
+    }
+    let response = tokio::time::timeout(TIMEOUT, client.request(req))
+        .await
+        .context("timed out")??;
+    Ok(response.status())
+}
+
+fn retry(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn log_attempt(
+    hook: &WebhookConfig,
+    d: &Delivery,
+    attempt: u32,
+    result: &anyhow::Result<StatusCode>,
+    took: Duration,
+) {
+    let (status, error) = match result {
+        Ok(s) => (Some(s.as_u16()), None),
+        // hyper errors already show their cause
+        Err(e) => (None, Some(e.to_string())),
+    };
+    match result {
+        Ok(s) if s.is_success() => tracing::debug!("webhook {} {}: {}", hook.url, d.kind, s),
+        _ => tracing::warn!(
+            "webhook {} {} attempt {}: {}",
+            hook.url,
+            d.kind,
+            attempt,
+            error
+                .clone()
+                .unwrap_or_else(|| status.unwrap_or(0).to_string())
+        ),
+    }
+    if let Some(f) = DELIVERY_LOG.get() {
+        let line = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "url": hook.url,
+            "delivery": d.id,
+            "event": d.kind,
+            "attempt": attempt,
+            "status": status,
+            "error": error,
+            "ms": took.as_millis(),
+        });
+        if let Err(e) = writeln!(f.lock().unwrap(), "{}", line) {
+            tracing::error!("can not write webhook log: {}", e);
+        }
+    }
+}
+
+/// Sends the deliveries of one hook until the queue is closed.
+async fn worker(
+    client: HttpClient,
+    hook: WebhookConfig,
+    mut queue: mpsc::Receiver<Delivery>,
+    backoff: Duration,
+) {
+    while let Some(d) = queue.recv().await {
+        let mut wait = backoff;
+        for attempt in 1..=ATTEMPTS {
+            let start = Instant::now();
+            let result = send(&client, &hook, &d).await;
+            log_attempt(&hook, &d, attempt, &result, start.elapsed());
+            match result {
+                Ok(s) if !retry(s) => break,
+                _ if attempt == ATTEMPTS => {
+                    tracing::error!("webhook {} {} {} failed, giving up", hook.url, d.kind, d.id)
+                }
+                _ => {
+                    tokio::time::sleep(wait).await;
+                    wait *= 2;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn t_sign() {
+    // the example of RFC 4231, test case 2
+    assert_eq!(
+        sign("Jefe", b"what do ya want for nothing?"),
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    let hook = WebhookConfig {
+        url: "http://localhost/".into(),
+        events: vec![],
+        secret: None,
+    };
+    assert!(wants(&hook, &Event::ScanFailed { error: "x".into() }));
+    assert!(wants(&hook, &Event::MangaRemoved { manga: "1".into() }));
+    let progress = Event::Progress {
+        user: "a".into(),
+        manga: "1".into(),
+        chapter: "1".into(),
+        page: None,
+        completed: false,
+    };
+    assert!(!wants(&hook, &progress));
+    assert!(!wants(
+        &WebhookConfig {
+            events: vec!["scan_failed".into()],
+            ..hook
+        },
+        &Event::MangaRemoved { manga: "1".into() }
+    ));
+}
+
+#[tokio::test]
+async fn t_retry() {
+    use hyper::service::{make_service_fn, service_fn};
+    use std::sync::Arc;
+
+    // a local stand-in for the receiving service, it fails the first attempt
+    let seen = Arc::new(Mutex::new(Vec::<(hyper::HeaderMap, Bytes)>::new()));
+    let s = seen.clone();
+    let make_svc = make_service_fn(move |_| {
+        let s = s.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let s = s.clone();
+                async move {
+                    let headers = req.headers().clone();
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    let mut seen = s.lock().unwrap();
+                    seen.push((headers, body));
+                    let mut r = hyper::Response::new(Body::empty());
+                    if seen.len() == 1 {
+                        *r.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    Ok::<_, hyper::Error>(r)
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let url = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+
+    let hook = WebhookConfig {
+        url,
+        events: vec![],
+        secret: Some("s3cret".into()),
+    };
+    let (tx, rx) = mpsc::channel(1);
+    let d = Delivery::new(
+        "1".into(),
+        &Event::ChaptersAdded {
+            manga: "7".into(),
+            chapters: vec!["101".into()],
+        },
+    );
+    tx.send(d.clone()).await.unwrap();
+    drop(tx);
+    worker(client(), hook, rx, Duration::from_millis(10)).await;
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    let (headers, body) = &seen[1];
+    assert_eq!(*body, d.body);
+    assert_eq!(headers[EVENT_HEADER], "chapters_added");
+    assert_eq!(headers[DELIVERY_HEADER], "1");
+    assert_eq!(headers[SIGNATURE_HEADER], sign("s3cret", body).as_str());
+    let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+    assert_eq!(json["event"]["chapters"][0], "101");
+}
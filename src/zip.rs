@@ -0,0 +1,679 @@
+//! Just enough of the zip format to list an archive and stream single
+//! entries out of it without holding the archive open in a reader, and to
+//! write one without compression. The reader is fuzzed by the
+//! `zip_entries` target under `fuzz/`.
+use std::{
+    io::{Error, ErrorKind, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader, ReadBuf},
+};
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EOCD_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const CENTRAL_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_SIGNATURE: u32 = 0x04034b50;
+const DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+/// End of central directory record without the comment.
+pub const EOCD_LEN: usize = 22;
+/// Zip64 end of central directory record without extensible data.
+pub const ZIP64_EOCD_LEN: usize = 56;
+pub const ZIP64_LOCATOR_LEN: usize = 20;
+
+pub const STORED: u16 = 0;
+pub const DEFLATED: u16 = 8;
+/// Crc and sizes follow the data in a data descriptor.
+pub const FLAG_DESCRIPTOR: u16 = 1 << 3;
+/// The name is utf-8.
+pub const FLAG_UTF8: u16 = 1 << 11;
+
+pub const LOCAL_HEADER_LEN: u64 = 30;
+pub const CENTRAL_HEADER_LEN: u64 = 46;
+pub const DESCRIPTOR_LEN: u64 = 16;
+
+/// One entry of the central directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: u16,
+    /// Modification time and date in ms-dos format.
+    pub dos_time: u16,
+    pub dos_date: u16,
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub header_offset: u64,
+}
+
+impl ZipEntry {
+    /// Local file header, crc and sizes are left out with [`FLAG_DESCRIPTOR`].
+    pub fn local_header(&self, flags: u16) -> Vec<u8> {
+        let mut h = Vec::with_capacity(LOCAL_HEADER_LEN as usize + self.name.len());
+        h.extend(LOCAL_SIGNATURE.to_le_bytes());
+        h.extend(20u16.to_le_bytes());
+        self.common_fields(flags, &mut h);
+        h.extend(0u16.to_le_bytes());
+        h.extend(self.name.as_bytes());
+        h
+    }
+
+    /// Central directory header, sizes and an offset that do not fit in 32
+    /// bits go into a zip64 extra field.
+    pub fn central_header(&self, flags: u16) -> Vec<u8> {
+        let extra = self.zip64_fields();
+        let version: u16 = if extra.is_empty() { 20 } else { 45 };
+        let mut h = Vec::with_capacity(self.central_header_len() as usize);
+        h.extend(CENTRAL_SIGNATURE.to_le_bytes());
+        h.extend(version.to_le_bytes());
+        h.extend(version.to_le_bytes());
+        self.common_fields(flags & !FLAG_DESCRIPTOR, &mut h);
+        let extra_len = if extra.is_empty() { 0 } else { 4 + extra.len() };
+        h.extend((extra_len as u16).to_le_bytes());
+        // comment, disk, internal and external attributes
+        h.extend([0; 10]);
+        h.extend(saturated(self.header_offset).to_le_bytes());
+        h.extend(self.name.as_bytes());
+        if !extra.is_empty() {
+            h.extend(1u16.to_le_bytes());
+            h.extend((extra.len() as u16).to_le_bytes());
+            h.extend(extra);
+        }
+        h
+    }
+
+    pub fn central_header_len(&self) -> u64 {
+        let extra = self.zip64_fields().len() as u64;
+        CENTRAL_HEADER_LEN + self.name.len() as u64 + if extra == 0 { 0 } else { 4 + extra }
+    }
+
+    /// The fields of the zip64 extra field, in the order of the format.
+    fn zip64_fields(&self) -> Vec<u8> {
+        [
+            self.uncompressed_size,
+            self.compressed_size,
+            self.header_offset,
+        ]
+        .into_iter()
+        .filter(|v| *v >= u32::MAX as u64)
+        .flat_map(u64::to_le_bytes)
+        .collect()
+    }
+
+    fn common_fields(&self, flags: u16, h: &mut Vec<u8>) {
+        h.extend(flags.to_le_bytes());
+        h.extend(self.method.to_le_bytes());
+        h.extend(self.dos_time.to_le_bytes());
+        h.extend(self.dos_date.to_le_bytes());
+        if flags & FLAG_DESCRIPTOR != 0 {
+            h.extend([0; 12]);
+        } else {
+            h.extend(self.crc32.to_le_bytes());
+            h.extend(saturated(self.compressed_size).to_le_bytes());
+            h.extend(saturated(self.uncompressed_size).to_le_bytes());
+        }
+        h.extend((self.name.len() as u16).to_le_bytes());
+    }
+
+    /// Data descriptor written after the data with [`FLAG_DESCRIPTOR`].
+    pub fn data_descriptor(&self) -> Vec<u8> {
+        let mut h = Vec::with_capacity(DESCRIPTOR_LEN as usize);
+        h.extend(DESCRIPTOR_SIGNATURE.to_le_bytes());
+        h.extend(self.crc32.to_le_bytes());
+        h.extend(saturated(self.compressed_size).to_le_bytes());
+        h.extend(saturated(self.uncompressed_size).to_le_bytes());
+        h
+    }
+}
+
+/// `v`, or all ones when it takes more than 32 bits.
+fn saturated(v: u64) -> u32 {
+    v.min(u32::MAX as u64) as u32
+}
+
+/// The records closing an archive of `count` entries, with the zip64 ones
+/// when the counts or offsets need them.
+pub fn end_records(count: u64, cd_size: u64, cd_offset: u64) -> Vec<u8> {
+    if count < u16::MAX as u64 && cd_size < u32::MAX as u64 && cd_offset < u32::MAX as u64 {
+        return end_of_central_directory(count as u16, cd_size as u32, cd_offset as u32);
+    }
+    let mut h = zip64_end_of_central_directory(count, cd_size, cd_offset);
+    h.extend(end_of_central_directory(u16::MAX, u32::MAX, u32::MAX));
+    h
+}
+
+/// End of central directory record for `count` entries.
+pub fn end_of_central_directory(count: u16, cd_size: u32, cd_offset: u32) -> Vec<u8> {
+    let mut h = Vec::with_capacity(EOCD_LEN);
+    h.extend(EOCD_SIGNATURE.to_le_bytes());
+    h.extend([0; 4]);
+    h.extend(count.to_le_bytes());
+    h.extend(count.to_le_bytes());
+    h.extend(cd_size.to_le_bytes());
+    h.extend(cd_offset.to_le_bytes());
+    h.extend(0u16.to_le_bytes());
+    h
+}
+
+/// Zip64 end of central directory record and its locator, they go right
+/// before the plain record, which then has its fields saturated.
+pub fn zip64_end_of_central_directory(count: u64, cd_size: u64, cd_offset: u64) -> Vec<u8> {
+    let mut h = Vec::with_capacity(ZIP64_EOCD_LEN + ZIP64_LOCATOR_LEN);
+    h.extend(ZIP64_EOCD_SIGNATURE.to_le_bytes());
+    h.extend((ZIP64_EOCD_LEN as u64 - 12).to_le_bytes());
+    // made by and needed version 4.5, disk numbers
+    h.extend(45u16.to_le_bytes());
+    h.extend(45u16.to_le_bytes());
+    h.extend([0; 8]);
+    h.extend(count.to_le_bytes());
+    h.extend(count.to_le_bytes());
+    h.extend(cd_size.to_le_bytes());
+    h.extend(cd_offset.to_le_bytes());
+    // the record starts where the directory ends
+    h.extend(ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+    h.extend([0; 4]);
+    h.extend((cd_offset + cd_size).to_le_bytes());
+    h.extend(1u32.to_le_bytes());
+    h
+}
+
+/// Ms-dos time and date of `t` in utc, which can not go before 1980.
+pub fn dos_date_time(t: std::time::SystemTime) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+    let t = chrono::DateTime::<chrono::Utc>::from(t);
+    if t.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (t.hour() << 11) | (t.minute() << 5) | (t.second() / 2);
+    let date = (((t.year() - 1980) as u32) << 9) | (t.month() << 5) | t.day();
+    (time as u16, date as u16)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+fn u64_at(b: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(b[i..i + 8].try_into().unwrap())
+}
+
+/// Reads the central directory of the zip at `path`, entries in archive order.
+pub async fn read_entries(path: &str) -> std::io::Result<Vec<ZipEntry>> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+    read_entries_from(&mut file, len).await
+}
+
+/// [`read_entries`] of a zip of `len` bytes in `file`, also the entry point
+/// of the `zip_entries` fuzz target.
+pub async fn read_entries_from<R: AsyncRead + AsyncSeek + Unpin>(
+    file: &mut R,
+    len: u64,
+) -> std::io::Result<Vec<ZipEntry>> {
+    // the record sits at the end, followed by a comment of up to 64k
+    let tail_len = len.min((EOCD_LEN + u16::MAX as usize) as u64);
+    file.seek(SeekFrom::Start(len - tail_len)).await?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail).await?;
+    if tail.len() < EOCD_LEN {
+        return Err(invalid("too short for a zip"));
+    }
+    let eocd_at = (0..=tail.len() - EOCD_LEN)
+        .rev()
+        .find(|&i| u32_at(&tail, i) == EOCD_SIGNATURE)
+        .ok_or_else(|| invalid("end of central directory not found"))?;
+    let eocd = &tail[eocd_at..];
+    let mut count = u16_at(eocd, 10) as u64;
+    let mut cd_size = u32_at(eocd, 12) as u64;
+    let mut cd_offset = u32_at(eocd, 16) as u64;
+    if count == u16::MAX as u64 || cd_size == u32::MAX as u64 || cd_offset == u32::MAX as u64 {
+        // the locator sits right before the plain record
+        let locator = eocd_at
+            .checked_sub(ZIP64_LOCATOR_LEN)
+            .map(|i| &tail[i..eocd_at])
+            .filter(|l| u32_at(l, 0) == ZIP64_LOCATOR_SIGNATURE)
+            .ok_or_else(|| invalid("zip64 end of central directory locator not found"))?;
+        let record_at = u64_at(locator, 8);
+        if record_at
+            .checked_add(ZIP64_EOCD_LEN as u64)
+            .is_none_or(|end| end > len)
+        {
+            return Err(invalid("zip64 end of central directory out of bounds"));
+        }
+        file.seek(SeekFrom::Start(record_at)).await?;
+        let mut record = [0; ZIP64_EOCD_LEN];
+        file.read_exact(&mut record).await?;
+        if u32_at(&record, 0) != ZIP64_EOCD_SIGNATURE {
+            return Err(invalid("broken zip64 end of central directory"));
+        }
+        count = u64_at(&record, 32);
+        cd_size = u64_at(&record, 40);
+        cd_offset = u64_at(&record, 48);
+    }
+    if cd_offset.checked_add(cd_size).is_none_or(|end| end > len) {
+        return Err(invalid("central directory out of bounds"));
+    }
+    // every entry takes at least a header, a bogus count can not reserve much
+    if count > cd_size / CENTRAL_HEADER_LEN {
+        return Err(invalid("broken central directory"));
+    }
+
+    file.seek(SeekFrom::Start(cd_offset)).await?;
+    let mut cd = vec![0; cd_size as usize];
+    file.read_exact(&mut cd).await?;
+    let entries = parse_central_directory(&cd, count as usize)?;
+    // the data of every entry comes before the directory
+    if entries.iter().any(|e| {
+        e.header_offset
+            .checked_add(LOCAL_HEADER_LEN)
+            .and_then(|o| o.checked_add(e.compressed_size))
+            .is_none_or(|end| end > cd_offset)
+    }) {
+        return Err(invalid("entry out of bounds"));
+    }
+    Ok(entries)
+}
+
+fn parse_central_directory(cd: &[u8], count: usize) -> std::io::Result<Vec<ZipEntry>> {
+    let mut entries = Vec::with_capacity(count);
+    let mut i = 0;
+    for _ in 0..count {
+        if cd.len() < i + 46 || u32_at(cd, i) != CENTRAL_SIGNATURE {
+            return Err(invalid("broken central directory entry"));
+        }
+        let name_len = u16_at(cd, i + 28) as usize;
+        let extra_len = u16_at(cd, i + 30) as usize;
+        let comment_len = u16_at(cd, i + 32) as usize;
+        let end = i + 46 + name_len + extra_len + comment_len;
+        if cd.len() < end {
+            return Err(invalid("broken central directory entry"));
+        }
+        let name = String::from_utf8_lossy(&cd[i + 46..i + 46 + name_len]).into_owned();
+        let mut entry = ZipEntry {
+            name,
+            method: u16_at(cd, i + 10),
+            dos_time: u16_at(cd, i + 12),
+            dos_date: u16_at(cd, i + 14),
+            crc32: u32_at(cd, i + 16),
+            compressed_size: u32_at(cd, i + 20) as u64,
+            uncompressed_size: u32_at(cd, i + 24) as u64,
+            header_offset: u32_at(cd, i + 42) as u64,
+        };
+        zip64_extra(
+            &cd[i + 46 + name_len..i + 46 + name_len + extra_len],
+            &mut entry,
+        )?;
+        entries.push(entry);
+        i = end;
+    }
+    Ok(entries)
+}
+
+/// Replaces the saturated 32 bit fields with the values from the zip64
+/// extra field, they appear in a fixed order and only when saturated.
+fn zip64_extra(mut extra: &[u8], e: &mut ZipEntry) -> std::io::Result<()> {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let size = u16_at(extra, 2) as usize;
+        if extra.len() < 4 + size {
+            return Err(invalid("broken extra field"));
+        }
+        if id == 0x0001 {
+            let mut data = &extra[4..4 + size];
+            for field in [
+                &mut e.uncompressed_size,
+                &mut e.compressed_size,
+                &mut e.header_offset,
+            ] {
+                if *field == u32::MAX as u64 {
+                    if data.len() < 8 {
+                        return Err(invalid("broken zip64 extra field"));
+                    }
+                    *field = u64_at(data, 0);
+                    data = &data[8..];
+                }
+            }
+        }
+        extra = &extra[4 + size..];
+    }
+    Ok(())
+}
+
+/// Opens the raw, still compressed data of an entry.
+pub async fn open_raw(path: &str, e: &ZipEntry) -> std::io::Result<tokio::io::Take<File>> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(e.header_offset)).await?;
+    let mut header = [0; 30];
+    file.read_exact(&mut header).await?;
+    if u32_at(&header, 0) != LOCAL_SIGNATURE {
+        return Err(invalid("broken local file header"));
+    }
+    let skip = u16_at(&header, 26) as i64 + u16_at(&header, 28) as i64;
+    file.seek(SeekFrom::Current(skip)).await?;
+    Ok(file.take(e.compressed_size))
+}
+
+/// Opens an entry for reading its decompressed content, the crc and size are
+/// checked once the end is reached.
+pub async fn open_entry(
+    path: &str,
+    e: &ZipEntry,
+) -> std::io::Result<Pin<Box<dyn AsyncRead + Send + Sync>>> {
+    let raw = open_raw(path, e).await?;
+    let reader: Pin<Box<dyn AsyncRead + Send + Sync>> = match e.method {
+        STORED => Box::pin(raw),
+        DEFLATED => Box::pin(async_compression::tokio::bufread::DeflateDecoder::new(
+            BufReader::new(raw),
+        )),
+        m => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported compression method {}", m),
+            ))
+        }
+    };
+    Ok(Box::pin(Checked {
+        inner: reader,
+        hasher: crc32fast::Hasher::new(),
+        read: 0,
+        crc32: e.crc32,
+        len: e.uncompressed_size,
+    }))
+}
+
+/// Passes data through and fails at the end if it does not match the
+/// expected crc and length.
+struct Checked<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    read: u64,
+    crc32: u32,
+    len: u64,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for Checked<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        let new = &buf.filled()[before..];
+        if new.is_empty() && buf.remaining() > 0 {
+            let crc = self.hasher.clone().finalize();
+            if self.read != self.len || crc != self.crc32 {
+                return Poll::Ready(Err(invalid("zip entry does not match its crc")));
+            }
+        } else {
+            self.read += new.len() as u64;
+            self.hasher.update(new);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn t_read_entries() {
+    // a stored entry and a deflated one
+    let dir = std::env::temp_dir().join(format!("manga-server-zip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("t.zip");
+    let data = b"hello hello hello hello";
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let deflated = rt.block_on(async {
+        use tokio::io::AsyncWriteExt;
+        let mut e = async_compression::tokio::write::DeflateEncoder::new(Vec::new());
+        e.write_all(data).await.unwrap();
+        e.shutdown().await.unwrap();
+        e.into_inner()
+    });
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, method, body) in [
+        ("0.jpg", STORED, &data[..]),
+        ("1.jpg", DEFLATED, &deflated[..]),
+    ] {
+        let e = ZipEntry {
+            name: name.to_owned(),
+            method,
+            dos_time: 0,
+            dos_date: 0,
+            crc32: crc32fast::hash(data),
+            compressed_size: body.len() as u64,
+            uncompressed_size: data.len() as u64,
+            header_offset: out.len() as u64,
+        };
+        // the stored entry uses a data descriptor
+        let flags = if method == STORED { FLAG_DESCRIPTOR } else { 0 };
+        out.extend(e.local_header(flags));
+        out.extend(body);
+        if flags != 0 {
+            out.extend(e.data_descriptor());
+        }
+        central.extend(e.central_header(flags));
+    }
+    let cd_offset = out.len() as u32;
+    out.extend(&central);
+    out.extend(end_of_central_directory(2, central.len() as u32, cd_offset));
+    std::fs::write(&path, &out).unwrap();
+
+    let path = path.to_str().unwrap();
+    rt.block_on(async {
+        let entries = read_entries(path).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].name, "1.jpg");
+        for e in &entries {
+            let mut read = Vec::new();
+            open_entry(path, e)
+                .await
+                .unwrap()
+                .read_to_end(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read, data);
+        }
+        // a wrong crc is noticed at the end
+        let mut bad = entries[0].clone();
+        bad.crc32 ^= 1;
+        let mut read = Vec::new();
+        let r = open_entry(path, &bad)
+            .await
+            .unwrap()
+            .read_to_end(&mut read)
+            .await;
+        assert!(r.is_err());
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Stored entries named `names` holding `data`, and their central directory.
+#[cfg(test)]
+fn test_zip(names: &[&str], data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for name in names {
+        let e = ZipEntry {
+            name: name.to_string(),
+            method: STORED,
+            dos_time: 0,
+            dos_date: 0,
+            crc32: crc32fast::hash(data),
+            compressed_size: data.len() as u64,
+            uncompressed_size: data.len() as u64,
+            header_offset: out.len() as u64,
+        };
+        out.extend(e.local_header(0));
+        out.extend(data);
+        central.extend(e.central_header(0));
+    }
+    (out, central)
+}
+
+#[test]
+fn t_bad_input() {
+    let dir = std::env::temp_dir().join(format!("manga-server-bad-zip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let read = |name: &str, bytes: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        rt.block_on(read_entries(path.to_str().unwrap()))
+    };
+    let (data, central) = test_zip(&["0.jpg", "1.jpg"], b"page");
+    let cd_offset = data.len() as u32;
+    let eocd = end_of_central_directory(2, central.len() as u32, cd_offset);
+    let good = [&data[..], &central, &eocd].concat();
+    assert_eq!(read("good.zip", &good).unwrap().len(), 2);
+
+    // cut into the end of central directory record, or nothing but a stub
+    assert!(read("truncated.zip", &good[..good.len() - 5]).is_err());
+    assert!(read("short.zip", b"PK\x05").is_err());
+
+    // a trailing comment
+    let mut commented = good.clone();
+    let comment = b"scanned by someone";
+    let at = commented.len() - 2;
+    commented[at..].copy_from_slice(&(comment.len() as u16).to_le_bytes());
+    commented.extend(comment);
+    assert_eq!(read("comment.zip", &commented).unwrap()[1].name, "1.jpg");
+
+    // a zip64 directory with the plain record saturated
+    let zip64 = [
+        &data[..],
+        &central,
+        &zip64_end_of_central_directory(2, central.len() as u64, cd_offset as u64),
+        &end_of_central_directory(u16::MAX, u32::MAX, u32::MAX),
+    ]
+    .concat();
+    let entries = read("zip64.zip", &zip64).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].header_offset, (data.len() / 2) as u64);
+    // without the locator
+    let no_locator = [
+        &data[..],
+        &central,
+        &end_of_central_directory(u16::MAX, u32::MAX, u32::MAX),
+    ]
+    .concat();
+    assert!(read("no_locator.zip", &no_locator).is_err());
+    // a locator pointing past the end
+    let mut far = zip64.clone();
+    let at = far.len() - EOCD_LEN - ZIP64_LOCATOR_LEN + 8;
+    far[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(read("far_zip64.zip", &far).is_err());
+
+    // a directory past the end of the file
+    let far = [
+        &data[..],
+        &central,
+        &end_of_central_directory(2, central.len() as u32, cd_offset + 100),
+    ]
+    .concat();
+    assert!(read("far_directory.zip", &far).is_err());
+    // more entries than the directory has room for
+    let many = [
+        &data[..],
+        &central,
+        &end_of_central_directory(60000, central.len() as u32, cd_offset),
+    ]
+    .concat();
+    assert!(read("many.zip", &many).is_err());
+    // an entry whose data would overlap the directory
+    let mut overlapping = good.clone();
+    let at = data.len() + central.len() / 2 + 42;
+    overlapping[at..at + 4].copy_from_slice(&(cd_offset - 10).to_le_bytes());
+    assert!(read("overlapping.zip", &overlapping).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn t_mutated() {
+    // a cheap stand in for the fuzz target: damaged archives must fail or
+    // list entries inside the file, never panic
+    let (data, central) = test_zip(&["0.jpg", "1.jpg", "2.jpg"], b"page");
+    let cd_offset = data.len() as u64;
+    let plain = [
+        &data[..],
+        &central,
+        &end_of_central_directory(3, central.len() as u32, cd_offset as u32),
+    ]
+    .concat();
+    let zip64 = [
+        &data[..],
+        &central,
+        &zip64_end_of_central_directory(3, central.len() as u64, cd_offset),
+        &end_of_central_directory(u16::MAX, u32::MAX, u32::MAX),
+    ]
+    .concat();
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for i in 0..20_000 {
+        let mut bytes = if i % 2 == 0 {
+            plain.clone()
+        } else {
+            zip64.clone()
+        };
+        for _ in 0..1 + next() % 4 {
+            let at = next() as usize % bytes.len();
+            match next() % 3 {
+                0 => bytes[at] = next() as u8,
+                1 => bytes[at] = [0, 0xff][next() as usize % 2],
+                _ => bytes.truncate(at.max(1)),
+            }
+        }
+        let len = bytes.len() as u64;
+        let read = pollster::block_on(read_entries_from(&mut std::io::Cursor::new(&bytes), len));
+        for e in read.into_iter().flatten() {
+            assert!(e.header_offset + LOCAL_HEADER_LEN + e.compressed_size <= len);
+        }
+    }
+}
+
+#[test]
+fn t_zip64_header() {
+    let e = ZipEntry {
+        name: "0001.jpg".into(),
+        method: STORED,
+        dos_time: 0,
+        dos_date: 33,
+        crc32: 7,
+        compressed_size: 100,
+        uncompressed_size: 100,
+        header_offset: 5 << 30,
+    };
+    let h = e.central_header(FLAG_UTF8);
+    assert_eq!(h.len() as u64, e.central_header_len());
+    let parsed = parse_central_directory(&h, 1).unwrap();
+    assert_eq!(parsed[0].header_offset, 5 << 30);
+    assert_eq!(parsed[0].compressed_size, 100);
+
+    // small archives keep the plain record
+    assert_eq!(end_records(3, 100, 1000).len(), EOCD_LEN);
+    assert_eq!(
+        end_records(u16::MAX as u64, 100, 1000).len(),
+        ZIP64_EOCD_LEN + ZIP64_LOCATOR_LEN + EOCD_LEN
+    );
+}
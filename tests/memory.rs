@@ -0,0 +1,290 @@
+//! Requests through `request_resolver::resolve` against the in-memory
+//! backend, from routing to the response body.
+//!
+//! The config and the library index are process wide, every test shares the
+//! library set up by `setup`: 2 series of 3 chapters with 4 pages each.
+use std::sync::Once;
+
+use hyper::{body::Bytes, header, Body, Method, Request, Response, StatusCode};
+use manga_server::{
+    config::{self, Backend, Config},
+    manga_list,
+    memory::{self, Chapter, Library, Memory, Page},
+    request_resolver::resolve,
+};
+
+fn setup() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let mut config = Config::new(Backend::Memory);
+        config.port = Some(0);
+        config::init(config).unwrap();
+        memory::seed(Library::generated(2, 3, 4));
+        manga_list::get_list_ref();
+    });
+}
+
+async fn send(req: Request<Body>) -> Response<Body> {
+    setup();
+    resolve::<Memory>(req).await.unwrap()
+}
+
+async fn get(path: &str) -> Response<Body> {
+    send(Request::get(path).body(Body::empty()).unwrap()).await
+}
+
+async fn body(r: Response<Body>) -> Bytes {
+    hyper::body::to_bytes(r.into_body()).await.unwrap()
+}
+
+async fn json(path: &str) -> serde_json::Value {
+    let r = get(path).await;
+    assert_eq!(r.status(), StatusCode::OK, "{}", path);
+    serde_json::from_slice(&body(r).await).unwrap()
+}
+
+#[tokio::test]
+async fn t_library() {
+    let libraries = json("/api/v1/libraries").await;
+    assert_eq!(libraries[0]["backend"], "memory");
+    assert_eq!(libraries[0]["series_count"], 2);
+
+    let series = json("/api/v1/series?q=series%201").await;
+    assert_eq!(series.as_array().unwrap().len(), 1);
+    assert_eq!(series[0]["name"], "Series 1");
+    assert_eq!(series[0]["chapter_count"], 3);
+    assert_eq!(
+        series[0]["cover_url"],
+        "/api/v1/series/1/chapters/1/pages/0"
+    );
+
+    let chapters = json("/api/v1/series/1/chapters").await;
+    assert_eq!(chapters.as_array().unwrap().len(), 3);
+    assert_eq!(chapters[2]["name"], "Chapter 3");
+    assert_eq!(chapters[2]["page_count"], 4);
+
+    let pages = json("/api/v1/series/1/chapters/2/pages").await;
+    assert_eq!(pages.as_array().unwrap().len(), 4);
+
+    let manga = json("/manga/1").await;
+    assert_eq!(manga["chapters"].as_array().unwrap().len(), 3);
+
+    let r = get("/api/v1/series/9").await;
+    assert_eq!(r.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn t_page() {
+    let r = get("/manga/1/2/3").await;
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_eq!(r.headers()[header::CONTENT_TYPE], "image/png");
+    let etag = r.headers()[header::ETAG].clone();
+    let full = body(r).await;
+    assert!(full.starts_with(b"\x89PNG\r\n\x1a\n"));
+    let decoded = image::load_from_memory(&full).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (60, 80));
+
+    let api = body(get("/api/v1/series/1/chapters/2/pages/3").await).await;
+    assert_eq!(api, full);
+    assert_ne!(body(get("/manga/1/2/2").await).await, full);
+
+    let range = |if_range: &str| {
+        send(
+            Request::get("/manga/1/2/3")
+                .header(header::RANGE, "bytes=1-3")
+                .header(header::IF_RANGE, if_range)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let r = range(etag.to_str().unwrap()).await;
+    assert_eq!(r.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body(r).await, full.slice(1..4));
+    // a page that changed since is sent whole
+    let r = range("\"00000000\"").await;
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_eq!(body(r).await, full);
+
+    let r = get("/api/v1/series/1/chapters/2/pages/4").await;
+    assert_eq!(r.status(), StatusCode::NOT_FOUND);
+    // answered with the 404 page by the server
+    let r = resolve::<Memory>(Request::get("/manga/1/2/4").body(Body::empty()).unwrap()).await;
+    assert!(r.unwrap_err().to_string().ends_with("page not found"));
+}
+
+#[tokio::test]
+async fn t_download() {
+    let r = get("/download/2/1").await;
+    assert_eq!(r.status(), StatusCode::OK);
+    let zip = body(r).await;
+    assert!(zip.starts_with(b"PK\x03\x04"));
+    // every page is stored as it is
+    let page = body(get("/manga/2/1/0").await).await;
+    assert!(zip.windows(page.len()).any(|w| w == page));
+
+    // the same bytes from the middle on, as a resumed download asks for them
+    let r = send(
+        Request::get("/download/2/1")
+            .header(header::RANGE, "bytes=500-")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(r.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body(r).await, zip.slice(500..));
+}
+
+#[tokio::test]
+async fn t_webdav() {
+    let propfind = |path: &str, depth: &str| {
+        send(
+            Request::builder()
+                .method(Method::from_bytes(b"PROPFIND").unwrap())
+                .uri(path)
+                .header("depth", depth)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let r = propfind("/dav/memory/Series%201", "1").await;
+    assert_eq!(r.status(), StatusCode::MULTI_STATUS);
+    let xml = String::from_utf8(body(r).await.to_vec()).unwrap();
+    assert!(xml.contains("<D:displayname>003 Chapter 3.cbz</D:displayname>"));
+    assert_eq!(
+        propfind("/dav/memory", "infinity").await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let whole = body(get("/dav/memory/Series%201/001%20Chapter%201.cbz").await).await;
+    assert_eq!(whole, body(get("/download/1/1").await).await);
+}
+
+#[tokio::test]
+async fn t_progress() {
+    let path = "/api/v1/series/1/chapters/1/progress";
+    let auth = "Basic dGVzdDpwYXNz"; // test:pass
+    let put = |page: usize| {
+        send(
+            Request::builder()
+                .method(Method::PUT)
+                .uri(path)
+                .header(header::AUTHORIZATION, auth)
+                .body(Body::from(format!("{{\"page\":{}}}", page)))
+                .unwrap(),
+        )
+    };
+    assert_eq!(put(2).await.status(), StatusCode::OK);
+    assert_eq!(put(4).await.status(), StatusCode::BAD_REQUEST);
+
+    let r = send(
+        Request::get(path)
+            .header(header::AUTHORIZATION, auth)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let progress: serde_json::Value = serde_json::from_slice(&body(r).await).unwrap();
+    assert_eq!(progress["page"], 2);
+    // other users have their own progress
+    assert_eq!(json(path).await, serde_json::Value::Null);
+
+    let huge = format!("{{\"page\":1,\"pad\":\"{}\"}}", "x".repeat(100_000));
+    let r = send(
+        Request::builder()
+            .method(Method::PUT)
+            .uri(path)
+            .body(Body::from(huge))
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(r.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn t_graphql_batch() {
+    let batch = |n: usize| {
+        let ops = vec!["{\"query\":\"{ seriesList { name } }\"}"; n].join(",");
+        send(
+            Request::post("/graphql")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!("[{}]", ops)))
+                .unwrap(),
+        )
+    };
+    let r = batch(2).await;
+    assert_eq!(r.status(), StatusCode::OK);
+    let results: serde_json::Value = serde_json::from_slice(&body(r).await).unwrap();
+    assert_eq!(results.as_array().unwrap().len(), 2);
+    assert_eq!(batch(11).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn t_feeds() {
+    let r = get("/opds/series/1").await;
+    assert_eq!(r.status(), StatusCode::OK);
+    let opds = String::from_utf8(body(r).await.to_vec()).unwrap();
+    assert!(opds.contains("Chapter 3"));
+    // the pages are pngs, and so are the covers and streamed pages
+    assert!(opds.contains("/manga/1/3/0\" type=\"image/png\""));
+    assert!(!opds.contains("image/jpeg"));
+    let list = String::from_utf8(body(get("/opds/series").await).await.to_vec()).unwrap();
+    assert!(list.contains("/manga/2/1/0\" type=\"image/png\""));
+    assert!(!list.contains("rel=\"next\""));
+    let past = String::from_utf8(body(get("/opds/series?page=2").await).await.to_vec()).unwrap();
+    assert!(past.contains("rel=\"previous\" href=\"/opds/series?page=1\""));
+    assert!(!past.contains("Series 1"));
+
+    let r = get("/feed/recent.atom").await;
+    assert_eq!(r.status(), StatusCode::OK);
+    let feed = String::from_utf8(body(r).await.to_vec()).unwrap();
+    assert!(feed.contains("Series 2"));
+    assert!(feed.contains("href=\"http://localhost/download/"));
+    let r = send(
+        Request::get("/feed/recent.atom")
+            .header("host", "example.com:8080")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    let feed = String::from_utf8(body(r).await.to_vec()).unwrap();
+    assert!(feed.contains("href=\"http://example.com:8080/feed/recent.atom\""));
+
+    // books carry their own date and the size of their download
+    let book = json("/komga/api/v1/books/31-31").await;
+    let zip = body(get("/download/1/1").await).await;
+    assert_eq!(book["sizeBytes"], zip.len());
+    let last = json("/komga/api/v1/books/31-33").await;
+    assert_ne!(book["created"], last["created"]);
+
+    let pages = json("/komga/api/v1/books/31-31/pages").await;
+    assert_eq!(pages[3]["number"], 4);
+    assert_eq!(pages[3]["mediaType"], "image/png");
+
+    // the memory library has no folder on disk, that is counted, not fatal
+    let stats = json("/api/stats").await;
+    assert_eq!(stats["manga"], 2);
+    assert_eq!(stats["unreadable_files"], 1);
+
+    assert_eq!(get("/readyz").await.status(), StatusCode::OK);
+    // the server answers these with the 404 page
+    setup();
+    let r = resolve::<Memory>(Request::get("/no/such/page").body(Body::empty()).unwrap()).await;
+    assert!(r.is_err());
+}
+
+#[tokio::test]
+async fn t_rescan() {
+    setup();
+    // only series 2 changes, the other tests read it without counting chapters
+    let mut library = Library::generated(2, 3, 4);
+    library.series[1].chapters.push(Chapter {
+        id: "extra".into(),
+        name: "Extra".into(),
+        added: 0,
+        pages: vec![Page::generated(1000, 10, 10)],
+    });
+    memory::seed(library);
+    assert_eq!(manga_list::rescan(), 1);
+    let chapter = json("/api/v1/series/2/chapters/extra").await;
+    assert_eq!(chapter["page_count"], 1);
+}
@@ -0,0 +1,70 @@
+//! A whole server on a free port, served with `run_until`. The config is
+//! process wide, this binary builds the one server it can.
+use std::time::Duration;
+
+use hyper::{body::Bytes, Body, Client, Response, StatusCode};
+use manga_server::{
+    config::{Backend, Config},
+    memory::{self, Library},
+    net::ListenAddr,
+    server::ServerBuilder,
+};
+
+async fn get(url: &str) -> (StatusCode, Bytes) {
+    let r: Response<Body> = Client::new().get(url.parse().unwrap()).await.unwrap();
+    let status = r.status();
+    (status, hyper::body::to_bytes(r).await.unwrap())
+}
+
+#[tokio::test]
+async fn t_run_until() {
+    memory::seed(Library::generated(1, 1, 2));
+    let server = ServerBuilder::new(Config::new(Backend::Memory))
+        .listen(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()))
+        .route("/hello", |_req| async { Ok(Response::new("hi".into())) })
+        .build()
+        .unwrap();
+    let base = format!("http://{}", server.local_addrs()[0]);
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::spawn(server.run_until(async {
+        stopped.await.ok();
+    }));
+
+    // the library is scanned in the background
+    let mut ready = false;
+    for _ in 0..50 {
+        if get(&format!("{}/readyz", base)).await.0 == StatusCode::OK {
+            ready = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(ready);
+    assert_eq!(get(&format!("{}/hello", base)).await.1, "hi");
+    let (status, body) = get(&format!("{}/api/v1/series/1/chapters/1/pages", base)).await;
+    assert_eq!(status, StatusCode::OK);
+    let pages: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(pages.as_array().unwrap().len(), 2);
+    // errors of the routes get the 404 page
+    assert_eq!(
+        get(&format!("{}/manga/1/1/9", base)).await.0,
+        StatusCode::NOT_FOUND
+    );
+
+    // one server per process
+    let again = ServerBuilder::new(Config::new(Backend::Memory))
+        .listen(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()))
+        .build();
+    assert!(again.is_err());
+
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), running)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    // nothing is left listening
+    assert!(tokio::net::TcpStream::connect(&base["http://".len()..])
+        .await
+        .is_err());
+}
@@ -0,0 +1,57 @@
+//! Bodies of the versioned json api under `/api/v1`.
+use serde::{Deserialize, Serialize};
+
+/// A library is the content of one backend, there is one per server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct Library {
+    pub id: String,
+    pub backend: String,
+    pub series_count: usize,
+    pub chapter_count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct Series {
+    pub id: String,
+    pub library_id: String,
+    pub name: String,
+    /// Url of the first page of the first chapter.
+    pub cover_url: String,
+    pub chapter_count: usize,
+    /// Last change on disk as unix seconds, 0 if unknown.
+    pub added: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct Chapter {
+    pub id: String,
+    pub series_id: String,
+    pub name: String,
+    pub page_count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct Page {
+    pub index: usize,
+    pub url: String,
+}
+
+/// Body of a progress update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct ProgressUpdate {
+    /// Last page read, starting at 0.
+    pub page: usize,
+    #[serde(default)]
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct ErrorBody {
+    pub error: String,
+}
@@ -0,0 +1,77 @@
+//! The types manga-server answers with, shared by the server and its client
+//! so neither has to depend on the other.
+//!
+//! The `schema` feature derives their OpenAPI schemas.
+use serde::{Deserialize, Serialize};
+
+pub mod api;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq)]
+pub struct MangaInfo {
+    pub name: String,
+    pub pic: String,
+    pub id: String,
+    pub chapters: Vec<ChapterBasicInfo>,
+    /// Last modification on disk as unix seconds, 0 if unknown.
+    pub added: u64,
+}
+impl MangaInfo {
+    /// Words in brackets of the name, e.g. the author in `[author] title`.
+    pub fn tags(&self) -> Vec<String> {
+        let mut out = Vec::<String>::new();
+        let mut rest = self.name.as_str();
+        while let Some(start) = rest.find(['[', '【']) {
+            let after = &rest[start..];
+            let open = after.chars().next().map_or(1, char::len_utf8);
+            let close = match after.find([']', '】']) {
+                Some(c) => c,
+                None => break,
+            };
+            let tag = after[open..close].trim();
+            if !tag.is_empty() && !out.iter().any(|t| t == tag) {
+                out.push(tag.to_owned());
+            }
+            rest = &after[close..];
+        }
+        out
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Eq)]
+pub struct ChapterBasicInfo {
+    pub id: String,
+    pub name: String,
+    pub length: usize,
+    /// When the chapter showed up on disk as unix seconds, 0 if unknown.
+    pub added: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChapterInfo {
+    pub length: usize,
+    pub name: String,
+}
+
+/// How far the library scan is, shown by `/readyz`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ScanProgress {
+    pub ready: bool,
+    /// Files and directories looked at so far.
+    pub entries_scanned: usize,
+    /// Number of manga, known once the scan is done.
+    pub manga: Option<usize>,
+    pub elapsed_ms: Option<u128>,
+    /// A rescan is running, the previous index is answered meanwhile.
+    pub rescanning: bool,
+}
+
+/// Where a user stopped reading a chapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(utoipa::ToSchema))]
+pub struct Progress {
+    /// Last page read, starting at 0.
+    pub page: usize,
+    pub completed: bool,
+    /// Unix seconds.
+    pub updated: u64,
+}